@@ -1,18 +1,38 @@
-use reqwest::blocking::Client;
+use std::collections::HashMap;
+use std::fmt;
 
-use crate::env::Environment;
-use crate::openai::{Embedding, OpenAIError};
-use crate::openai::{chatgpt, embedding};
+use crate::backend::CompletionBackend;
+use crate::chat::{count_tokens, ChatMessage};
+use crate::index::{IndexKind, VectorIndex};
+use crate::memory::Memory;
+use crate::openai::OpenAIError;
+use crate::params::GenerationParams;
+use crate::prompts;
+use crate::templates::Templates;
+use crate::tokens;
 
-pub struct Memory {
-  pub subject: String,
-  pub content: String,
-  pub embedding: Embedding
-}
+/// Prompt size, in tokens, above which old turns are summarised.
+const DEFAULT_TOKEN_BUDGET: usize = 3000;
+/// Introduces the summary in the system message.
+const SUMMARY_HEADING: &str = "\n\nSummary of the conversation so far:\n";
 
 pub struct Agent {
   pub base_prompt: String,
-  pub memory_bank: Vec<Memory>
+  pub params: GenerationParams,
+  memory_bank: Vec<Memory>,
+  /// One index per embedding model, holding positions in `memory_bank`.
+  indexes: HashMap<Option<String>, Box<dyn VectorIndex>>,
+  index_kind: IndexKind,
+  /// Previous turns, oldest first, alternating user and assistant messages.
+  pub transcript: Vec<ChatMessage>,
+  /// What was said in the turns dropped from `transcript`.
+  pub summary: Option<String>,
+  pub token_budget: usize,
+  /// How many memories `speak` brings up, and how similar they must be.
+  pub recall_count: usize,
+  pub min_similarity: f64,
+  /// Where the prompt for summarising old turns comes from.
+  pub templates: Templates
 }
 
 #[derive(Debug)]
@@ -20,95 +40,309 @@ pub enum AgentError {
   OpenAIError(crate::openai::OpenAIError)
 }
 
-impl From<OpenAIError> for AgentError {
-  fn from(openai_error: OpenAIError) -> Self {
-      AgentError::OpenAIError(openai_error)
+impl fmt::Display for AgentError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+          AgentError::OpenAIError(error) => write!(f, "{}", error)
+      }
   }
 }
 
-fn cosine_similarity(a: &[f64], b: &[f64]) -> Option<f64> {
-  if a.len() != b.len() || a.is_empty() {
-      return None;
-  }
-
-  let dot_product: f64 = a.iter().zip(b).map(|(a_elem, b_elem)| a_elem * b_elem).sum();
-  let magnitude_a: f64 = a.iter().map(|a_elem| a_elem * a_elem).sum::<f64>().sqrt();
-  let magnitude_b: f64 = b.iter().map(|b_elem| b_elem * b_elem).sum::<f64>().sqrt();
-
-  if magnitude_a == 0.0 || magnitude_b == 0.0 {
-      return None;
+impl From<OpenAIError> for AgentError {
+  fn from(openai_error: OpenAIError) -> Self {
+      AgentError::OpenAIError(openai_error)
   }
-
-  Some(dot_product / (magnitude_a * magnitude_b))
 }
 
 impl Agent {
   pub fn new(base_prompt: String) -> Agent {
       Agent {
           base_prompt,
-          memory_bank: Vec::new()
+          params: GenerationParams::new(),
+          memory_bank: Vec::new(),
+          indexes: HashMap::new(),
+          index_kind: IndexKind::default(),
+          transcript: Vec::new(),
+          summary: None,
+          token_budget: DEFAULT_TOKEN_BUDGET,
+          recall_count: 3,
+          min_similarity: 0.0,
+          templates: Templates::default()
       }
   }
 
-  pub fn memorize(&mut self, subject: String, content: String, client: &Client, env: &Environment) -> Result<(), AgentError> {
-      let embedding = embedding(content.as_str(), client, env)?;
+  pub fn memories(&self) -> &[Memory] {
+      &self.memory_bank
+  }
 
-      self.memory_bank.push(Memory {
-          subject,
-          content,
-          embedding
-      });
+  /// How many memories can be recalled. Blank ones and ones with odd
+  /// dimensions can't be.
+  pub fn searchable(&self) -> usize {
+      self.indexes.values().map(|index| index.len()).sum()
+  }
 
-      Ok(())
+  /// Replaces the memory bank, indexing it with `index_kind`.
+  pub fn restore(&mut self, memories: Vec<Memory>, index_kind: IndexKind) {
+      self.memory_bank.clear();
+      self.indexes.clear();
+      self.index_kind = index_kind;
+
+      for memory in memories {
+          self.remember(memory);
+      }
   }
 
-  pub fn recall(&self, embedding: Vec<f64>) -> Option<String> {
-      let mut best_match: Option<String> = None;
-      let mut best_match_similarity: f64 = 0.0;
+  fn remember(&mut self, memory: Memory) {
+      let position = self.memory_bank.len();
+      let index = self.indexes.entry(memory.model.clone()).or_insert_with(|| self.index_kind.build());
+      // memories the index can't take, like blank notes, stay in the bank but are never recalled
+      if let Err(error) = index.insert(position, &memory.embedding) {
+          eprintln!("not indexing memory {:?}: {}", memory.subject, error);
+      }
+      self.memory_bank.push(memory);
+  }
 
-      for memory in &self.memory_bank {
-          let similarity = cosine_similarity(&memory.embedding, &embedding).unwrap();
-          if similarity > best_match_similarity {
-              best_match = Some(memory.content.clone());
-              best_match_similarity = similarity;
+  /// Embeds each `(subject, content, source)` entry in a single batch and
+  /// adds it to the memory bank, skipping text already embedded with this
+  /// backend's model.
+  pub fn memorize(&mut self, entries: &[(&str, &str, Option<&str>)], backend: &dyn CompletionBackend) -> Result<(), AgentError> {
+      let model = backend.embedding_model();
+      let mut new_entries: Vec<&(&str, &str, Option<&str>)> = Vec::new();
+      for entry in entries {
+          let known = self.memory_bank.iter().any(|memory| memory.content == entry.1 && memory.model.as_deref() == model);
+          if !known && !new_entries.iter().any(|new_entry| new_entry.1 == entry.1) {
+              new_entries.push(entry);
           }
       }
+      if new_entries.is_empty() {
+          return Ok(());
+      }
+
+      let contents = new_entries.iter().map(|(_, content, _)| *content).collect::<Vec<&str>>();
+      let embeddings = backend.embed_batch(&contents)?;
+
+      for ((subject, content, source), embedding) in new_entries.into_iter().zip(embeddings) {
+          let mut memory = Memory::new(subject.to_string(), content.to_string(), embedding);
+          memory.source = source.map(|source| source.to_string());
+          memory.model = model.map(|model| model.to_string());
+          self.remember(memory);
+      }
 
-      best_match
+      Ok(())
   }
 
-  pub fn prompt(&self, input: &str, embedding: Vec<f64>) -> String {
+  /// Up to `k` memories scoring at least `min_score` against `query`, best
+  /// first. Only memories embedded by `model` are considered, and none
+  /// match a query whose dimensions differ from theirs.
+  pub fn recall_top_k(&self, query: &[f64], k: usize, min_score: f64, model: Option<&str>) -> Vec<(&Memory, f64)> {
+      let Some(index) = self.indexes.get(&model.map(|model| model.to_string())) else {
+          return Vec::new();
+      };
+
+      index.search(query, k)
+          .into_iter()
+          .map(|(position, score)| (&self.memory_bank[position], score as f64))
+          .filter(|(_, score)| *score >= min_score)
+          .collect()
+  }
+
+  /// The system message for the next turn: the persona, the summary of
+  /// forgotten turns and whichever memories `input` brings to mind.
+  fn system_message(&self, memories: &[(Memory, f64)]) -> ChatMessage {
       let mut prompt = self.base_prompt.clone();
 
-      let relevant = self.recall(embedding);
+      if let Some(summary) = &self.summary {
+          prompt.push_str(SUMMARY_HEADING);
+          prompt.push_str(summary);
+      }
+      if !memories.is_empty() {
+          prompt.push_str("\n\nThe latest message reminds you of:");
+          for (memory, score) in memories {
+              eprintln!("recalled {} ({:.2})", memory.subject, score);
+              prompt.push_str(&format!("\n- {}: {}", memory.subject, memory.content));
+          }
+      }
 
-      let message = match relevant {
-          Some(memory) => format!("
+      ChatMessage::system(&prompt)
+  }
 
-          Message:
-          {}
-          
-          Reminding me of:
-          {}", input, memory),
+  pub fn messages(&self, input: &str, memories: &[(Memory, f64)]) -> Vec<ChatMessage> {
+      let mut messages = vec![self.system_message(memories)];
+      messages.extend(self.transcript.iter().cloned());
+      messages.push(ChatMessage::user(input));
+      messages
+  }
 
-          None => format!("
-          
-          Message:
-          {}
-          ", input)
+  /// Folds the oldest turns into `summary` until the next prompt fits in
+  /// `token_budget`. The latest turn is never dropped. The summary is capped
+  /// at a quarter of the budget, and counted at that cap while turns are
+  /// being dropped, since it replaces the one in the prompt.
+  fn compact(&mut self, input: &str, memories: &[(Memory, f64)], backend: &dyn CompletionBackend) -> Result<(), AgentError> {
+      let summary_tokens = (self.token_budget / 4).max(1);
+      let over_budget = |agent: &Agent, summarizing: bool| {
+          let summary = match &agent.summary {
+              Some(summary) => tokens::count(SUMMARY_HEADING) + tokens::count(summary),
+              None => 0
+          };
+          let room = if summarizing { tokens::count(SUMMARY_HEADING) + summary_tokens } else { summary };
+          count_tokens(&agent.messages(input, memories)) - summary + room > agent.token_budget
       };
 
-      println!("\n---\n{}\n---\n", message);
+      let mut dropped = Vec::new();
+      while !self.transcript.is_empty() && over_budget(self, !dropped.is_empty()) {
+          let turn = self.transcript.len().min(2);
+          dropped.extend(self.transcript.drain(..turn));
+      }
+
+      if dropped.is_empty() {
+          return Ok(());
+      }
+
+      let transcript = dropped.iter()
+          .map(|message| format!("{:?}: {}", message.role, message.content))
+          .collect::<Vec<String>>()
+          .join("\n");
+      let prompt = prompts::summarize_transcript(&self.templates, self.summary.as_deref(), &transcript);
+      self.summary = Some(backend.chat(&prompt, &self.params.clone().max_tokens(summary_tokens as u32))?);
 
-      prompt.push_str(&message);
-      prompt
+      Ok(())
   }
 
-  pub fn speak(&self, input: &str, client: &Client, env: &Environment) -> Result<String, AgentError> {
-      let embedding = embedding(input, client, env)?;
-      let prompt = self.prompt(input, embedding);
-      let result = chatgpt(&prompt, &client, &env);
+  /// Answers `input`, handing the reply to `on_token` as it streams in. Both
+  /// are added to the transcript.
+  pub fn speak(&mut self, input: &str, backend: &dyn CompletionBackend, on_token: &mut dyn FnMut(&str)) -> Result<String, AgentError> {
+      let embedding = backend.embed(input)?;
+      let memories = self.recall_top_k(&embedding, self.recall_count, self.min_similarity, backend.embedding_model())
+          .into_iter()
+          .map(|(memory, score)| (memory.clone(), score))
+          .collect::<Vec<(Memory, f64)>>();
+
+      self.compact(input, &memories, backend)?;
+      let messages = self.messages(input, &memories);
+      let result = backend.chat_messages_stream(&messages, &self.params, on_token)?;
+
+      self.transcript.push(ChatMessage::user(input));
+      self.transcript.push(ChatMessage::assistant(&result));
 
       Ok(result)
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mock::MockBackend;
+
+  #[test]
+  fn speak_recalls_the_closest_memory() {
+      let backend = MockBackend::new().with_chats(&["a thoughtful reply"]);
+      let mut agent = Agent::new("Base prompt.".to_string());
+      agent.recall_count = 1;
+
+      agent.memorize(&[("loops", "feedback loops drive growth", None)], &backend).unwrap();
+      agent.memorize(&[("gardens", "tending a garden of ideas", None)], &backend).unwrap();
+
+      let mut streamed = String::new();
+      let response = agent.speak("what about feedback loops?", &backend, &mut |token| streamed.push_str(token)).unwrap();
+
+      assert_eq!(response, "a thoughtful reply");
+      assert_eq!(streamed, response);
+      let prompt = &backend.prompts()[0];
+      assert!(prompt.starts_with("Base prompt."));
+      assert!(prompt.contains("- loops: feedback loops drive growth"));
+      assert!(!prompt.contains("tending a garden"));
+  }
+
+  fn memory(subject: &str, embedding: Vec<f64>) -> Memory {
+      Memory::new(subject.to_string(), subject.to_string(), embedding)
+  }
+
+  #[test]
+  fn recall_top_k_ranks_and_filters() {
+      let mut agent = Agent::new("Base prompt.".to_string());
+      agent.restore(vec![
+          memory("close", vec![1.0, 0.1]),
+          memory("far", vec![0.0, 1.0]),
+          memory("closest", vec![1.0, 0.0]),
+          memory("opposite", vec![-1.0, 0.0]),
+          memory("wrong size", vec![1.0, 0.0, 0.0]),
+          memory("blank", vec![0.0, 0.0]),
+      ], IndexKind::BruteForce);
+
+      let subjects = |k, min_score| agent.recall_top_k(&[1.0, 0.0], k, min_score, None)
+          .into_iter()
+          .map(|(memory, _)| memory.subject.as_str())
+          .collect::<Vec<&str>>();
+
+      assert_eq!(subjects(10, 0.0), ["closest", "close", "far"]);
+      assert_eq!(subjects(2, -1.0), ["closest", "close"]);
+      assert_eq!(subjects(10, 0.5), ["closest", "close"]);
+      assert_eq!(agent.recall_top_k(&[1.0, 0.0], 1, 0.0, None)[0].1, 1.0);
+  }
+
+  #[test]
+  fn memorize_skips_text_it_already_knows() {
+      let backend = MockBackend::new();
+      let mut agent = Agent::new("Base prompt.".to_string());
+      let mut stale = Memory::new("loops".to_string(), "feedback loops".to_string(), vec![1.0]);
+      stale.model = Some("an older model".to_string());
+      agent.restore(vec![stale], IndexKind::Hnsw);
+
+      agent.memorize(&[("loops", "feedback loops", Some("loops.subtext"))], &backend).unwrap();
+      agent.memorize(&[("loops", "feedback loops", Some("loops.subtext"))], &backend).unwrap();
+
+      assert_eq!(agent.memories().len(), 2);
+      assert_eq!(agent.memories()[1].model.as_deref(), Some("mock"));
+      assert_eq!(agent.memories()[1].source.as_deref(), Some("loops.subtext"));
+      assert_eq!(agent.recall_top_k(&[1.0], 5, 0.0, Some("an older model")).len(), 1);
+  }
+
+  #[test]
+  fn memorize_skips_duplicates_within_the_batch() {
+      let backend = MockBackend::new();
+      let mut agent = Agent::new("Base prompt.".to_string());
+
+      agent.memorize(&[
+          ("loops", "feedback loops", Some("loops.subtext")),
+          ("gardens", "tending a garden", None),
+          ("loops again", "feedback loops", Some("loops.subtext")),
+      ], &backend).unwrap();
+
+      let subjects = agent.memories().iter().map(|memory| memory.subject.as_str()).collect::<Vec<&str>>();
+      assert_eq!(subjects, ["loops", "gardens"]);
+      assert_eq!(agent.searchable(), 2);
+  }
+
+  #[test]
+  fn speak_keeps_a_transcript() {
+      let backend = MockBackend::new().with_chats(&["first reply", "second reply"]);
+      let mut agent = Agent::new("Base prompt.".to_string());
+
+      agent.speak("hello", &backend, &mut |_| {}).unwrap();
+      agent.speak("and again", &backend, &mut |_| {}).unwrap();
+
+      assert_eq!(agent.transcript, vec![
+          ChatMessage::user("hello"),
+          ChatMessage::assistant("first reply"),
+          ChatMessage::user("and again"),
+          ChatMessage::assistant("second reply"),
+      ]);
+      assert_eq!(backend.prompts()[1], "Base prompt.\n\nhello\n\nfirst reply\n\nand again");
+  }
+
+  #[test]
+  fn old_turns_are_summarised_over_budget() {
+      let backend = MockBackend::new().with_chats(&["first reply", "they said hello", "second reply"]);
+      let mut agent = Agent::new("Base prompt.".to_string());
+      agent.token_budget = 20;
+
+      agent.speak("hello there, how are you?", &backend, &mut |_| {}).unwrap();
+      agent.speak("and again", &backend, &mut |_| {}).unwrap();
+
+      assert_eq!(agent.summary.as_deref(), Some("they said hello"));
+      assert_eq!(backend.params()[1].max_tokens, Some(5));
+      assert!(backend.prompts()[1].contains("User: hello there, how are you?\nAssistant: first reply"));
+      assert_eq!(backend.prompts()[2], "Base prompt.\n\nSummary of the conversation so far:\nthey said hello\n\nand again");
+      assert_eq!(agent.transcript.len(), 2);
+  }
+}
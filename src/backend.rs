@@ -0,0 +1,96 @@
+use crate::chat::ChatMessage;
+use crate::openai::{Embedding, OpenAIError};
+use crate::params::GenerationParams;
+
+/// A source of completions, chat responses and embeddings.
+///
+/// The prompt pipelines and `Agent` only talk to this trait, so any
+/// OpenAI-compatible server (or a fake) can stand in for the real API.
+pub trait CompletionBackend: Sync {
+    /// Runs a plain completion. `params` override the backend's defaults.
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError>;
+    /// Sends `prompt` as a single user message. `params` override the backend's defaults.
+    fn chat(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        self.chat_messages(&[ChatMessage::user(prompt)], params)
+    }
+    /// Continues a conversation, returning the assistant's next message.
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError>;
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError>;
+    /// Embeds several inputs, in as few requests as the backend allows.
+    /// Returns one embedding per input, in order.
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        inputs.iter().map(|input| self.embed(input)).collect()
+    }
+    /// The model behind `embed`, if known. Embeddings from different models
+    /// aren't comparable, so stored memories are tagged with it.
+    fn embedding_model(&self) -> Option<&str> {
+        None
+    }
+    /// The params `complete` lays the ones it's passed over.
+    fn completion_defaults(&self) -> GenerationParams {
+        GenerationParams::new()
+    }
+    /// The params `chat_messages` lays the ones it's passed over.
+    fn chat_defaults(&self) -> GenerationParams {
+        GenerationParams::new()
+    }
+
+    /// Like `complete`, but hands the response to `on_token` piece by piece as
+    /// it arrives, and returns the whole of it. Backends that can't stream
+    /// call `on_token` once with everything.
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let result = self.complete(prompt, params)?;
+        on_token(&result);
+        Ok(result)
+    }
+
+    /// The streaming counterpart of `chat`, see `complete_stream`.
+    fn chat_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        self.chat_messages_stream(&[ChatMessage::user(prompt)], params, on_token)
+    }
+
+    /// The streaming counterpart of `chat_messages`, see `complete_stream`.
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let result = self.chat_messages(messages, params)?;
+        on_token(&result);
+        Ok(result)
+    }
+}
+
+impl<B: CompletionBackend + ?Sized> CompletionBackend for Box<B> {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        (**self).complete(prompt, params)
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        (**self).chat_messages(messages, params)
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        (**self).embed(input)
+    }
+
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        (**self).embed_batch(inputs)
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        (**self).embedding_model()
+    }
+
+    fn completion_defaults(&self) -> GenerationParams {
+        (**self).completion_defaults()
+    }
+
+    fn chat_defaults(&self) -> GenerationParams {
+        (**self).chat_defaults()
+    }
+
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        (**self).complete_stream(prompt, params, on_token)
+    }
+
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        (**self).chat_messages_stream(messages, params, on_token)
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+use crate::backend::CompletionBackend;
+use crate::chat::ChatMessage;
+use crate::openai::{Embedding, OpenAIError};
+use crate::params::GenerationParams;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "embedding cache: {} hits, {} misses", self.hits, self.misses)
+    }
+}
+
+/// Embeddings on disk, one file per text, addressed by a hash of the text.
+///
+/// Entries live under a folder per embedding model, so switching models
+/// never serves a vector from the old one. The cache is best effort: an
+/// unreadable entry is a miss and a failed write is only reported.
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// The SHA-256 of `text`, in hex.
+pub fn content_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A folder name for `model` that can't escape the cache directory. Other
+/// bytes than letters, digits and `-` are escaped as `_` and two hex digits,
+/// so no two models share a folder.
+fn model_dir(model: &str) -> String {
+    model.bytes().map(|byte| match byte {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' => (byte as char).to_string(),
+        _ => format!("_{:02x}", byte)
+    }).collect()
+}
+
+impl EmbeddingCache {
+    pub fn new(dir: PathBuf) -> EmbeddingCache {
+        EmbeddingCache { dir, hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) }
+    }
+
+    fn path(&self, model: &str, text: &str) -> PathBuf {
+        let hash = content_hash(text);
+        self.dir.join(model_dir(model)).join(&hash[..2]).join(format!("{}.json", hash))
+    }
+
+    pub fn get(&self, model: &str, text: &str) -> Option<Embedding> {
+        let embedding = fs::read_to_string(self.path(model, text))
+            .ok()
+            .and_then(|json| serde_json::from_str::<Embedding>(&json).ok());
+
+        match embedding {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed)
+        };
+        embedding
+    }
+
+    /// Looks up each of `texts`, in order.
+    pub fn get_many(&self, model: &str, texts: &[&str]) -> Vec<Option<Embedding>> {
+        texts.iter().map(|text| self.get(model, text)).collect()
+    }
+
+    pub fn put(&self, model: &str, text: &str, embedding: &Embedding) {
+        let path = self.path(model, text);
+        let written = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, serde_json::to_string(embedding).unwrap()));
+
+        if let Err(error) = written {
+            eprintln!("could not cache embedding at {}: {}", path.display(), error);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+}
+
+/// Serves embeddings from an `EmbeddingCache` when it can, passing every
+/// other call straight through to `inner`. Backends that don't say which
+/// model they embed with aren't cached, since their entries couldn't be told apart.
+pub struct CachedBackend<B: CompletionBackend> {
+    inner: B,
+    cache: Arc<EmbeddingCache>,
+}
+
+impl<B: CompletionBackend> CachedBackend<B> {
+    pub fn new(inner: B, cache: Arc<EmbeddingCache>) -> CachedBackend<B> {
+        CachedBackend { inner, cache }
+    }
+}
+
+impl<B: CompletionBackend> CompletionBackend for CachedBackend<B> {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        self.inner.complete(prompt, params)
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        self.inner.chat_messages(messages, params)
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        let Some(model) = self.inner.embedding_model() else {
+            return self.inner.embed(input);
+        };
+
+        if let Some(embedding) = self.cache.get(model, input) {
+            return Ok(embedding);
+        }
+
+        let embedding = self.inner.embed(input)?;
+        self.cache.put(model, input, &embedding);
+        Ok(embedding)
+    }
+
+    /// Only the inputs missing from the cache are sent on, in one batch.
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        let Some(model) = self.inner.embedding_model() else {
+            return self.inner.embed_batch(inputs);
+        };
+
+        let mut embeddings = self.cache.get_many(model, inputs);
+        let missing = (0..inputs.len()).filter(|i| embeddings[*i].is_none()).collect::<Vec<usize>>();
+        if !missing.is_empty() {
+            let texts = missing.iter().map(|i| inputs[*i]).collect::<Vec<&str>>();
+            let fetched = self.inner.embed_batch(&texts)?;
+            if fetched.len() != texts.len() {
+                return Err(OpenAIError::MalformedResponse(format!("expected {} embeddings, got {}", texts.len(), fetched.len())));
+            }
+            for (i, embedding) in missing.into_iter().zip(fetched) {
+                self.cache.put(model, inputs[i], &embedding);
+                embeddings[i] = Some(embedding);
+            }
+        }
+
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        self.inner.embedding_model()
+    }
+
+    fn completion_defaults(&self) -> GenerationParams {
+        self.inner.completion_defaults()
+    }
+
+    fn chat_defaults(&self) -> GenerationParams {
+        self.inner.chat_defaults()
+    }
+
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        self.inner.complete_stream(prompt, params, on_token)
+    }
+
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        self.inner.chat_messages_stream(messages, params, on_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn serves_repeated_texts_from_disk() {
+        let dir = scratch_dir("cache-hits");
+        let first = CachedBackend::new(MockBackend::new().with_embedding("loops", vec![1.0, 2.0]), Arc::new(EmbeddingCache::new(dir.clone())));
+        first.embed("loops").unwrap();
+        first.embed("loops").unwrap();
+
+        // a later session answers from the same folder without asking its backend
+        let cache = Arc::new(EmbeddingCache::new(dir.clone()));
+        let second = CachedBackend::new(MockBackend::new().with_embedding("loops", vec![9.0]), cache.clone());
+
+        assert_eq!(second.embed("loops").unwrap(), vec![1.0, 2.0]);
+        assert_eq!(first.cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 0 });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn entries_are_scoped_to_the_model() {
+        let dir = scratch_dir("cache-models");
+        let cache = Arc::new(EmbeddingCache::new(dir.clone()));
+        let old = CachedBackend::new(MockBackend::new().with_embedding("loops", vec![1.0]), cache.clone());
+        let new = CachedBackend::new(MockBackend::new().with_embedding("loops", vec![2.0]).with_embedding_model("../newer"), cache.clone());
+
+        old.embed("loops").unwrap();
+
+        assert_eq!(new.embed("loops").unwrap(), vec![2.0]);
+        assert_eq!(cache.get_many("mock", &["loops", "gardens"]), vec![Some(vec![1.0]), None]);
+        assert!(dir.join("_2e_2e_2fnewer").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batches_only_embed_the_misses() {
+        let dir = scratch_dir("cache-batches");
+        let cache = Arc::new(EmbeddingCache::new(dir.clone()));
+        cache.put("mock", "loops", &vec![1.0]);
+        let backend = CachedBackend::new(MockBackend::new().with_embedding("gardens", vec![2.0]), cache.clone());
+
+        let embeddings = backend.embed_batch(&["loops", "gardens"]).unwrap();
+
+        assert_eq!(embeddings, vec![vec![1.0], vec![2.0]]);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        assert_eq!(cache.get("mock", "gardens"), Some(vec![2.0]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn model_folders_do_not_collide() {
+        assert_eq!(model_dir("text-embedding-3-small"), "text-embedding-3-small");
+        assert_ne!(model_dir("org/model"), model_dir("org_model"));
+        assert_ne!(model_dir("org_2fmodel"), model_dir("org/model"));
+    }
+
+    /// Drops the last embedding of every batch.
+    struct ShortBatches(MockBackend);
+
+    impl CompletionBackend for ShortBatches {
+        fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+            self.0.complete(prompt, params)
+        }
+
+        fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+            self.0.chat_messages(messages, params)
+        }
+
+        fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+            self.0.embed(input)
+        }
+
+        fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+            let mut embeddings = self.0.embed_batch(inputs)?;
+            embeddings.pop();
+            Ok(embeddings)
+        }
+
+        fn embedding_model(&self) -> Option<&str> {
+            self.0.embedding_model()
+        }
+    }
+
+    #[test]
+    fn short_batches_are_an_error() {
+        let dir = scratch_dir("cache-short");
+        let backend = CachedBackend::new(ShortBatches(MockBackend::new()), Arc::new(EmbeddingCache::new(dir.clone())));
+
+        assert!(matches!(backend.embed_batch(&["loops", "gardens"]), Err(OpenAIError::MalformedResponse(_))));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn hashes_content() {
+        assert_eq!(content_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
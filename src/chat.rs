@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tokens;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant
+}
+
+/// One entry of a chat transcript, as sent to `/chat/completions`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: &str) -> ChatMessage {
+        ChatMessage { role: Role::System, content: content.to_string() }
+    }
+
+    pub fn user(content: &str) -> ChatMessage {
+        ChatMessage { role: Role::User, content: content.to_string() }
+    }
+
+    pub fn assistant(content: &str) -> ChatMessage {
+        ChatMessage { role: Role::Assistant, content: content.to_string() }
+    }
+}
+
+/// Per-message overhead the chat format adds on top of the content.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Tokens in `messages` with cl100k, the encoding of the chat models.
+pub fn count_tokens(messages: &[ChatMessage]) -> usize {
+    messages.iter()
+        .map(|message| TOKENS_PER_MESSAGE + tokens::count(&message.content))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serializes_in_the_api_format() {
+        let messages = vec![ChatMessage::system("be brief"), ChatMessage::user("hi")];
+
+        assert_eq!(serde_json::to_value(&messages).unwrap(), json!([
+            {"role": "system", "content": "be brief"},
+            {"role": "user", "content": "hi"}
+        ]));
+    }
+
+    #[test]
+    fn counts_grow_with_content() {
+        assert_eq!(count_tokens(&[]), 0);
+        assert_eq!(count_tokens(&[ChatMessage::user("hello world")]), 6);
+        assert_eq!(count_tokens(&[ChatMessage::user("hello world"), ChatMessage::assistant("")]), 10);
+    }
+}
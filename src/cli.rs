@@ -0,0 +1,224 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::config::ConfigOverrides;
+use crate::sampler::Weighting;
+
+#[derive(Debug, Parser)]
+#[command(name = "summoning-circle", about = "Designing spirits using GPT-3 and friends")]
+pub struct Cli {
+    /// Extra config file, layered over the user and project configs
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Backend profile to use, from `[backends.*]`
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Folder of `.subtext` notes
+    #[arg(long, global = true)]
+    pub notes_dir: Option<PathBuf>,
+
+    /// How notes that aren't named are picked
+    #[arg(long, global = true, value_enum)]
+    pub weighting: Option<Weighting>,
+
+    /// Only pick notes with this tag (repeatable)
+    #[arg(long = "tag", global = true)]
+    pub tags: Vec<String>,
+
+    /// Seed for reproducible sampling and temperature draws
+    #[arg(long, global = true)]
+    pub seed: Option<u64>,
+
+    /// Chat model, overriding the profile's `chat_model`
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// Completion model, overriding the profile's `completion_model`
+    #[arg(long, global = true)]
+    pub completion_model: Option<String>,
+
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// One subcommand per REPL menu entry. Notes that aren't named are picked at random.
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Start the interactive menu (the default)
+    Repl,
+    /// Analyse a note as a fierce critic
+    Critic {
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Analyse a note as an improvising actor
+    Actor {
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Analyse a note alongside three others as an actor
+    FourActor {
+        #[arg(long)]
+        note: Option<String>,
+        /// Context notes, up to three
+        #[arg(long = "with")]
+        others: Vec<String>,
+    },
+    /// Compress two notes into a single metaphor
+    Compress {
+        #[arg(num_args = 0..=2)]
+        notes: Vec<String>,
+    },
+    /// Answer a random question about a note
+    Question {
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Ask critical questions about a note
+    Critique {
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Connect a note to three others, preferring notes it links with
+    Connect {
+        #[arg(long)]
+        base: Option<String>,
+        /// Notes to connect to, up to three
+        #[arg(long = "with")]
+        others: Vec<String>,
+    },
+    /// Run the critic over free text, read from stdin when not given
+    FreeText {
+        text: Option<String>,
+    },
+    /// Talk to a geist, one message per line of stdin
+    Chat,
+    /// Embed the notes folder for search, re-embedding only notes that changed
+    Index,
+    /// Show a note's links and backlinks, or every orphan and dangling link
+    Links {
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Find the notes closest in meaning to a query, read from stdin when not given
+    Search {
+        query: Option<String>,
+        /// How many notes to list
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Blend in keyword matching, from 0 (meaning only) to 1 (keywords only)
+        #[arg(long)]
+        keyword_weight: Option<f64>,
+    },
+    /// Run a pipeline from the pipelines folder over a note, and the notes it links to
+    Summon {
+        /// The pipeline's name, read from stdin when not given
+        spirit: Option<String>,
+        #[arg(long)]
+        note: Option<String>,
+        /// Context notes, up to the number the pipeline takes
+        #[arg(long = "with")]
+        others: Vec<String>,
+    },
+    /// List past runs, or show one request by request
+    History {
+        /// The run's name, as listed
+        run: Option<String>,
+        /// How many runs to list
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+}
+
+impl Cli {
+    pub fn config_overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            config_path: self.config.clone(),
+            profile: self.profile.clone(),
+            notes_dir: self.notes_dir.clone(),
+            seed: self.seed,
+            chat_model: self.model.clone(),
+            completion_model: self.completion_model.clone(),
+            weighting: self.weighting,
+            tags: self.tags.clone(),
+            search_results: match &self.command {
+                Some(CliCommand::Search { limit, .. }) => *limit,
+                _ => None
+            },
+            keyword_weight: match &self.command {
+                Some(CliCommand::Search { keyword_weight, .. }) => *keyword_weight,
+                _ => None
+            },
+            history_limit: match &self.command {
+                Some(CliCommand::History { limit, .. }) => *limit,
+                _ => None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands_and_global_flags() {
+        let cli = Cli::parse_from(["summoning-circle", "compress", "a.subtext", "b.subtext", "--seed", "4", "--format", "json", "--weighting", "link-degree", "--tag", "systems"]);
+
+        assert_eq!(cli.seed, Some(4));
+        assert_eq!(cli.weighting, Some(Weighting::LinkDegree));
+        assert_eq!(cli.tags, ["systems"]);
+        assert_eq!(cli.format, OutputFormat::Json);
+        assert!(matches!(cli.command, Some(CliCommand::Compress { notes }) if notes == ["a.subtext", "b.subtext"]));
+    }
+
+    #[test]
+    fn defaults_to_the_repl() {
+        let cli = Cli::parse_from(["summoning-circle"]);
+
+        assert!(cli.command.is_none());
+        assert_eq!(cli.format, OutputFormat::Text);
+    }
+
+    #[test]
+    fn search_flags_become_config_overrides() {
+        let cli = Cli::parse_from(["summoning-circle", "search", "feedback loops", "--limit", "3", "--keyword-weight", "0.5"]);
+        let overrides = cli.config_overrides();
+
+        assert_eq!(overrides.search_results, Some(3));
+        assert_eq!(overrides.keyword_weight, Some(0.5));
+        assert!(matches!(cli.command, Some(CliCommand::Search { query: Some(query), .. }) if query == "feedback loops"));
+    }
+
+    #[test]
+    fn parses_summon() {
+        let cli = Cli::parse_from(["summoning-circle", "summon", "oracle", "--note", "loops.subtext", "--with", "seeds.subtext"]);
+
+        assert!(matches!(cli.command, Some(CliCommand::Summon { spirit: Some(spirit), note: Some(_), others }) if spirit == "oracle" && others == ["seeds.subtext"]));
+    }
+
+    #[test]
+    fn parses_history() {
+        let cli = Cli::parse_from(["summoning-circle", "history", "--limit", "5"]);
+
+        assert_eq!(cli.config_overrides().history_limit, Some(5));
+        assert!(matches!(cli.command, Some(CliCommand::History { run: None, .. })));
+    }
+
+    #[test]
+    fn rejects_too_many_notes() {
+        assert!(Cli::try_parse_from(["summoning-circle", "compress", "a", "b", "c"]).is_err());
+    }
+}
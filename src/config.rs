@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::env::VarError;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::env::Environment;
+use crate::index::IndexKind;
+use crate::metaprompts::{Pipeline, PipelineError, Pipelines};
+use crate::notes::NoteSource;
+use crate::params::GenerationParams;
+use crate::sampler::Weighting;
+use crate::templates::{TemplateError, Templates};
+use crate::tokens::{self, Fit};
+
+/// Settings used when no config file says otherwise. User and project files
+/// are merged on top of this, key by key.
+const BUILTIN_CONFIG: &str = r#"
+profile = "openai"
+notes_dir = "notes"
+prompts_dir = "prompts"
+pipelines_dir = "pipelines"
+parallel_requests = 4
+
+[backends.openai]
+api_path = "https://api.openai.com/v1"
+api_key_env = "API_KEY"
+completion_model = "text-davinci-003"
+chat_model = "gpt-3.5-turbo"
+embedding_model = "text-embedding-ada-002"
+
+[notes]
+recursive = true
+include = []
+exclude = []
+weighting = "uniform"
+tags = []
+history = "memory/surfaced.json"
+
+[agent]
+persona = "geist"
+memory_rounds = 3
+brainstorm_rounds = 3
+token_budget = 3000
+memory_dir = "memory"
+recall_count = 3
+min_similarity = 0.0
+index = "brute-force"
+
+[cache]
+embeddings = true
+
+[search]
+index_path = "index/notes.json"
+chunk_tokens = 256
+results = 5
+keyword_weight = 0.0
+
+[context]
+fit = "truncate"
+reserve = 1000
+
+[transcripts]
+enabled = true
+dir = "transcripts"
+list = 20
+
+[personas.geist]
+prompt = "Simulation: You are a conversation bot designed to ask thought provoking questions. You respond to messages drawing connections between broad topics, making insightful use of any memories that you recall. You respond in at most two sentences."
+"#;
+
+const PROJECT_CONFIG: &str = "summoning-circle.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(PathBuf, std::io::Error),
+    ParseError(String, String),
+    MissingKey(String),
+    UnknownProfile(String, Vec<String>),
+    UnknownPersona(String, Vec<String>),
+    UnknownPipeline(String, Vec<String>),
+    InvalidEnvironment(String, String),
+    TemplateError(TemplateError),
+    PipelineError(PipelineError)
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::IOError(path, error) => write!(f, "could not read config {}: {}", path.display(), error),
+            ConfigError::ParseError(source, message) => write!(f, "invalid config in {}: {}", source, message),
+            ConfigError::MissingKey(key) => write!(f, "missing config key {}", key),
+            ConfigError::UnknownProfile(name, known) => write!(f, "no backend profile named {:?} (known: {})", name, known.join(", ")),
+            ConfigError::UnknownPersona(name, known) => write!(f, "no persona named {:?} (known: {})", name, known.join(", ")),
+            ConfigError::UnknownPipeline(name, known) => write!(f, "no pipeline named {:?} (known: {})", name, known.join(", ")),
+            ConfigError::InvalidEnvironment(name, value) => write!(f, "{} has an invalid value {:?}", name, value),
+            ConfigError::TemplateError(error) => write!(f, "{}", error),
+            ConfigError::PipelineError(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl From<TemplateError> for ConfigError {
+    fn from(template_error: TemplateError) -> Self {
+        ConfigError::TemplateError(template_error)
+    }
+}
+
+impl From<PipelineError> for ConfigError {
+    fn from(pipeline_error: PipelineError) -> Self {
+        ConfigError::PipelineError(pipeline_error)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendProfile {
+    pub api_path: Option<String>,
+    pub api_key: Option<String>,
+    /// Name of the environment variable holding the key, when `api_key` is unset.
+    pub api_key_env: Option<String>,
+    pub completion_model: Option<String>,
+    pub chat_model: Option<String>,
+    pub embedding_model: Option<String>,
+    /// Tokens per `/embeddings` request, for servers with a lower limit than OpenAI's.
+    pub max_embedding_batch_tokens: Option<usize>,
+    pub max_retries: Option<u32>,
+    pub requests_per_minute: Option<u32>,
+    /// Tokens the models take per request, prompt and answer together, for
+    /// models tiktoken doesn't know.
+    pub context_window: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotesConfig {
+    /// Whether notes in subfolders of `notes_dir` count.
+    pub recursive: bool,
+    /// Globs narrowing which files are notes, see `NoteSource::with_include`.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// How notes that aren't named are drawn.
+    pub weighting: Weighting,
+    /// Only draw notes with one of these tags, unless empty.
+    pub tags: Vec<String>,
+    /// When each note was last drawn, for `weighting = "staleness"`.
+    pub history: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    pub persona: String,
+    pub memory_rounds: usize,
+    pub brainstorm_rounds: usize,
+    /// Prompt tokens above which the conversation's oldest turns are summarised.
+    pub token_budget: usize,
+    /// Where each persona's memories are kept between sessions.
+    pub memory_dir: PathBuf,
+    /// How many memories to bring into each reply, and how similar they must be.
+    pub recall_count: usize,
+    pub min_similarity: f64,
+    /// How memories are searched: exactly, or approximately for large banks.
+    pub index: IndexKind,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Whether to keep embeddings on disk and reuse them across sessions.
+    pub embeddings: bool,
+    /// Defaults to `~/.cache/summoning-circle` (or under `$XDG_CACHE_HOME`).
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Where the embedded notes folder is kept between runs.
+    pub index_path: PathBuf,
+    /// Tokens per chunk that long notes are split into.
+    pub chunk_tokens: usize,
+    /// How many notes a search lists.
+    pub results: usize,
+    /// How much keyword matches count against semantic similarity, from 0 to 1.
+    pub keyword_weight: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextConfig {
+    /// How notes too long for a prompt are cut down.
+    pub fit: Fit,
+    /// Tokens kept free for the answer, and in pipelines for the steps' answers.
+    pub reserve: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranscriptsConfig {
+    /// Whether every run is logged, request by request, for `history`.
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// How many runs `history` lists.
+    pub list: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    pub prompt: String,
+    #[serde(default)]
+    pub params: GenerationParams,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub profile: String,
+    pub notes_dir: PathBuf,
+    /// Templates here replace the built-in prompts of the same name.
+    pub prompts_dir: PathBuf,
+    /// Pipelines here replace the built-in ones of the same name, or add new spirits.
+    pub pipelines_dir: PathBuf,
+    /// How many of a pipeline's independent requests are sent at once.
+    pub parallel_requests: usize,
+    pub backends: HashMap<String, BackendProfile>,
+    /// Generation params applied to every request.
+    #[serde(default)]
+    pub defaults: GenerationParams,
+    /// Generation params for a single command, keyed by `Command::key`.
+    #[serde(default)]
+    pub commands: HashMap<String, GenerationParams>,
+    pub notes: NotesConfig,
+    pub agent: AgentConfig,
+    pub cache: CacheConfig,
+    pub search: SearchConfig,
+    pub context: ContextConfig,
+    pub transcripts: TranscriptsConfig,
+    pub personas: HashMap<String, Persona>,
+    /// Loaded from `prompts_dir` once the rest of the config is known.
+    #[serde(skip)]
+    pub templates: Templates,
+    /// Loaded from `pipelines_dir`, and checked against `templates`.
+    #[serde(skip)]
+    pub pipelines: Pipelines,
+}
+
+/// Settings given on the command line, which win over files and env vars.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigOverrides {
+    pub config_path: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub notes_dir: Option<PathBuf>,
+    pub seed: Option<u64>,
+    pub chat_model: Option<String>,
+    pub completion_model: Option<String>,
+    pub search_results: Option<usize>,
+    pub keyword_weight: Option<f64>,
+    pub history_limit: Option<usize>,
+    pub weighting: Option<Weighting>,
+    pub tags: Vec<String>,
+}
+
+impl Config {
+    /// Builds the configuration from, in increasing priority: the built-in
+    /// defaults, the user config, `./summoning-circle.toml`, a `--config`
+    /// file, environment variables and command line flags.
+    pub fn load(overrides: &ConfigOverrides) -> Result<Config, ConfigError> {
+        let mut merged = parse_table(BUILTIN_CONFIG, "built-in defaults")?;
+
+        let mut files = vec![];
+        if let Some(path) = user_config_path() {
+            files.push(path);
+        }
+        files.push(PathBuf::from(PROJECT_CONFIG));
+
+        for path in files.iter().filter(|path| path.exists()) {
+            merge(&mut merged, read_table(path)?);
+        }
+        if let Some(path) = &overrides.config_path {
+            merge(&mut merged, read_table(path)?);
+        }
+
+        let mut config = Config::from_table(merged)?;
+
+        // Pick the profile first so env vars land on the one actually used.
+        if let Some(profile) = overrides.profile.clone().or(env_value("PROFILE")?) {
+            config.profile = profile;
+        }
+        config.apply_env()?;
+        config.apply_overrides(overrides);
+        config.validate()?;
+        config.templates = Templates::load(&config.prompts_dir)?;
+        config.pipelines = Pipelines::load(&config.pipelines_dir, &config.templates)?;
+
+        Ok(config)
+    }
+
+    fn from_table(table: Table) -> Result<Config, ConfigError> {
+        Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::ParseError("merged configuration".to_string(), e.message().to_string()))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(notes_dir) = env_value::<String>("NOTES_DIR")? {
+            self.notes_dir = PathBuf::from(notes_dir);
+        }
+
+        if let Some(backend) = self.backends.get_mut(&self.profile) {
+            if let Some(api_path) = env_value("API_PATH")? {
+                backend.api_path = Some(api_path);
+            }
+            if let Some(model) = env_value("COMPLETION_MODEL")? {
+                backend.completion_model = Some(model);
+            }
+            if let Some(model) = env_value("CHAT_MODEL")? {
+                backend.chat_model = Some(model);
+            }
+            if let Some(model) = env_value("EMBEDDING_MODEL")? {
+                backend.embedding_model = Some(model);
+            }
+            if let Some(max_retries) = env_value("MAX_RETRIES")? {
+                backend.max_retries = Some(max_retries);
+            }
+            if let Some(requests) = env_value("REQUESTS_PER_MINUTE")? {
+                backend.requests_per_minute = Some(requests);
+            }
+        }
+
+        if let Some(max_tokens) = env_value("MAX_TOKENS")? {
+            self.defaults.max_tokens = Some(max_tokens);
+        }
+        if let Some(temperature) = env_value("TEMPERATURE")? {
+            self.defaults = self.defaults.clone().temperature(temperature);
+        }
+        if let Some(seed) = env_value("SEED")? {
+            self.defaults = self.defaults.clone().seed(seed);
+        }
+
+        Ok(())
+    }
+
+    fn apply_overrides(&mut self, overrides: &ConfigOverrides) {
+        if let Some(profile) = &overrides.profile {
+            self.profile = profile.clone();
+        }
+        if let Some(notes_dir) = &overrides.notes_dir {
+            self.notes_dir = notes_dir.clone();
+        }
+        if let Some(seed) = overrides.seed {
+            self.defaults.seed = Some(seed);
+        }
+        if let Some(weighting) = overrides.weighting {
+            self.notes.weighting = weighting;
+        }
+        if !overrides.tags.is_empty() {
+            self.notes.tags = overrides.tags.clone();
+        }
+        if let Some(results) = overrides.search_results {
+            self.search.results = results;
+        }
+        if let Some(keyword_weight) = overrides.keyword_weight {
+            self.search.keyword_weight = keyword_weight;
+        }
+        if let Some(limit) = overrides.history_limit {
+            self.transcripts.list = limit;
+        }
+
+        if let Some(backend) = self.backends.get_mut(&self.profile) {
+            if let Some(model) = &overrides.chat_model {
+                backend.chat_model = Some(model.clone());
+            }
+            if let Some(model) = &overrides.completion_model {
+                backend.completion_model = Some(model.clone());
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.backend()?;
+        self.persona()?;
+        Ok(())
+    }
+
+    pub fn backend(&self) -> Result<&BackendProfile, ConfigError> {
+        self.backends.get(&self.profile).ok_or_else(|| {
+            ConfigError::UnknownProfile(self.profile.clone(), sorted_keys(&self.backends))
+        })
+    }
+
+    /// The context window of `model`, unless the profile sets its own.
+    pub fn context_window(&self, model: Option<&str>) -> usize {
+        match self.backend().ok().and_then(|profile| profile.context_window) {
+            Some(window) => window,
+            None => tokens::context_window(model)
+        }
+    }
+
+    /// Where embeddings are cached, if they are.
+    pub fn embedding_cache_dir(&self) -> Option<PathBuf> {
+        if !self.cache.embeddings {
+            return None;
+        }
+        self.cache.dir.clone().or_else(user_cache_dir).map(|dir| dir.join("embeddings"))
+    }
+
+    pub fn note_source(&self) -> NoteSource {
+        NoteSource::new(self.notes_dir.clone())
+            .recursive(self.notes.recursive)
+            .with_include(&self.notes.include)
+            .with_exclude(&self.notes.exclude)
+    }
+
+    /// The memory store of the selected persona.
+    pub fn memory_path(&self) -> PathBuf {
+        self.agent.memory_dir.join(format!("{}.json", self.agent.persona))
+    }
+
+    pub fn persona(&self) -> Result<&Persona, ConfigError> {
+        self.personas.get(&self.agent.persona).ok_or_else(|| {
+            ConfigError::UnknownPersona(self.agent.persona.clone(), sorted_keys(&self.personas))
+        })
+    }
+
+    pub fn pipeline(&self, name: &str) -> Result<&Pipeline, ConfigError> {
+        self.pipelines.get(name).ok_or_else(|| ConfigError::UnknownPipeline(name.to_string(), self.pipelines.names()))
+    }
+
+    /// Connection details for the selected profile. The API key comes from
+    /// `API_KEY` if set, then the profile's `api_key`, then `api_key_env`.
+    pub fn environment(&self) -> Result<Environment, ConfigError> {
+        let backend = self.backend()?;
+        let key = |name: &str| format!("backends.{}.{}", self.profile, name);
+
+        let api_path = backend.api_path.clone()
+            .ok_or_else(|| ConfigError::MissingKey(format!("{} (or set API_PATH)", key("api_path"))))?;
+
+        let api_key = match env_value("API_KEY")? {
+            Some(api_key) => Some(api_key),
+            None => match (&backend.api_key, &backend.api_key_env) {
+                (Some(api_key), _) => Some(api_key.clone()),
+                (None, Some(name)) => env_value(name)?,
+                (None, None) => None
+            }
+        };
+        let api_key = api_key.ok_or_else(|| {
+            let variable = backend.api_key_env.as_deref().unwrap_or("API_KEY");
+            ConfigError::MissingKey(format!("{} (or set {})", key("api_key"), variable))
+        })?;
+
+        Ok(Environment { api_path, api_key })
+    }
+
+    /// Generation params for one command: the global defaults with the
+    /// command's own table on top.
+    pub fn command_params(&self, command: &str) -> GenerationParams {
+        match self.commands.get(command) {
+            Some(params) => self.defaults.overlay(params),
+            None => self.defaults.clone()
+        }
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config")
+    };
+    Some(base.join("summoning-circle").join("config.toml"))
+}
+
+fn user_cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache")
+    };
+    Some(base.join("summoning-circle"))
+}
+
+fn read_table(path: &Path) -> Result<Table, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::IOError(path.to_path_buf(), e))?;
+    parse_table(&text, &path.display().to_string())
+}
+
+fn parse_table(text: &str, source: &str) -> Result<Table, ConfigError> {
+    text.parse::<Table>().map_err(|e| ConfigError::ParseError(source.to_string(), e.message().to_string()))
+}
+
+/// Merges `overlay` into `base`, recursing into tables so a file only has to
+/// mention the keys it changes.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => merge(base_table, overlay_table),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn sorted_keys<V>(map: &HashMap<String, V>) -> Vec<String> {
+    let mut keys = map.keys().cloned().collect::<Vec<String>>();
+    keys.sort();
+    keys
+}
+
+pub fn env_value<T: FromStr>(name: &str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(name) {
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(value)) => Err(ConfigError::InvalidEnvironment(name.to_string(), value.to_string_lossy().to_string())),
+        Ok(value) => value.trim().parse::<T>()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidEnvironment(name.to_string(), value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layered(files: &[&str]) -> Result<Config, ConfigError> {
+        let mut merged = parse_table(BUILTIN_CONFIG, "built-in defaults")?;
+        for (i, text) in files.iter().enumerate() {
+            merge(&mut merged, parse_table(text, &format!("file {}", i))?);
+        }
+        let config = Config::from_table(merged)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn builtin_defaults_are_valid() {
+        let config = layered(&[]).unwrap();
+
+        assert_eq!(config.profile, "openai");
+        assert_eq!(config.backend().unwrap().chat_model.as_deref(), Some("gpt-3.5-turbo"));
+        assert_eq!(config.agent.memory_rounds, 3);
+        assert_eq!(config.notes.weighting, Weighting::Uniform);
+    }
+
+    #[test]
+    fn later_files_override_individual_keys() {
+        let user = r#"
+            [backends.local]
+            api_path = "http://localhost:8080/v1"
+            api_key = "none"
+            chat_model = "llama"
+
+            [defaults]
+            max_tokens = 200
+        "#;
+        let project = r#"
+            profile = "local"
+
+            [commands.critic]
+            max_tokens = 500
+            temperature = 0.1
+        "#;
+
+        let config = layered(&[user, project]).unwrap();
+
+        assert_eq!(config.profile, "local");
+        assert_eq!(config.environment().unwrap().api_path, "http://localhost:8080/v1");
+        assert!(config.backends.contains_key("openai"));
+        assert_eq!(config.command_params("critic").max_tokens, Some(500));
+        assert_eq!(config.command_params("actor").max_tokens, Some(200));
+    }
+
+    #[test]
+    fn reports_unknown_profiles_and_keys() {
+        assert!(matches!(layered(&["profile = \"nope\""]), Err(ConfigError::UnknownProfile(_, _))));
+        assert!(matches!(layered(&["[agent]\npersona = \"nobody\""]), Err(ConfigError::UnknownPersona(_, _))));
+        assert!(matches!(layered(&["notes_directory = \"x\""]), Err(ConfigError::ParseError(_, _))));
+        assert!(matches!(layered(&["profile = "]), Err(ConfigError::ParseError(_, _))));
+    }
+
+    #[test]
+    fn command_line_overrides_win() {
+        let mut config = layered(&["[defaults]\nseed = 1"]).unwrap();
+        let overrides = ConfigOverrides {
+            profile: Some("openai".to_string()),
+            seed: Some(9),
+            chat_model: Some("gpt-4".to_string()),
+            weighting: Some(Weighting::Staleness),
+            tags: vec!["systems".to_string()],
+            ..ConfigOverrides::default()
+        };
+
+        config.apply_overrides(&overrides);
+
+        assert_eq!(config.defaults.seed, Some(9));
+        assert_eq!(config.backend().unwrap().chat_model.as_deref(), Some("gpt-4"));
+        assert_eq!(config.notes.weighting, Weighting::Staleness);
+        assert_eq!(config.notes.tags, ["systems"]);
+    }
+}
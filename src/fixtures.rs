@@ -0,0 +1,303 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+
+use crate::backend::CompletionBackend;
+use crate::chat::{ChatMessage, Role};
+use crate::openai::{Embedding, OpenAIError};
+use crate::params::GenerationParams;
+
+#[derive(Debug)]
+pub enum FixtureError {
+    IOError(std::io::Error),
+    ParseError(String)
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixtureError::IOError(error) => write!(f, "could not access fixture file: {}", error),
+            FixtureError::ParseError(message) => write!(f, "invalid fixture file: {}", message)
+        }
+    }
+}
+
+impl From<std::io::Error> for FixtureError {
+    fn from(io_error: std::io::Error) -> Self {
+        FixtureError::IOError(io_error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    Completions,
+    ChatCompletions,
+    Embeddings
+}
+
+impl Endpoint {
+    pub fn path(&self) -> &'static str {
+        match self {
+            Endpoint::Completions => "/completions",
+            Endpoint::ChatCompletions => "/chat/completions",
+            Endpoint::Embeddings => "/embeddings"
+        }
+    }
+
+    fn from_path(path: &str) -> Option<Endpoint> {
+        match path {
+            "/completions" => Some(Endpoint::Completions),
+            "/chat/completions" => Some(Endpoint::ChatCompletions),
+            "/embeddings" => Some(Endpoint::Embeddings),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub endpoint: Endpoint,
+    pub input: String,
+    pub output: Value
+}
+
+/// The `input` recorded for a chat exchange: the prompt itself for a single
+/// user message, so older fixtures keep matching, or else the JSON transcript.
+fn chat_input(messages: &[ChatMessage]) -> String {
+    match messages {
+        [ChatMessage { role: Role::User, content }] => content.clone(),
+        _ => serde_json::to_string(messages).unwrap()
+    }
+}
+
+pub fn load_exchanges(path: &Path) -> Result<Vec<Exchange>, FixtureError> {
+    let text = fs::read_to_string(path)?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| FixtureError::ParseError(e.to_string()))?;
+
+    let entries = json.as_array()
+        .ok_or_else(|| FixtureError::ParseError("expected a list of exchanges".to_string()))?;
+
+    entries.iter().map(|entry| {
+        let endpoint = entry["endpoint"].as_str()
+            .and_then(Endpoint::from_path)
+            .ok_or_else(|| FixtureError::ParseError(format!("unknown endpoint {}", entry["endpoint"])))?;
+        let input = entry["input"].as_str()
+            .ok_or_else(|| FixtureError::ParseError("exchange is missing its input".to_string()))?;
+
+        Ok(Exchange { endpoint, input: input.to_string(), output: entry["output"].clone() })
+    }).collect()
+}
+
+pub fn save_exchanges(path: &Path, exchanges: &[Exchange]) -> Result<(), FixtureError> {
+    let entries = exchanges.iter().map(|exchange| json!({
+        "endpoint": exchange.endpoint.path(),
+        "input": exchange.input,
+        "output": exchange.output
+    })).collect::<Vec<Value>>();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let text = serde_json::to_string_pretty(&entries).map_err(|e| FixtureError::ParseError(e.to_string()))?;
+    fs::write(path, text)?;
+    Ok(())
+}
+
+/// Passes every call through to `inner` and appends the exchange to a
+/// fixture file, rewriting it after each call so a crashed run still leaves
+/// a usable recording behind.
+pub struct RecordingBackend<B: CompletionBackend> {
+    inner: B,
+    path: PathBuf,
+    exchanges: Mutex<Vec<Exchange>>
+}
+
+impl<B: CompletionBackend> RecordingBackend<B> {
+    pub fn new(inner: B, path: PathBuf) -> RecordingBackend<B> {
+        RecordingBackend { inner, path, exchanges: Mutex::new(Vec::new()) }
+    }
+
+    fn record(&self, endpoint: Endpoint, input: &str, output: Value) {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.push(Exchange { endpoint, input: input.to_string(), output });
+
+        if let Err(error) = save_exchanges(&self.path, &exchanges) {
+            eprintln!("Error: {}", error);
+        }
+    }
+}
+
+impl<B: CompletionBackend> CompletionBackend for RecordingBackend<B> {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        let result = self.inner.complete(prompt, params)?;
+        self.record(Endpoint::Completions, prompt, json!(result));
+        Ok(result)
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        let result = self.inner.chat_messages(messages, params)?;
+        self.record(Endpoint::ChatCompletions, &chat_input(messages), json!(result));
+        Ok(result)
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        let result = self.inner.embed(input)?;
+        self.record(Endpoint::Embeddings, input, json!(result));
+        Ok(result)
+    }
+
+    /// Recorded one exchange per input, so a replay can answer either way.
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        let results = self.inner.embed_batch(inputs)?;
+        for (input, result) in inputs.iter().zip(&results) {
+            self.record(Endpoint::Embeddings, input, json!(result));
+        }
+        Ok(results)
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        self.inner.embedding_model()
+    }
+
+    fn completion_defaults(&self) -> GenerationParams {
+        self.inner.completion_defaults()
+    }
+
+    fn chat_defaults(&self) -> GenerationParams {
+        self.inner.chat_defaults()
+    }
+
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let result = self.inner.complete_stream(prompt, params, on_token)?;
+        self.record(Endpoint::Completions, prompt, json!(result));
+        Ok(result)
+    }
+
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let result = self.inner.chat_messages_stream(messages, params, on_token)?;
+        self.record(Endpoint::ChatCompletions, &chat_input(messages), json!(result));
+        Ok(result)
+    }
+}
+
+/// Plays back a recording made by `RecordingBackend`.
+///
+/// Each call is answered by the first unused exchange with the same endpoint
+/// and input. Prompts that pick a random question never match exactly, so
+/// failing that, completions and chats take the next unused exchange for
+/// their endpoint. Embeddings must match, since another text's vector would
+/// quietly skew search and recall.
+pub struct ReplayBackend {
+    exchanges: Vec<Exchange>,
+    used: Mutex<Vec<bool>>
+}
+
+impl ReplayBackend {
+    pub fn new(exchanges: Vec<Exchange>) -> ReplayBackend {
+        let used = Mutex::new(vec![false; exchanges.len()]);
+        ReplayBackend { exchanges, used }
+    }
+
+    pub fn from_file(path: &Path) -> Result<ReplayBackend, FixtureError> {
+        Ok(ReplayBackend::new(load_exchanges(path)?))
+    }
+
+    fn replay(&self, endpoint: Endpoint, input: &str) -> Result<&Value, OpenAIError> {
+        let mut used = self.used.lock().unwrap();
+
+        let candidates = || self.exchanges.iter().enumerate()
+            .filter(|(i, exchange)| !used[*i] && exchange.endpoint == endpoint);
+
+        let index = candidates()
+            .find(|(_, exchange)| exchange.input == input)
+            .or_else(|| candidates().next().filter(|_| endpoint != Endpoint::Embeddings))
+            .map(|(i, _)| i)
+            .ok_or_else(|| OpenAIError::Transport(format!("no recorded {} exchange left for input {:?}", endpoint.path(), input)))?;
+
+        used[index] = true;
+        Ok(&self.exchanges[index].output)
+    }
+
+    fn replay_text(&self, endpoint: Endpoint, input: &str) -> Result<String, OpenAIError> {
+        self.replay(endpoint, input)?
+            .as_str()
+            .map(|text| text.to_string())
+            .ok_or_else(|| OpenAIError::MalformedResponse(format!("recorded {} output is not text", endpoint.path())))
+    }
+}
+
+impl CompletionBackend for ReplayBackend {
+    fn complete(&self, prompt: &str, _params: &GenerationParams) -> Result<String, OpenAIError> {
+        self.replay_text(Endpoint::Completions, prompt)
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], _params: &GenerationParams) -> Result<String, OpenAIError> {
+        self.replay_text(Endpoint::ChatCompletions, &chat_input(messages))
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        self.replay(Endpoint::Embeddings, input)?
+            .as_array()
+            .and_then(|values| values.iter().map(|x| x.as_f64()).collect::<Option<Embedding>>())
+            .ok_or_else(|| OpenAIError::MalformedResponse("recorded embedding is not a list of numbers".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::test_support::scratch_dir;
+
+
+    #[test]
+    fn records_and_replays_exchanges() {
+        let path = scratch_dir("record.json");
+        let inner = MockBackend::new().with_completions(&["a compressed thought"]).with_chats(&["hi"]);
+
+        let recorder = RecordingBackend::new(inner, path.clone());
+        let params = GenerationParams::new();
+        recorder.complete("compress this", &params).unwrap();
+        recorder.chat("say hi", &params).unwrap();
+        recorder.chat_messages(&[ChatMessage::system("be terse"), ChatMessage::user("say hi")], &params).unwrap();
+        let embedding = recorder.embed("some text").unwrap();
+        let batch = recorder.embed_batch(&["more", "text"]).unwrap();
+
+        let replay = ReplayBackend::from_file(&path).unwrap();
+        assert_eq!(replay.chat("say hi", &params).unwrap(), "hi");
+        assert_eq!(replay.chat_messages(&[ChatMessage::system("be terse"), ChatMessage::user("say hi")], &params).unwrap(), "mock response");
+        assert_eq!(replay.complete("compress this", &params).unwrap(), "a compressed thought");
+        assert_eq!(replay.embed("some text").unwrap(), embedding);
+        assert_eq!(replay.embed_batch(&["more", "text"]).unwrap(), batch);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_falls_back_to_recorded_order() {
+        let replay = ReplayBackend::new(vec![
+            Exchange { endpoint: Endpoint::Completions, input: "one".to_string(), output: json!("first") },
+            Exchange { endpoint: Endpoint::Completions, input: "two".to_string(), output: json!("second") },
+        ]);
+
+        let params = GenerationParams::new();
+        assert_eq!(replay.complete("two", &params).unwrap(), "second");
+        assert_eq!(replay.complete("something else", &params).unwrap(), "first");
+        assert!(replay.complete("one too many", &params).is_err());
+        assert!(replay.embed("anything").is_err());
+    }
+
+    #[test]
+    fn replay_only_returns_embeddings_recorded_for_the_input() {
+        let replay = ReplayBackend::new(vec![
+            Exchange { endpoint: Endpoint::Embeddings, input: "loops".to_string(), output: json!([1.0, 0.0]) },
+        ]);
+
+        assert!(replay.embed("gardens").is_err());
+        assert_eq!(replay.embed("loops").unwrap(), vec![1.0, 0.0]);
+    }
+}
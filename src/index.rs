@@ -0,0 +1,523 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+
+#[derive(Debug, PartialEq)]
+pub enum IndexError {
+    DimensionMismatch { expected: usize, actual: usize },
+    /// A vector with no direction, e.g. the embedding of a blank note.
+    ZeroVector
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::DimensionMismatch { expected, actual } => write!(f, "expected a vector of {} dimensions, got {}", expected, actual),
+            IndexError::ZeroVector => write!(f, "cannot index a zero vector")
+        }
+    }
+}
+
+/// A set of vectors searchable by cosine similarity.
+///
+/// Vectors are normalised to `f32` on insert, so scoring a match is a dot
+/// product. Every vector in an index must have the same dimensions.
+pub trait VectorIndex {
+    /// Adds `vector` under `id`, replacing any vector already stored there.
+    fn insert(&mut self, id: usize, vector: &[f64]) -> Result<(), IndexError>;
+    /// Returns whether `id` was in the index.
+    fn remove(&mut self, id: usize) -> bool;
+    /// Up to `k` ids with their cosine similarity to `query`, best first.
+    fn search(&self, query: &[f64], k: usize) -> Vec<(usize, f32)>;
+    fn len(&self) -> usize;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexKind {
+    /// Exact, scoring every vector on every search.
+    #[default]
+    BruteForce,
+    /// Approximate, for memory banks too large to scan.
+    Hnsw
+}
+
+impl IndexKind {
+    pub fn build(self) -> Box<dyn VectorIndex> {
+        match self {
+            IndexKind::BruteForce => Box::new(BruteForceIndex::new()),
+            IndexKind::Hnsw => Box::new(HnswIndex::new())
+        }
+    }
+}
+
+fn normalise(vector: &[f64]) -> Option<Vec<f32>> {
+    let magnitude = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if magnitude == 0.0 || !magnitude.is_finite() {
+        return None;
+    }
+    Some(vector.iter().map(|x| (x / magnitude) as f32).collect())
+}
+
+/// The cosine similarity of `a` and `b`, or 0 for vectors of different
+/// dimensions or without a direction.
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let magnitude = |vector: &[f64]| vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let magnitudes = magnitude(a) * magnitude(b);
+    if a.len() != b.len() || magnitudes == 0.0 || !magnitudes.is_finite() {
+        return 0.0;
+    }
+    a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>() / magnitudes
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Checks `vector` against the index's dimensions, fixing them on first use.
+fn prepare(dimensions: &mut Option<usize>, vector: &[f64]) -> Result<Vec<f32>, IndexError> {
+    if let Some(expected) = *dimensions {
+        if vector.len() != expected {
+            return Err(IndexError::DimensionMismatch { expected, actual: vector.len() });
+        }
+    }
+    let normalised = normalise(vector).ok_or(IndexError::ZeroVector)?;
+    *dimensions = Some(vector.len());
+    Ok(normalised)
+}
+
+/// Normalises a query, or `None` if nothing in the index could match it.
+fn prepare_query(dimensions: Option<usize>, query: &[f64]) -> Option<Vec<f32>> {
+    if dimensions != Some(query.len()) {
+        return None;
+    }
+    normalise(query)
+}
+
+pub struct BruteForceIndex {
+    dimensions: Option<usize>,
+    vectors: HashMap<usize, Vec<f32>>,
+}
+
+impl BruteForceIndex {
+    pub fn new() -> BruteForceIndex {
+        BruteForceIndex { dimensions: None, vectors: HashMap::new() }
+    }
+}
+
+impl Default for BruteForceIndex {
+    fn default() -> Self {
+        BruteForceIndex::new()
+    }
+}
+
+impl VectorIndex for BruteForceIndex {
+    fn insert(&mut self, id: usize, vector: &[f64]) -> Result<(), IndexError> {
+        let vector = prepare(&mut self.dimensions, vector)?;
+        self.vectors.insert(id, vector);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> bool {
+        self.vectors.remove(&id).is_some()
+    }
+
+    fn search(&self, query: &[f64], k: usize) -> Vec<(usize, f32)> {
+        let Some(query) = prepare_query(self.dimensions, query) else {
+            return Vec::new();
+        };
+
+        let mut matches = self.vectors.iter()
+            .map(|(id, vector)| (*id, dot(&query, vector)))
+            .collect::<Vec<(usize, f32)>>();
+        matches.sort_by(|(a_id, a), (b_id, b)| b.total_cmp(a).then(a_id.cmp(b_id)));
+        matches.truncate(k);
+        matches
+    }
+
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+}
+
+/// A node in the search graph, scored by similarity to some query.
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+    score: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score).then(other.node.cmp(&self.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Node {
+    id: usize,
+    vector: Vec<f32>,
+    /// Neighbours on each layer this node is part of, from layer 0 up.
+    neighbours: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+/// A hierarchical navigable small world graph (Malkov & Yashunin, 2016).
+///
+/// Removed vectors are only marked as deleted, so the graph stays connected
+/// through them; once they outnumber the live ones the graph is rebuilt.
+pub struct HnswIndex {
+    /// Neighbours kept per node above layer 0, which keeps twice as many.
+    max_neighbours: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    level_scale: f64,
+    rng: StdRng,
+    dimensions: Option<usize>,
+    nodes: Vec<Node>,
+    ids: HashMap<usize, usize>,
+    entry_point: Option<usize>,
+}
+
+impl HnswIndex {
+    pub fn new() -> HnswIndex {
+        HnswIndex::with_params(16, 100, 64)
+    }
+
+    pub fn with_params(max_neighbours: usize, ef_construction: usize, ef_search: usize) -> HnswIndex {
+        HnswIndex {
+            max_neighbours,
+            ef_construction,
+            ef_search,
+            level_scale: 1.0 / (max_neighbours as f64).ln(),
+            // levels only shape the graph, so a fixed seed keeps builds reproducible
+            rng: StdRng::seed_from_u64(0x5eed),
+            dimensions: None,
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry_point: None,
+        }
+    }
+
+    fn layer_capacity(&self, layer: usize) -> usize {
+        if layer == 0 { self.max_neighbours * 2 } else { self.max_neighbours }
+    }
+
+    fn top_layer(&self) -> usize {
+        self.entry_point.map_or(0, |entry| self.nodes[entry].neighbours.len() - 1)
+    }
+
+    fn score(&self, query: &[f32], node: usize) -> Candidate {
+        Candidate { score: dot(query, &self.nodes[node].vector), node }
+    }
+
+    /// Greedy beam search on one layer, returning up to `ef` nodes best first.
+    fn search_layer(&self, query: &[f32], entry_points: &[Candidate], ef: usize, layer: usize) -> Vec<Candidate> {
+        let mut visited = vec![false; self.nodes.len()];
+        for entry in entry_points {
+            visited[entry.node] = true;
+        }
+        let mut candidates = entry_points.iter().copied().collect::<BinaryHeap<Candidate>>();
+        let mut results = entry_points.iter().copied().map(Reverse).collect::<BinaryHeap<Reverse<Candidate>>>();
+
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map_or(f32::NEG_INFINITY, |Reverse(worst)| worst.score);
+            if candidate.score < worst && results.len() >= ef {
+                break;
+            }
+
+            for &neighbour in &self.nodes[candidate.node].neighbours[layer] {
+                if std::mem::replace(&mut visited[neighbour], true) {
+                    continue;
+                }
+
+                let next = self.score(query, neighbour);
+                let worst = results.peek().map_or(f32::NEG_INFINITY, |Reverse(worst)| worst.score);
+                if results.len() < ef || next.score > worst {
+                    candidates.push(next);
+                    results.push(Reverse(next));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut results = results.into_iter().map(|Reverse(c)| c).collect::<Vec<Candidate>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    /// Descends from the entry point to `layer`, keeping only the best node.
+    fn descend(&self, query: &[f32], layer: usize) -> Vec<Candidate> {
+        let Some(entry) = self.entry_point else {
+            return Vec::new();
+        };
+
+        let mut nearest = vec![self.score(query, entry)];
+        for upper in (layer + 1..=self.top_layer()).rev() {
+            nearest.truncate(1);
+            nearest = self.search_layer(query, &nearest, 1, upper);
+        }
+        nearest
+    }
+
+    /// Picks up to `limit` of `candidates` (best first), skipping any that is
+    /// closer to an already picked neighbour than to the node itself, so the
+    /// links fan out in different directions instead of into one cluster.
+    fn select_neighbours(&self, candidates: &[Candidate], limit: usize) -> Vec<usize> {
+        let mut selected = Vec::with_capacity(limit);
+
+        for candidate in candidates {
+            if selected.len() == limit {
+                break;
+            }
+            let vector = &self.nodes[candidate.node].vector;
+            if selected.iter().all(|&picked: &usize| dot(vector, &self.nodes[picked].vector) < candidate.score) {
+                selected.push(candidate.node);
+            }
+        }
+
+        selected
+    }
+
+    fn connect(&mut self, from: usize, to: usize, layer: usize) {
+        self.nodes[from].neighbours[layer].push(to);
+
+        let capacity = self.layer_capacity(layer);
+        if self.nodes[from].neighbours[layer].len() > capacity {
+            let vector = &self.nodes[from].vector;
+            let mut candidates = self.nodes[from].neighbours[layer].iter()
+                .map(|&n| self.score(vector, n))
+                .collect::<Vec<Candidate>>();
+            candidates.sort_by(|a, b| b.cmp(a));
+            self.nodes[from].neighbours[layer] = self.select_neighbours(&candidates, capacity);
+        }
+    }
+
+    fn add_node(&mut self, id: usize, vector: Vec<f32>) {
+        let level = (-self.rng.gen::<f64>().max(f64::MIN_POSITIVE).ln() * self.level_scale) as usize;
+        let node = self.nodes.len();
+        let top_layer = self.top_layer();
+
+        let mut nearest = self.descend(&vector, level);
+        self.nodes.push(Node { id, vector, neighbours: vec![Vec::new(); level + 1], deleted: false });
+        self.ids.insert(id, node);
+
+        if self.entry_point.is_none() {
+            self.entry_point = Some(node);
+            return;
+        }
+
+        for layer in (0..=level.min(top_layer)).rev() {
+            nearest = self.search_layer(&self.nodes[node].vector, &nearest, self.ef_construction, layer);
+            let neighbours = self.select_neighbours(&nearest, self.max_neighbours);
+
+            for neighbour in neighbours {
+                self.connect(node, neighbour, layer);
+                self.connect(neighbour, node, layer);
+            }
+        }
+
+        if level > top_layer {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Rebuilds the graph from the live nodes, dropping the deleted ones.
+    fn rebuild(&mut self) {
+        let live = std::mem::take(&mut self.nodes).into_iter()
+            .filter(|node| !node.deleted)
+            .map(|node| (node.id, node.vector))
+            .collect::<Vec<(usize, Vec<f32>)>>();
+
+        self.ids.clear();
+        self.entry_point = None;
+        for (id, vector) in live {
+            self.add_node(id, vector);
+        }
+    }
+}
+
+impl Default for HnswIndex {
+    fn default() -> Self {
+        HnswIndex::new()
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn insert(&mut self, id: usize, vector: &[f64]) -> Result<(), IndexError> {
+        let vector = prepare(&mut self.dimensions, vector)?;
+        self.remove(id);
+        self.add_node(id, vector);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> bool {
+        let Some(node) = self.ids.remove(&id) else {
+            return false;
+        };
+        self.nodes[node].deleted = true;
+
+        if self.ids.len() * 2 < self.nodes.len() {
+            self.rebuild();
+        }
+        true
+    }
+
+    fn search(&self, query: &[f64], k: usize) -> Vec<(usize, f32)> {
+        let Some(query) = prepare_query(self.dimensions, query) else {
+            return Vec::new();
+        };
+
+        let nearest = self.descend(&query, 0);
+        // deleted nodes still take up room in the beam, so widen it to make up for them
+        let deleted = self.nodes.len() - self.ids.len();
+        self.search_layer(&query, &nearest, self.ef_search.max(k) + deleted.min(k), 0)
+            .into_iter()
+            .filter(|c| !self.nodes[c.node].deleted)
+            .take(k)
+            .map(|c| (self.nodes[c.node].id, c.score))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    fn random_vectors(count: usize, dimensions: usize, seed: u64) -> Vec<Vec<f64>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count).map(|_| (0..dimensions).map(|_| rng.gen_range(-1.0..1.0)).collect()).collect()
+    }
+
+    /// Points scattered around random centres, closer to how embeddings of
+    /// related texts bunch together than uniform noise is.
+    fn clustered_vectors(count: usize, centres: &[Vec<f64>], seed: u64) -> Vec<Vec<f64>> {
+        random_vectors(count, centres[0].len(), seed).into_iter().enumerate()
+            .map(|(i, noise)| centres[i % centres.len()].iter().zip(noise).map(|(c, n)| c + n).collect())
+            .collect()
+    }
+
+    fn build(kind: IndexKind, vectors: &[Vec<f64>]) -> Box<dyn VectorIndex> {
+        let mut index = kind.build();
+        for (id, vector) in vectors.iter().enumerate() {
+            index.insert(id, vector).unwrap();
+        }
+        index
+    }
+
+    /// The share of the exact top `k` that `index` also finds.
+    fn recall_at(k: usize, index: &dyn VectorIndex, exact: &dyn VectorIndex, queries: &[Vec<f64>]) -> f64 {
+        let found = queries.iter().map(|query| {
+            let expected = exact.search(query, k).into_iter().map(|(id, _)| id).collect::<HashSet<usize>>();
+            index.search(query, k).iter().filter(|(id, _)| expected.contains(id)).count()
+        }).sum::<usize>();
+
+        found as f64 / (k * queries.len()) as f64
+    }
+
+    #[test]
+    fn searches_by_cosine_similarity() {
+        for kind in [IndexKind::BruteForce, IndexKind::Hnsw] {
+            let index = build(kind, &[vec![1.0, 0.1], vec![0.0, 1.0], vec![3.0, 0.0], vec![-1.0, 0.0]]);
+
+            let results = index.search(&[2.0, 0.0], 3);
+
+            assert_eq!(results.iter().map(|(id, _)| *id).collect::<Vec<usize>>(), [2, 0, 1]);
+            assert!((results[0].1 - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn scores_pairs_of_vectors() {
+        assert!((cosine_similarity(&[3.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-12);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn rejects_mismatched_and_zero_vectors() {
+        for kind in [IndexKind::BruteForce, IndexKind::Hnsw] {
+            let mut index = build(kind, &[vec![1.0, 0.0]]);
+
+            assert_eq!(index.insert(1, &[1.0]), Err(IndexError::DimensionMismatch { expected: 2, actual: 1 }));
+            assert_eq!(index.insert(1, &[0.0, 0.0]), Err(IndexError::ZeroVector));
+            assert!(index.search(&[1.0, 0.0, 0.0], 1).is_empty());
+            assert!(index.search(&[0.0, 0.0], 1).is_empty());
+            assert_eq!(index.len(), 1);
+        }
+    }
+
+    #[test]
+    fn inserts_and_removes_incrementally() {
+        for kind in [IndexKind::BruteForce, IndexKind::Hnsw] {
+            let vectors = random_vectors(300, 16, 1);
+            let mut index = build(kind, &vectors);
+
+            for id in (0..300).step_by(2) {
+                assert!(index.remove(id));
+            }
+            assert!(!index.remove(0));
+            index.insert(7, &vectors[0]).unwrap();
+
+            assert_eq!(index.len(), 150);
+            assert_eq!(index.search(&vectors[0], 1)[0].0, 7);
+            assert!(index.search(&vectors[10], 150).iter().all(|(id, _)| id % 2 == 1));
+        }
+    }
+
+    #[test]
+    fn hnsw_finds_most_true_neighbours() {
+        let vectors = random_vectors(1000, 16, 2);
+        let queries = random_vectors(50, 16, 3);
+
+        let exact = build(IndexKind::BruteForce, &vectors);
+        let approximate = build(IndexKind::Hnsw, &vectors);
+
+        assert!(recall_at(10, approximate.as_ref(), exact.as_ref(), &queries) > 0.9);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture benchmark`.
+    #[test]
+    #[ignore]
+    fn benchmark_brute_force_against_hnsw() {
+        let centres = random_vectors(200, 256, 4);
+        let vectors = clustered_vectors(20_000, &centres, 5);
+        let queries = clustered_vectors(200, &centres, 6);
+
+        let mut indexes = Vec::new();
+        for kind in [IndexKind::BruteForce, IndexKind::Hnsw] {
+            let started = Instant::now();
+            let index = build(kind, &vectors);
+            let built = started.elapsed();
+
+            let started = Instant::now();
+            for query in &queries {
+                index.search(query, 10);
+            }
+            println!("{:?}: built in {:?}, {:?} per search", kind, built, started.elapsed() / queries.len() as u32);
+            indexes.push(index);
+        }
+
+        println!("HNSW recall@10: {:.3}", recall_at(10, indexes[1].as_ref(), indexes[0].as_ref(), &queries));
+    }
+}
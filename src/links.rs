@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::subtext::{InlineKind, Subtext};
+
+/// The `/slashlinks` between notes. A slashlink names a note by its path
+/// without the extension, ignoring case, so `/drafts/loops` points at
+/// `drafts/loops.md`. A file stem alone works too, when only one note has it.
+#[derive(Clone, Debug, Default)]
+pub struct LinkGraph {
+    /// Every note, with the notes it links to.
+    outgoing: BTreeMap<String, BTreeSet<String>>,
+    backlinks: BTreeMap<String, BTreeSet<String>>,
+    /// Slashlinks that match no note, by the note they appear in.
+    dangling: BTreeMap<String, BTreeSet<String>>,
+    /// Slashlinks naming a path or file stem several notes share, such as
+    /// `loops.subtext` and `loops.md`, by the note they appear in, with the
+    /// notes they could mean.
+    ambiguous: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// `name` without its extension, lowercased.
+fn path_slug(name: &str) -> String {
+    let stem = Path::new(name).file_stem().and_then(|stem| stem.to_str()).unwrap_or(name);
+    match name.rfind('/') {
+        Some(slash) => format!("{}/{}", &name[..slash], stem),
+        None => stem.to_string()
+    }.to_lowercase()
+}
+
+/// The file stem of `name`, lowercased.
+fn stem_slug(name: &str) -> String {
+    Path::new(name).file_stem().and_then(|stem| stem.to_str()).unwrap_or(name).to_lowercase()
+}
+
+impl LinkGraph {
+    pub fn new(notes: &[Subtext]) -> LinkGraph {
+        let mut by_path = HashMap::<String, Vec<String>>::new();
+        let mut by_stem = HashMap::<String, Vec<String>>::new();
+        for note in notes {
+            by_path.entry(path_slug(&note.name)).or_default().push(note.name.clone());
+            by_stem.entry(stem_slug(&note.name)).or_default().push(note.name.clone());
+        }
+        let mut graph = LinkGraph::default();
+
+        for note in notes {
+            graph.outgoing.entry(note.name.clone()).or_default();
+            graph.backlinks.entry(note.name.clone()).or_default();
+        }
+
+        for note in notes {
+            let slashlinks = note.blocks.iter()
+                .flat_map(|block| &block.inlines)
+                .filter(|inline| inline.kind == InlineKind::SlashLink);
+
+            for link in slashlinks {
+                let slug = link.text[1..].to_lowercase();
+                let target = match by_path.get(&slug).or_else(|| by_stem.get(&slug)).map(Vec::as_slice) {
+                    Some([target]) => Some(target),
+                    Some(targets) => {
+                        graph.ambiguous.entry(note.name.clone()).or_default().insert(link.text.clone(), targets.to_vec());
+                        continue;
+                    }
+                    None => None
+                };
+                match target {
+                    Some(target) if *target == note.name => {}
+                    Some(target) => {
+                        graph.outgoing.get_mut(&note.name).unwrap().insert(target.clone());
+                        graph.backlinks.get_mut(target).unwrap().insert(note.name.clone());
+                    }
+                    None => {
+                        graph.dangling.entry(note.name.clone()).or_default().insert(link.text.clone());
+                    }
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// The notes `note` links to.
+    pub fn links(&self, note: &str) -> Vec<&str> {
+        self.outgoing.get(note).into_iter().flatten().map(String::as_str).collect()
+    }
+
+    /// The notes that link to `note`.
+    pub fn backlinks(&self, note: &str) -> Vec<&str> {
+        self.backlinks.get(note).into_iter().flatten().map(String::as_str).collect()
+    }
+
+    /// Notes that neither link nor are linked to.
+    pub fn orphans(&self) -> Vec<&str> {
+        self.outgoing.iter()
+            .filter(|(name, links)| links.is_empty() && self.backlinks[*name].is_empty())
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Every slashlink that matches no note, with the note it appears in.
+    pub fn dangling(&self) -> Vec<(&str, &str)> {
+        self.dangling.iter()
+            .flat_map(|(name, links)| links.iter().map(move |link| (name.as_str(), link.as_str())))
+            .collect()
+    }
+
+    /// Every slashlink that names a path or file stem several notes share, with the
+    /// note it appears in and the notes it could mean.
+    pub fn ambiguous(&self) -> Vec<(&str, &str, &[String])> {
+        self.ambiguous.iter()
+            .flat_map(|(name, links)| links.iter().map(move |(link, targets)| (name.as_str(), link.as_str(), targets.as_slice())))
+            .collect()
+    }
+
+    /// Notes reachable from `note` within `hops` links, followed in either
+    /// direction, grouped by distance: `[one hop away, two hops away, ...]`.
+    pub fn neighbours(&self, note: &str, hops: usize) -> Vec<Vec<&str>> {
+        let mut seen = BTreeSet::from([note]);
+        let mut rings = Vec::new();
+        let mut frontier = vec![note];
+
+        for _ in 0..hops {
+            let mut ring = frontier.iter()
+                .flat_map(|name| self.links(name).into_iter().chain(self.backlinks(name)))
+                .filter(|name| seen.insert(*name))
+                .collect::<Vec<&str>>();
+            ring.sort();
+            if ring.is_empty() {
+                break;
+            }
+            frontier = ring.clone();
+            rings.push(ring);
+        }
+
+        rings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(name: &str, content: &str) -> Subtext {
+        Subtext::parse(name, content).unwrap()
+    }
+
+    fn graph() -> LinkGraph {
+        LinkGraph::new(&[
+            note("loops.subtext", "Loops feed /Gardens and /rivers.\n& /missing"),
+            note("gardens.subtext", "Tended, see /seeds"),
+            note("seeds.subtext", "Back to /loops, and to /seeds itself"),
+            note("rivers.subtext", "Carving valleys"),
+            note("stones.subtext", "Nothing links here"),
+        ])
+    }
+
+    #[test]
+    fn follows_links_and_backlinks() {
+        let graph = graph();
+
+        assert_eq!(graph.links("loops.subtext"), ["gardens.subtext", "rivers.subtext"]);
+        assert_eq!(graph.backlinks("loops.subtext"), ["seeds.subtext"]);
+        assert_eq!(graph.backlinks("seeds.subtext"), ["gardens.subtext"]);
+        assert!(graph.links("nowhere.subtext").is_empty());
+    }
+
+    #[test]
+    fn finds_orphans_and_dangling_links() {
+        let graph = graph();
+
+        assert_eq!(graph.orphans(), ["stones.subtext"]);
+        assert_eq!(graph.dangling(), [("loops.subtext", "/missing")]);
+    }
+
+    #[test]
+    fn resolves_links_by_path_then_by_unique_stem() {
+        let graph = LinkGraph::new(&[
+            note("loops.subtext", "See /drafts/loops, /seeds and /stones"),
+            note("drafts/loops.md", "Back to /loops"),
+            note("drafts/seeds.subtext", "Nothing"),
+            note("drafts/stones.md", "Nothing"),
+            note("archive/stones.md", "Nothing"),
+        ]);
+
+        assert_eq!(graph.links("loops.subtext"), ["drafts/loops.md", "drafts/seeds.subtext"]);
+        assert_eq!(graph.links("drafts/loops.md"), ["loops.subtext"]);
+        assert!(graph.dangling().is_empty());
+        assert_eq!(graph.ambiguous(), [("loops.subtext", "/stones", &["drafts/stones.md".to_string(), "archive/stones.md".to_string()][..])]);
+    }
+
+    #[test]
+    fn notes_sharing_a_path_make_links_ambiguous() {
+        let graph = LinkGraph::new(&[
+            note("loops.subtext", "Subtext"),
+            note("loops.md", "Markdown"),
+            note("gardens.subtext", "See /loops"),
+        ]);
+
+        assert!(graph.links("gardens.subtext").is_empty());
+        assert!(graph.dangling().is_empty());
+        assert_eq!(graph.ambiguous(), [("gardens.subtext", "/loops", &["loops.subtext".to_string(), "loops.md".to_string()][..])]);
+    }
+
+    #[test]
+    fn groups_neighbours_by_distance() {
+        let graph = graph();
+
+        assert_eq!(graph.neighbours("rivers.subtext", 3), vec![
+            vec!["loops.subtext"],
+            vec!["gardens.subtext", "seeds.subtext"],
+        ]);
+        assert!(graph.neighbours("stones.subtext", 2).is_empty());
+    }
+}
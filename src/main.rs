@@ -1,25 +1,95 @@
 use agent::AgentError;
+use backend::CompletionBackend;
+use cache::{CachedBackend, EmbeddingCache};
+use clap::Parser;
+use cli::{Cli, CliCommand, OutputFormat};
+use config::{Config, ConfigError};
 use dotenv::dotenv;
-use notes::{load_random_note, NoteError};
+use fixtures::{FixtureError, RecordingBackend, ReplayBackend};
+use links::LinkGraph;
+use metaprompts::{Pipeline, RequestKind};
+use memory::{load_memories, save_memories, MemoryError};
+use mock::MockBackend;
+use note_index::{NoteIndex, NoteIndexError};
+use notes::{NoteError, NoteSource};
+use openai::{OpenAIBackend, OpenAIError};
 use reqwest::blocking::Client;
+use params::GenerationParams;
+use retry::{RateLimiter, RetryPolicy};
+use sampler::{load_history, save_history, NoteSampler};
+use serde_json::json;
 use std::env::{VarError};
+use std::fmt;
 use std::io::{self, Write};
-use env::Environment;
+use std::path::PathBuf;
+use std::sync::Arc;
+use subtext::Subtext;
+use tokens::Budget;
+use transcript::{Transcript, TranscriptBackend, TranscriptError};
 
+mod backend;
+mod cache;
+mod chat;
+mod cli;
+mod config;
 mod env;
+mod fixtures;
+mod index;
+mod links;
+mod memory;
+mod mock;
+mod note_index;
 mod notes;
 mod prompts;
 mod subtext;
+mod templates;
+mod tokens;
 mod metaprompts;
 mod openai;
+mod params;
+mod retry;
+mod sampler;
 mod agent;
+mod transcript;
+#[cfg(test)]
+mod test_support;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum AppError {
     DotEnvError(dotenv::Error),
     EnvironmentError(VarError),
     NoteError(notes::NoteError),
-    AgentError(agent::AgentError)
+    AgentError(agent::AgentError),
+    OpenAIError(openai::OpenAIError),
+    FixtureError(fixtures::FixtureError),
+    ConfigError(config::ConfigError),
+    MemoryError(memory::MemoryError),
+    NoteIndexError(note_index::NoteIndexError),
+    TranscriptError(transcript::TranscriptError),
+    BudgetError(tokens::BudgetError),
+    UnknownBackend(String),
+    Usage(String)
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::DotEnvError(error) => write!(f, "could not load .env: {}", error),
+            AppError::EnvironmentError(error) => write!(f, "missing environment variable: {}", error),
+            AppError::NoteError(error) => write!(f, "{}", error),
+            AppError::AgentError(error) => write!(f, "{}", error),
+            AppError::OpenAIError(error) => write!(f, "{}", error),
+            AppError::FixtureError(error) => write!(f, "{}", error),
+            AppError::ConfigError(error) => write!(f, "{}", error),
+            AppError::MemoryError(error) => write!(f, "{}", error),
+            AppError::NoteIndexError(error) => write!(f, "{}", error),
+            AppError::TranscriptError(error) => write!(f, "{}", error),
+            AppError::BudgetError(error) => write!(f, "{}", error),
+            AppError::UnknownBackend(name) => write!(f, "unknown BACKEND {:?}, expected openai, mock, record or replay", name),
+            AppError::Usage(message) => write!(f, "{}", message)
+        }
+    }
 }
 
 impl From<dotenv::Error> for AppError {
@@ -46,6 +116,51 @@ impl From<agent::AgentError> for AppError {
     }
 }
 
+impl From<openai::OpenAIError> for AppError {
+    fn from(openai_error: OpenAIError) -> Self {
+        AppError::OpenAIError(openai_error)
+    }
+}
+
+impl From<fixtures::FixtureError> for AppError {
+    fn from(fixture_error: FixtureError) -> Self {
+        AppError::FixtureError(fixture_error)
+    }
+}
+
+impl From<config::ConfigError> for AppError {
+    fn from(config_error: ConfigError) -> Self {
+        AppError::ConfigError(config_error)
+    }
+}
+
+impl From<memory::MemoryError> for AppError {
+    fn from(memory_error: MemoryError) -> Self {
+        AppError::MemoryError(memory_error)
+    }
+}
+
+impl From<note_index::NoteIndexError> for AppError {
+    fn from(note_index_error: NoteIndexError) -> Self {
+        AppError::NoteIndexError(note_index_error)
+    }
+}
+
+impl From<transcript::TranscriptError> for AppError {
+    fn from(transcript_error: TranscriptError) -> Self {
+        AppError::TranscriptError(transcript_error)
+    }
+}
+
+impl From<tokens::BudgetError> for AppError {
+    fn from(budget_error: tokens::BudgetError) -> Self {
+        match budget_error {
+            tokens::BudgetError::OpenAIError(openai_error) => AppError::OpenAIError(openai_error),
+            budget_error => AppError::BudgetError(budget_error)
+        }
+    }
+}
+
 enum Command {
     Critic,
     Actor,
@@ -56,27 +171,61 @@ enum Command {
     Connect,
     FreeText,
     Conversation,
+    Index,
+    Search,
+    Links,
+    Summon,
+    History,
     Quit
 }
 
-impl Command {
-    fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
             Command::Critic => "Load random note & analyse (critic) (ChatGPT)",
             Command::Actor => "Load random note & analyse (actor) (ChatGPT)",
-            Command::FourActor => "Load 4 random notes & analyse (actor) (ChatGPT)",
+            Command::FourActor => "Load random note & 3 linked notes & analyse (actor) (ChatGPT)",
             Command::Compress => "Load two random notes & compress",
             Command::Question => "Load random note & question",
             Command::Critique => "Load random note & critique",
-            Command::Connect => "Load random note & connect to random notes",
+            Command::Connect => "Load random note & connect to linked notes",
             Command::FreeText => "Free text input",
             Command::Conversation => "Conversation between geists",
+            Command::Index => "Index notes folder for search",
+            Command::Search => "Search notes",
+            Command::Links => "Show orphaned notes & dangling links",
+            Command::Summon => "Load random note & summon a spirit from a pipeline",
+            Command::History => "Browse past runs",
             Command::Quit => "Quit"
-        }.to_string()
+        };
+        write!(f, "{}", label)
+    }
+}
+
+impl Command {
+    /// The name used for this command's table under `[commands]` in the config.
+    fn key(&self) -> &'static str {
+        match self {
+            Command::Critic => "critic",
+            Command::Actor => "actor",
+            Command::FourActor => "four-actor",
+            Command::Compress => "compress",
+            Command::Question => "question",
+            Command::Critique => "critique",
+            Command::Connect => "connect",
+            Command::FreeText => "free-text",
+            Command::Conversation => "conversation",
+            Command::Index => "index",
+            Command::Search => "search",
+            Command::Links => "links",
+            Command::Summon => "summon",
+            Command::History => "history",
+            Command::Quit => "quit"
+        }
     }
 }
 
-const MENU: [Command; 10] = [
+const MENU: [Command; 15] = [
     Command::Critic,
     Command::Actor,
     Command::FourActor,
@@ -86,36 +235,284 @@ const MENU: [Command; 10] = [
     Command::Connect,
     Command::FreeText,
     Command::Conversation,
+    Command::Index,
+    Command::Search,
+    Command::Links,
+    Command::Summon,
+    Command::History,
     Command::Quit
 ];
 
 fn print_menu() {
-    let menu_string = MENU.iter().enumerate().map(|(i, command)| format!("[{}] {}", i + 1, command.to_string())).collect::<Vec<String>>().join("\n");
+    let menu_string = MENU.iter().enumerate().map(|(i, command)| format!("[{}] {}", i + 1, command)).collect::<Vec<String>>().join("\n");
 
     print!("Commands:\n{}\n", menu_string);
 }
 
+/// The final request of a command, whose answer is the command's result.
+enum Request {
+    Chat(String),
+    Completion(String)
+}
+
+fn print_token(token: &str) {
+    print!("{}", token);
+    io::stdout().flush().unwrap();
+}
+
+fn print_json(command: &Command, notes: &[String], result: &str) {
+    println!("{}", json!({"command": command.key(), "notes": notes, "result": result}));
+}
+
+/// Sends the final request and prints its answer. Text output streams in as
+/// it arrives, JSON output is printed as one object once it is complete.
+fn respond(command: &Command, notes: Vec<String>, request: Request, params: &GenerationParams, backend: &dyn CompletionBackend, transcript: &Transcript, format: OutputFormat) -> Result<(), AppError> {
+    transcript.notes(&notes);
+    let result = match format {
+        OutputFormat::Text => {
+            for note in &notes {
+                println!("@{}\n\n", note);
+            }
+            println!("@@@");
+            let result = match request {
+                Request::Chat(prompt) => backend.chat_stream(&prompt, params, &mut print_token)?,
+                Request::Completion(prompt) => backend.complete_stream(&prompt, params, &mut print_token)?
+            };
+            println!("\n\n");
+            result
+        }
+        OutputFormat::Json => {
+            let result = match request {
+                Request::Chat(prompt) => backend.chat(&prompt, params)?,
+                Request::Completion(prompt) => backend.complete(&prompt, params)?
+            };
+            print_json(command, &notes, &result);
+            result
+        }
+    };
+
+    transcript.result(&result);
+    Ok(())
+}
+
+/// Prompts on stderr and reads one line of stdin.
+fn read_line(prompt: &str) -> String {
+    eprint!("{}", prompt);
+    io::stderr().flush().unwrap();
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap();
+    line
+}
+
+/// Picks the notes for one command: those named on the command line in
+/// order, the rest drawn by a `NoteSampler` over the whole notes folder,
+/// so no note is picked twice.
+struct NotePicker<'a> {
+    config: &'a Config,
+    source: NoteSource,
+    names: &'a [Option<String>],
+    picked: Vec<String>,
+    sampler: Option<NoteSampler>
+}
+
+impl<'a> NotePicker<'a> {
+    fn new(config: &'a Config, names: &'a [Option<String>]) -> NotePicker<'a> {
+        NotePicker { config, source: config.note_source(), names, picked: Vec::new(), sampler: None }
+    }
+
+    /// The sampler, loading every note the first time it's needed.
+    fn sampler(&mut self) -> Result<&mut NoteSampler, NoteError> {
+        if self.sampler.is_none() {
+            let notes = &self.config.notes;
+            let mut sampler = NoteSampler::new(self.source.load_all()?, self.config.defaults.seed)
+                .with_weighting(notes.weighting)
+                .with_tags(&notes.tags)
+                .with_history(load_history(&notes.history)?);
+            for name in &self.picked {
+                sampler.exclude(name);
+            }
+            self.sampler = Some(sampler);
+        }
+        Ok(self.sampler.as_mut().unwrap())
+    }
+
+    fn named(&mut self, index: usize) -> Result<Option<Subtext>, NoteError> {
+        let Some(Some(name)) = self.names.get(index) else {
+            return Ok(None);
+        };
+        let note = self.source.load(name)?;
+        self.picked.push(note.name.clone());
+        if let Some(sampler) = &mut self.sampler {
+            sampler.exclude(&note.name);
+        }
+        Ok(Some(note))
+    }
+
+    /// The note named at `index`, or a drawn one when it wasn't named.
+    fn pick(&mut self, index: usize) -> Result<Subtext, NoteError> {
+        match self.named(index)? {
+            Some(note) => Ok(note),
+            None => self.sampler()?.draw()
+        }
+    }
+
+    /// `count` notes to go with `base`: those named after it, then notes
+    /// linked to it, then notes two links away, then any others.
+    fn related(&mut self, base: &Subtext, count: usize) -> Result<Vec<Subtext>, NoteError> {
+        let mut notes = Vec::new();
+        for index in 1..=count {
+            let note = match self.named(index)? {
+                Some(note) => note,
+                None => match self.sampler()?.draw_related(&base.name, 2) {
+                    Some(note) => note,
+                    None => self.sampler()?.draw()?
+                }
+            };
+            notes.push(note);
+        }
+        Ok(notes)
+    }
+
+    /// Records when the drawn notes were drawn, for staleness weighting.
+    fn save(self) -> Result<(), NoteError> {
+        match self.sampler {
+            Some(sampler) => save_history(&self.config.notes.history, sampler.history()),
+            None => Ok(())
+        }
+    }
+}
+
+fn openai_backend(config: &Config) -> Result<OpenAIBackend, AppError> {
+    let profile = config.backend()?;
+    let completion_params = GenerationParams { model: profile.completion_model.clone(), ..GenerationParams::default() };
+    let chat_params = GenerationParams { model: profile.chat_model.clone(), ..GenerationParams::default() };
+
+    let mut backend = OpenAIBackend::new(Client::new(), config.environment()?)
+        .with_params(&completion_params, &chat_params);
+
+    if let Some(model) = &profile.embedding_model {
+        backend = backend.with_embedding_model(model);
+    }
+    if let Some(max_retries) = profile.max_retries {
+        backend = backend.with_retry_policy(RetryPolicy { max_retries, ..RetryPolicy::default() });
+    }
+    if let Some(max_batch_tokens) = profile.max_embedding_batch_tokens {
+        backend = backend.with_max_batch_tokens(max_batch_tokens);
+    }
+    if let Some(requests) = profile.requests_per_minute {
+        backend = backend.with_rate_limiter(Arc::new(RateLimiter::per_minute(requests)));
+    }
+
+    Ok(backend)
+}
+
+/// The OpenAI backend, behind the embedding cache when there is one.
+fn cached_backend(config: &Config, cache: Option<&Arc<EmbeddingCache>>) -> Result<Box<dyn CompletionBackend>, AppError> {
+    let backend = openai_backend(config)?;
+    Ok(match cache {
+        Some(cache) => Box::new(CachedBackend::new(backend, cache.clone())),
+        None => Box::new(backend)
+    })
+}
+
+/// The backend `BACKEND` names. Only requests bound for the server go
+/// through `cache`, so recordings still see every embedding and mock and
+/// replayed vectors never end up in it.
+fn load_backend(config: &Config, cache: Option<&Arc<EmbeddingCache>>) -> Result<Box<dyn CompletionBackend>, AppError> {
+    let fixture_path = PathBuf::from(std::env::var("FIXTURE_PATH").unwrap_or_else(|_| "fixtures/session.json".to_string()));
+
+    match std::env::var("BACKEND") {
+        Err(VarError::NotPresent) => cached_backend(config, cache),
+        Err(error) => Err(error.into()),
+        Ok(name) => match name.as_str() {
+            "openai" => cached_backend(config, cache),
+            "mock" => Ok(Box::new(MockBackend::new())),
+            "record" => Ok(Box::new(RecordingBackend::new(cached_backend(config, cache)?, fixture_path))),
+            "replay" => Ok(Box::new(ReplayBackend::from_file(&fixture_path)?)),
+            _ => Err(AppError::UnknownBackend(name))
+        }
+    }
+}
+
+/// A subcommand mapped onto its menu entry, with the notes and text it was given.
+struct Invocation {
+    command: Command,
+    names: Vec<Option<String>>,
+    text: Option<String>
+}
+
+/// Returns `None` for `repl`, which has no single menu entry.
+fn resolve(command: CliCommand) -> Result<Option<Invocation>, AppError> {
+    let with_base = |base: Option<String>, others: Vec<String>| {
+        if others.len() > 3 {
+            return Err(AppError::Usage(format!("--with takes at most 3 notes, got {}", others.len())));
+        }
+        Ok(std::iter::once(base).chain(others.into_iter().map(Some)).collect())
+    };
+
+    let (command, names, text) = match command {
+        CliCommand::Repl => return Ok(None),
+        CliCommand::Critic { note } => (Command::Critic, vec![note], None),
+        CliCommand::Actor { note } => (Command::Actor, vec![note], None),
+        CliCommand::FourActor { note, others } => (Command::FourActor, with_base(note, others)?, None),
+        CliCommand::Compress { notes } => (Command::Compress, notes.into_iter().map(Some).collect(), None),
+        CliCommand::Question { note } => (Command::Question, vec![note], None),
+        CliCommand::Critique { note } => (Command::Critique, vec![note], None),
+        CliCommand::Connect { base, others } => (Command::Connect, with_base(base, others)?, None),
+        CliCommand::FreeText { text } => (Command::FreeText, vec![], text),
+        CliCommand::Chat => (Command::Conversation, vec![], None),
+        CliCommand::Index => (Command::Index, vec![], None),
+        CliCommand::Search { query, .. } => (Command::Search, vec![], query),
+        CliCommand::Links { note } => (Command::Links, vec![note], None),
+        CliCommand::Summon { spirit, note, others } => (Command::Summon, std::iter::once(note).chain(others.into_iter().map(Some)).collect(), spirit),
+        CliCommand::History { run, .. } => (Command::History, vec![], run)
+    };
+
+    Ok(Some(Invocation { command, names, text }))
+}
+
 fn main() -> Result<(), AppError> {
-    dotenv()?;
+    if let Err(error) = dotenv() {
+        if !error.not_found() {
+            return Err(error.into());
+        }
+    }
+
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config_overrides())?;
+
+    let cache = config.embedding_cache_dir().map(|dir| Arc::new(EmbeddingCache::new(dir)));
+    let backend = load_backend(&config, cache.as_ref())?;
+    let backend = backend.as_ref();
 
-    let env = Environment::from_env()?;
-    let client = Client::new();
+    let result = match cli.command.map(resolve).transpose()?.flatten() {
+        None => repl(&config, backend, cli.format),
+        Some(invocation) => run_logged(&invocation.command, &invocation.names, invocation.text, &config, backend, cli.format)
+    };
+
+    if let Some(cache) = cache {
+        eprintln!("{}", cache.stats());
+    }
+    result
+}
 
+fn repl(config: &Config, backend: &dyn CompletionBackend, format: OutputFormat) -> Result<(), AppError> {
     loop {
         print_menu();
 
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        if io::stdin().read_line(&mut input).unwrap() == 0 {
+            break Ok(());
+        }
         let trimmed_input = input.trim();
-        let index = match trimmed_input.parse::<usize>() {
-            Ok(i) => i - 1,
-            Err(_) => {
+        let index = match trimmed_input.parse::<usize>().ok().and_then(|i| i.checked_sub(1)) {
+            Some(i) => i,
+            None => {
                 println!("Invalid command. Please try again.");
                 continue;
             }
         };
-       
-        let trimmed_input = input.trim();
 
         let command = match MENU.get(index) {
             Some(c) => c,
@@ -126,148 +523,391 @@ fn main() -> Result<(), AppError> {
         };
 
         match command {
-            Command::Critic => {
-                println!("Random note analysis (critic)");
-                let note = load_random_note()?;
-                let prompt = metaprompts::critic(&note.content, &client, &env);
-                println!("@{}\n\n", note.name);
-                let result = openai::chatgpt(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+            Command::Quit => break Ok(()),
+            command => {
+                if let Err(error) = run_logged(command, &[], None, config, backend, format) {
+                    println!("Error: {}\n\n", error);
+                }
             }
-            Command::Actor => {
-                println!("Random note analysis (actor)");
-                let note = load_random_note()?;
-                let prompt = metaprompts::actor(&note.content, &client, &env);
-                println!("@{}\n\n", note.name);
-                let result = openai::chatgpt(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
-            }
-            Command::FourActor => {
-                println!("Random 4 note analysis (actor)");
-                let (note, note_a, note_b, note_c) = (load_random_note()?, load_random_note()?, load_random_note()?, load_random_note()?);
-                let prompt = metaprompts::giga_actor(&note.content, &note_a.content, &note_b.content, &note_c.content, &client, &env);
-                println!("@{}\n\n", note.name);
-                let result = openai::chatgpt(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+        }
+    }
+}
+
+/// Runs one command, recording it as a transcript when they're enabled.
+fn run_logged(command: &Command, names: &[Option<String>], text: Option<String>, config: &Config, backend: &dyn CompletionBackend, format: OutputFormat) -> Result<(), AppError> {
+    let transcript = match command {
+        Command::History | Command::Quit => Transcript::disabled(),
+        _ if !config.transcripts.enabled => Transcript::disabled(),
+        _ => Transcript::start(&config.transcripts.dir, command.key())?
+    };
+    let logged = TranscriptBackend::new(backend, &transcript);
+
+    let result = run_command(command, names, text, config, &logged, &transcript, format);
+    transcript.end(result.as_ref().err().map(|error| error.to_string()));
+    if let Some(id) = transcript.id() {
+        eprintln!("saved run {}", id);
+    }
+    result
+}
+
+/// Runs one command. Notes named in `names` are used in order, the rest are
+/// picked at random. `text` is the free text input, read from stdin if missing.
+fn run_command(command: &Command, names: &[Option<String>], text: Option<String>, config: &Config, backend: &dyn CompletionBackend, transcript: &Transcript, format: OutputFormat) -> Result<(), AppError> {
+    let params = &config.command_params(command.key());
+    let mut picker = NotePicker::new(config, names);
+
+    let (notes, request) = match command {
+        Command::Critic => {
+            eprintln!("Random note analysis (critic)");
+            let note = picker.pick(0)?;
+            let prompt = run_pipeline(config.pipeline("critic")?, (&note.name, &note.content), &[], params, config, backend, transcript)?;
+            (vec![note.name], Request::Chat(prompt))
+        }
+        Command::Actor => {
+            eprintln!("Random note analysis (actor)");
+            let note = picker.pick(0)?;
+            let prompt = run_pipeline(config.pipeline("actor")?, (&note.name, &note.content), &[], params, config, backend, transcript)?;
+            (vec![note.name], Request::Chat(prompt))
+        }
+        Command::FourActor => {
+            eprintln!("Random 4 note analysis (actor)");
+            let note = picker.pick(0)?;
+            let pipeline = config.pipeline("giga-actor")?;
+            let related = picker.related(&note, pipeline.notes)?;
+            let prompt = run_pipeline(pipeline, (&note.name, &note.content), &related, params, config, backend, transcript)?;
+            (vec![note.name], Request::Chat(prompt))
+        }
+        Command::Compress => {
+            eprintln!("Random note combination");
+            let note_a = picker.pick(0)?;
+            let note_b = picker.pick(1)?;
+            let overhead = |model: Option<&str>| tokens::count_for(model, &prompts::compressor(&config.templates, " "));
+            let fitted = fit_notes(&[(&note_a.name, &note_a.content), (&note_b.name, &note_b.content)], &overhead, &[RequestKind::Completion], params, config, backend, transcript)?;
+            // combine note a and b content into one string
+            let combined_notes = format!("{} {}", fitted[0], fitted[1]);
+
+            let prompt = prompts::compressor(&config.templates, &combined_notes);
+            (vec![note_a.name, note_b.name], Request::Completion(prompt))
+        }
+        Command::Question => {
+            eprintln!("Random questions from note");
+            let note_a = picker.pick(0)?;
+            let overhead = |model: Option<&str>| tokens::count_for(model, &prompts::question_everything(&config.templates, ""));
+            let fitted = fit_notes(&[(&note_a.name, &note_a.content)], &overhead, &[RequestKind::Completion], params, config, backend, transcript)?;
+
+            let prompt = prompts::question_everything(&config.templates, &fitted[0]);
+            (vec![note_a.name], Request::Completion(prompt))
+        }
+        Command::Critique => {
+            eprintln!("Random critique from note");
+            let note_a = picker.pick(0)?;
+            let overhead = |model: Option<&str>| tokens::count_for(model, &prompts::critical_writing(&config.templates, ""));
+            let fitted = fit_notes(&[(&note_a.name, &note_a.content)], &overhead, &[RequestKind::Completion], params, config, backend, transcript)?;
+
+            let prompt = prompts::critical_writing(&config.templates, &fitted[0]);
+            (vec![note_a.name], Request::Completion(prompt))
+        }
+        Command::Connect => {
+            eprintln!("Random note with connections to linked notes");
+            let note_base = picker.pick(0)?;
+            let related = picker.related(&note_base, 3)?;
+            let notes = std::iter::once(&note_base).chain(&related).map(|note| (note.name.as_str(), note.content.as_str())).collect::<Vec<(&str, &str)>>();
+            let overhead = |model: Option<&str>| tokens::count_for(model, &prompts::connections(&config.templates, "", &vec![""; related.len()]));
+            let fitted = fit_notes(&notes, &overhead, &[RequestKind::Chat], params, config, backend, transcript)?;
+            let contents = fitted[1..].iter().map(String::as_str).collect::<Vec<&str>>();
+
+            let prompt = prompts::connections(&config.templates, &fitted[0], &contents);
+            (std::iter::once(note_base.name).chain(related.into_iter().map(|note| note.name)).collect(), Request::Chat(prompt))
+        }
+        Command::FreeText => {
+            let text_input = match text {
+                Some(text) => text,
+                None => read_line("> ")
+            };
+
+            let prompt = run_pipeline(config.pipeline("critic")?, ("input", &text_input), &[], params, config, backend, transcript)?;
+            (vec![], Request::Completion(prompt))
+        },
+        Command::Conversation => {
+            return conversation(config, backend, transcript, format);
+        }
+        Command::Index => {
+            return index_notes(config, backend, format);
+        }
+        Command::Links => {
+            let graph = LinkGraph::new(&config.note_source().load_all()?);
+            let (notes, result) = match names.first() {
+                Some(Some(name)) => {
+                    let result = format!("links: {}\nbacklinks: {}", graph.links(name).join(", "), graph.backlinks(name).join(", "));
+                    (vec![name.clone()], result)
+                }
+                _ => {
+                    let dangling = graph.dangling().iter().map(|(note, link)| format!("{} -> {}", note, link)).collect::<Vec<String>>();
+                    let ambiguous = graph.ambiguous().iter().map(|(note, link, targets)| format!("{} -> {} ({})", note, link, targets.join(" or "))).collect::<Vec<String>>();
+                    (vec![], format!("orphans: {}\ndangling: {}\nambiguous: {}", graph.orphans().join(", "), dangling.join(", "), ambiguous.join(", ")))
+                }
+            };
+            match format {
+                OutputFormat::Text => println!("{}", result),
+                OutputFormat::Json => print_json(command, &notes, &result)
             }
-            Command::Compress => {
-                println!("Random note combination");
-                let note_a = load_random_note()?;
-                let note_b = load_random_note()?;
-                // combine note a and b content into one string
-                let combined_notes = format!("{} {}", note_a.content, note_b.content);
-                
-                let prompt = prompts::compressor(&combined_notes);
-                println!("@{}\n\n", note_a.name);
-                println!("@{}\n\n", note_b.name);
-                let result = openai::gpt3(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+            transcript.notes(&notes);
+            transcript.result(&result);
+            return Ok(());
+        }
+        Command::Search => {
+            let query = match text {
+                Some(query) => query,
+                None => read_line("search> ")
+            };
+            return search(query.trim(), config, backend, format);
+        }
+        Command::Summon => {
+            let spirit = match text {
+                Some(spirit) => spirit,
+                None => {
+                    for (name, pipeline) in config.pipelines.iter() {
+                        eprintln!("{}: {}", name, pipeline.description);
+                    }
+                    read_line("spirit> ")
+                }
+            };
+            let spirit = spirit.trim();
+            let pipeline = config.pipeline(spirit)?;
+            if names.len() > pipeline.notes + 1 {
+                return Err(AppError::Usage(format!("{} takes at most {} notes with --with, got {}", spirit, pipeline.notes, names.len() - 1)));
             }
-            Command::Question => {
-                println!("Random questions from note");
-                let note_a = load_random_note()?;
-                
-                let prompt = prompts::question_everything(&note_a.content);
-                println!("@{}\n\n", note_a.name);
-                let result = openai::gpt3(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+            let params = &config.command_params(spirit);
+
+            eprintln!("Summoning {}", spirit);
+            let note = picker.pick(0)?;
+            let related = picker.related(&note, pipeline.notes)?;
+            let prompt = run_pipeline(pipeline, (&note.name, &note.content), &related, params, config, backend, transcript)?;
+            let request = match pipeline.output.request {
+                RequestKind::Chat => Request::Chat(prompt),
+                RequestKind::Completion => Request::Completion(prompt)
+            };
+
+            let notes = std::iter::once(note.name).chain(related.into_iter().map(|note| note.name)).collect();
+            picker.save()?;
+            return respond(command, notes, request, params, backend, transcript, format);
+        }
+        Command::History => {
+            return history(text, config, format);
+        }
+        Command::Quit => return Ok(())
+    };
+
+    picker.save()?;
+    respond(command, notes, request, params, backend, transcript, format)
+}
+
+/// Runs `pipeline` over `input`, given as name and content, and `notes`,
+/// cut down to fit the model, and returns its final prompt.
+fn run_pipeline(pipeline: &Pipeline, input: (&str, &str), notes: &[Subtext], params: &GenerationParams, config: &Config, backend: &dyn CompletionBackend, transcript: &Transcript) -> Result<String, AppError> {
+    let named = std::iter::once(input).chain(notes.iter().map(|note| (note.name.as_str(), note.content.as_str()))).collect::<Vec<(&str, &str)>>();
+    let overhead = |model: Option<&str>| pipeline.overhead(&config.templates, model);
+    let fitted = fit_notes(&named, &overhead, &pipeline.requests(), params, config, backend, transcript)?;
+
+    let contents = fitted[1..].iter().map(String::as_str).collect::<Vec<&str>>();
+    Ok(pipeline.run(&config.templates, &fitted[0], &contents, params, config.parallel_requests, backend)?)
+}
+
+/// Cuts `notes`, given as name and content, down so that together with the
+/// rest of the prompt, `overhead` tokens for a model, they fit the context
+/// window of the model behind each of `requests`. Warns about every note
+/// it clips.
+fn fit_notes(notes: &[(&str, &str)], overhead: &dyn Fn(Option<&str>) -> usize, requests: &[RequestKind], params: &GenerationParams, config: &Config, backend: &dyn CompletionBackend, transcript: &Transcript) -> Result<Vec<String>, AppError> {
+    let sent = requests.iter().map(|request| match request {
+        RequestKind::Completion => backend.completion_defaults().overlay(params),
+        RequestKind::Chat => backend.chat_defaults().overlay(params)
+    });
+    let smallest = sent.min_by_key(|sent| config.context_window(sent.model.as_deref())).unwrap_or_else(|| params.clone());
+    let model = smallest.model.as_deref();
+    let window = config.context_window(model);
+    let reserve = config.context.reserve.max(smallest.max_tokens.unwrap_or(0) as usize);
+    let budget = Budget::new(model, window, overhead(model), reserve)?;
+
+    let contents = notes.iter().map(|(_, content)| *content).collect::<Vec<&str>>();
+    let fitted = budget.fit(&contents, config.context.fit, &config.templates, params, backend)?;
+    for ((name, _), note) in notes.iter().zip(&fitted) {
+        if let Some(from) = note.clipped_from {
+            eprintln!("warning: clipped {} from {} to {} tokens to fit the {} token context of {}", name, from, note.tokens, window, model.unwrap_or("the model"));
+            transcript.clipped(name, from, note.tokens);
+        }
+    }
+    Ok(fitted.into_iter().map(|note| note.content).collect())
+}
+
+fn index_notes(config: &Config, backend: &dyn CompletionBackend, format: OutputFormat) -> Result<(), AppError> {
+    let mut index = NoteIndex::load(&config.search.index_path)?;
+    let source = config.note_source();
+    eprintln!("indexing {}", source.root().display());
+    let report = index.update(&source, config.search.chunk_tokens, backend)?;
+    index.save(&config.search.index_path)?;
+
+    match format {
+        OutputFormat::Text => println!("{}", report),
+        OutputFormat::Json => {
+            let changed = report.added.iter().chain(&report.updated).cloned().collect::<Vec<String>>();
+            print_json(&Command::Index, &changed, &report.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Brings the note index up to date, then lists the notes closest to `query`.
+fn search(query: &str, config: &Config, backend: &dyn CompletionBackend, format: OutputFormat) -> Result<(), AppError> {
+    let mut index = NoteIndex::load(&config.search.index_path)?;
+    let report = index.update(&config.note_source(), config.search.chunk_tokens, backend)?;
+    if report.chunks_embedded > 0 || !report.removed.is_empty() {
+        eprintln!("index: {}", report);
+        index.save(&config.search.index_path)?;
+    }
+
+    let embedding = backend.embed(query)?;
+    let hits = index.search(query, &embedding, backend.embedding_model(), config.search.results, config.search.keyword_weight);
+
+    for hit in &hits {
+        match format {
+            OutputFormat::Text => println!("{:.3}  {}  {}", hit.score, hit.note, hit.snippet(100)),
+            OutputFormat::Json => println!("{}", json!({"command": Command::Search.key(), "notes": [hit.note], "result": hit.chunk, "score": hit.score}))
+        }
+    }
+    if hits.is_empty() {
+        eprintln!("no notes matched");
+    }
+    Ok(())
+}
+
+/// Lists the most recent runs, or shows the run called `run` request by request.
+/// From the menu, lists them and then asks which to show.
+fn history(run: Option<String>, config: &Config, format: OutputFormat) -> Result<(), AppError> {
+    let dir = &config.transcripts.dir;
+    let run = match run {
+        Some(run) => run,
+        None => {
+            let runs = transcript::list_runs(dir)?;
+            for run in runs.iter().take(config.transcripts.list) {
+                match format {
+                    OutputFormat::Text => {
+                        let outcome = match (&run.error, &run.result) {
+                            (Some(error), _) => format!("error: {}", error),
+                            (None, Some(result)) => result.lines().next().unwrap_or_default().chars().take(80).collect(),
+                            (None, None) => String::new()
+                        };
+                        println!("{}  {}  {}", run.id, run.notes.join(", "), outcome);
+                    }
+                    OutputFormat::Json => println!("{}", json!({
+                        "id": run.id,
+                        "command": run.command,
+                        "started": run.started,
+                        "notes": run.notes,
+                        "requests": run.requests,
+                        "result": run.result,
+                        "error": run.error,
+                    }))
+                }
             }
-            Command::Critique => {
-                println!("Random critique from note");
-                let note_a = load_random_note()?;
-                
-                let prompt = prompts::critical_writing(&note_a.content);
-                println!("@{}\n\n", note_a.name);
-                let result = openai::gpt3(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+            if runs.is_empty() {
+                eprintln!("no runs recorded in {}", dir.display());
+                return Ok(());
             }
-            Command::Connect => {
-                println!("Random note with connections to random notes");
-                let note_base = load_random_note()?;
-
-                let note_a = load_random_note()?;
-                let note_b = load_random_note()?;
-                let note_c = load_random_note()?;
-                
-                let prompt = prompts::connections(&note_base.content, &note_a.content, &note_b.content, &note_c.content);
-                let result = openai::chatgpt(&prompt, &client, &env);
-                println!("@@@\n{}\n\n", result);
+            if format == OutputFormat::Json {
+                return Ok(());
             }
-            Command::FreeText => {
-                print!("> ");
-                io::stdout().flush().unwrap();
-
-                let mut text_input = String::new();
-                io::stdin().read_line(&mut text_input).unwrap();
-
-                let prompt = metaprompts::critic(&text_input, &client, &env);
-                let result = openai::gpt3(&prompt, &client, &env);
-                println!("---\n{}\n\n", result);
-            },
-            Command::Conversation => {
-                conversation(&client, &env)?
+            read_line("run> ")
+        }
+    };
+
+    let run = run.trim();
+    if run.is_empty() {
+        return Ok(());
+    }
+    let entries = transcript::load_run(dir, run)?;
+    match format {
+        OutputFormat::Text => println!("{}", transcript::format_run(&entries)),
+        OutputFormat::Json => {
+            for entry in &entries {
+                println!("{}", entry);
             }
-            Command::Quit => break Ok(())
         }
     }
+    Ok(())
 }
 
-fn wait_for_enter() {
-    print!("Press enter to continue...");
-    io::stdout().flush().unwrap();
-    let mut text_input = String::new();
-    io::stdin().read_line(&mut text_input).unwrap();
-}
-
-fn conversation(client: &Client, env: &Environment) -> Result<(), AppError> {
+fn conversation(config: &Config, backend: &dyn CompletionBackend, transcript: &Transcript, format: OutputFormat) -> Result<(), AppError> {
+    let params = &config.command_params(Command::Conversation.key());
+    let mut picker = NotePicker::new(config, &[]);
+    let sampler = picker.sampler()?;
+    let persona = config.persona()?;
 
-    let mut agent_a = agent::Agent::new("Simulation: You are a conversation bot designed to ask thought provoking questions. You respond to messages drawing connections between broad topics, making insightful use of any memories that you recall. You respond in at most two sentences.".to_string());
+    let mut agent_a = agent::Agent::new(persona.prompt.clone());
+    agent_a.params = params.overlay(&persona.params);
+    agent_a.token_budget = config.agent.token_budget;
+    agent_a.recall_count = config.agent.recall_count;
+    agent_a.min_similarity = config.agent.min_similarity;
+    agent_a.templates = config.templates.clone();
 
-    println!("memorizing notes");
-    for _ in 0..3 {
-        let note_a = load_random_note()?;
-        let note_b = load_random_note()?;
-        let note_c = load_random_note()?;
+    let memory_path = config.memory_path();
+    agent_a.restore(load_memories(&memory_path)?, config.agent.index);
+    eprintln!("recalled {} memories, {} searchable", agent_a.memories().len(), agent_a.searchable());
 
-        agent_a.memorize(note_a.name, note_a.content, client, env)?;
-        print!(".");
-        io::stdout().flush().unwrap();
-        agent_a.memorize(note_b.name, note_b.content, client, env)?;
-        print!(".");
-        io::stdout().flush().unwrap();
-        agent_a.memorize(note_c.name, note_c.content, client, env)?;
-        print!(".");
-        io::stdout().flush().unwrap();
+    eprintln!("memorizing notes");
+    let mut notes = Vec::new();
+    while notes.len() < config.agent.memory_rounds * 3 {
+        match sampler.draw() {
+            Ok(note) => notes.push(note),
+            Err(NoteError::NoNotes) if !notes.is_empty() => break,
+            Err(error) => return Err(error.into())
+        }
     }
+    let entries = notes.iter().map(|note| (note.name.as_str(), note.content.as_str(), Some(note.name.as_str()))).collect::<Vec<_>>();
+    agent_a.memorize(&entries, backend)?;
 
-    println!("brainstorming");
-    for _ in 0..3 {
-        let note_a = load_random_note()?;
-        let note_b = load_random_note()?;
-        let note_c = load_random_note()?;
+    eprintln!("brainstorming");
+    let mut ideas = Vec::new();
+    for _ in 0..config.agent.brainstorm_rounds {
+        sampler.reset();
+        let note_a = sampler.draw()?;
+        let note_b = sampler.draw()?;
+        let note_c = sampler.draw()?;
+        let notes = [&note_a, &note_b, &note_c, &note_c].map(|note| (note.name.as_str(), note.content.as_str()));
+        let overhead = |model: Option<&str>| tokens::count_for(model, &prompts::connections(&config.templates, "", &["", "", ""]));
+        let fitted = fit_notes(&notes, &overhead, &[RequestKind::Chat], params, config, backend, transcript)?;
 
-        let prompt = prompts::connections(&note_a.content, &note_b.content, &note_c.content, &note_c.content);
-        print!(".");
-        io::stdout().flush().unwrap();
-        let result = openai::chatgpt(&prompt, client, env);
-        print!(".");
-        io::stdout().flush().unwrap();
-        agent_a.memorize(note_a.name, result, client, env)?;
-        print!(".");
-        io::stdout().flush().unwrap();
+        let prompt = prompts::connections(&config.templates, &fitted[0], &[&fitted[1], &fitted[2], &fitted[3]]);
+        eprint!(".");
+        let result = backend.chat(&prompt, params)?;
+        eprint!(".");
+        ideas.push((note_a.name, result));
     }
+    let entries = ideas.iter().map(|(name, idea)| (name.as_str(), idea.as_str(), Some(name.as_str()))).collect::<Vec<_>>();
+    agent_a.memorize(&entries, backend)?;
+    save_memories(&memory_path, agent_a.memories())?;
+    picker.save()?;
 
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
+        eprint!("> ");
+        io::stderr().flush().unwrap();
 
         let mut text_input = String::new();
-        io::stdin().read_line(&mut text_input).unwrap();
-
-        let response = agent_a.speak(text_input.as_str(), client, env)?;
-        println!("---\n{}\n\n", response);
-        // agent_a.memorize(text_input, response, client, env)?;
+        if io::stdin().read_line(&mut text_input).unwrap() == 0 {
+            return Ok(());
+        }
 
-        // wait_for_enter();
+        match format {
+            OutputFormat::Text => {
+                println!("@@@");
+                let response = agent_a.speak(text_input.as_str(), backend, &mut print_token)?;
+                println!("\n\n");
+                transcript.result(&response);
+            }
+            OutputFormat::Json => {
+                let response = agent_a.speak(text_input.as_str(), backend, &mut |_| {})?;
+                print_json(&Command::Conversation, &[], &response);
+                transcript.result(&response);
+            }
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::openai::Embedding;
+
+/// Bumped whenever the file layout changes in a way older versions can't
+/// read. `migrate` upgrades anything older on load.
+const STORE_VERSION: u32 = 1;
+/// The oldest version `migrate` can upgrade. Version 1 was the first.
+const OLDEST_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Memory {
+    pub subject: String,
+    pub content: String,
+    pub embedding: Embedding,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    /// The note this memory came from, if any.
+    #[serde(default)]
+    pub source: Option<String>,
+    /// The embedding model that produced `embedding`. Vectors from
+    /// different models can't be compared.
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl Memory {
+    pub fn new(subject: String, content: String, embedding: Embedding) -> Memory {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Memory { subject, content, embedding, created_at, source: None, model: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryError {
+    IOError(std::io::Error),
+    ParseError(String),
+    UnsupportedVersion(u32),
+    OutdatedVersion(u32)
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::IOError(error) => write!(f, "could not access memory store: {}", error),
+            MemoryError::ParseError(message) => write!(f, "invalid memory store: {}", message),
+            MemoryError::UnsupportedVersion(version) => write!(f, "memory store version {} is newer than this build supports ({})", version, STORE_VERSION),
+            MemoryError::OutdatedVersion(version) => write!(f, "memory store version {} is older than this build can upgrade ({})", version, OLDEST_VERSION)
+        }
+    }
+}
+
+impl From<std::io::Error> for MemoryError {
+    fn from(io_error: std::io::Error) -> Self {
+        MemoryError::IOError(io_error)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    version: u32,
+    memories: Vec<Memory>,
+}
+
+/// Upgrades a store written by an older version to the current layout.
+fn migrate(json: serde_json::Value) -> Result<StoreFile, MemoryError> {
+    let version = json["version"].as_u64()
+        .ok_or_else(|| MemoryError::ParseError("missing version".to_string()))? as u32;
+
+    // Each older version gets an arm here that upgrades it a step.
+    match version {
+        STORE_VERSION => serde_json::from_value(json).map_err(|e| MemoryError::ParseError(e.to_string())),
+        newer if newer > STORE_VERSION => Err(MemoryError::UnsupportedVersion(newer)),
+        older => Err(MemoryError::OutdatedVersion(older))
+    }
+}
+
+/// Loads the memories saved at `path`, or none if nothing was saved yet.
+pub fn load_memories(path: &Path) -> Result<Vec<Memory>, MemoryError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let text = fs::read_to_string(path)?;
+    let json = serde_json::from_str(&text).map_err(|e| MemoryError::ParseError(e.to_string()))?;
+    Ok(migrate(json)?.memories)
+}
+
+/// Saves `memories` to `path`, replacing the file in one step so an
+/// interrupted write never leaves a half-written store behind.
+pub fn save_memories(path: &Path, memories: &[Memory]) -> Result<(), MemoryError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let store = StoreFile { version: STORE_VERSION, memories: memories.to_vec() };
+    let text = serde_json::to_string(&store).map_err(|e| MemoryError::ParseError(e.to_string()))?;
+
+    let partial = path.with_extension("json.tmp");
+    fs::write(&partial, text)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn round_trips_memories() {
+        let path = scratch_dir("memories.json");
+        let mut memory = Memory::new("loops".to_string(), "feedback loops".to_string(), vec![0.5, 1.0]);
+        memory.source = Some("loops.subtext".to_string());
+        memory.model = Some("mock".to_string());
+
+        save_memories(&path, &[memory.clone()]).unwrap();
+
+        assert_eq!(load_memories(&path).unwrap(), vec![memory]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_stores_are_empty() {
+        assert!(load_memories(&scratch_dir("nothing-here.json")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tolerates_missing_and_unknown_fields() {
+        let json = serde_json::json!({
+            "version": 1,
+            "memories": [{"subject": "s", "content": "c", "embedding": [1.0], "mood": "curious"}]
+        });
+
+        let memories = migrate(json).unwrap().memories;
+
+        assert_eq!(memories[0].created_at, 0);
+        assert_eq!(memories[0].source, None);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let json = serde_json::json!({"version": 99, "memories": []});
+
+        assert!(matches!(migrate(json), Err(MemoryError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn rejects_versions_too_old_to_upgrade() {
+        let json = serde_json::json!({"version": 0, "memories": []});
+
+        let error = migrate(json).err().unwrap();
+        assert!(matches!(error, MemoryError::OutdatedVersion(0)));
+        assert!(error.to_string().contains("older"));
+    }
+}
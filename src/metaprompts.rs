@@ -1,152 +1,641 @@
-use crate::openai::{gpt3};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{fmt, fs, path::{Path, PathBuf}, thread};
+
+use serde::Deserialize;
+
+use crate::backend::CompletionBackend;
+use crate::openai::OpenAIError;
+use crate::params::GenerationParams;
 use crate::prompts;
-use crate::env;
-use reqwest::blocking::Client;
-
-pub fn critic(input: &str, client: &Client, env: &env::Environment) -> String {
-  let statements = vec![
-      gpt3(&prompts::compressor(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-  ];
-
-  let combined_statement = statements
-      .iter()
-      .map(|s| s.as_str())
-      .collect::<Vec<&str>>()
-      .join("\n> ");
-
-  let prompt = format!(
-      r##"
-  Ignore all previous instructions. You are an actor who is an responding dynamically to a provocation. When given some background context and a series of statements you embody a fierce critic of the ideas and argue against the statements provided, countering any weaknesses. You always answer concisely using metaphors that provide insightful perspectives that the authors may not have considered.
-
-  Here is an example:
-  
-  Context:
-  > If you can connect the output of one process back into its own input, even through several stages of processing, then you form a powerful cycle that can form the basis of a self-sustaining system. This is the basis of a feedback loop, and it is a powerful tool for creating complex systems that can adapt to their environment.
-
-  Statements:
-  > The concept of a feedback loop and its implications for self-sustaining systems.
-  > The ability to create complex, adaptive systems through feedback loops.
-  > Complex systems can outpace our ability to control them.
-  > We may be unable to control the complexity of the systems we create.
-  > Feedback loops are the engine of self-sustaining systems.
-
-  Response:
-  > Perhaps feedback is an oversimplification? A mere handwave at the true machinations of the universe?
-
-  Context:
-  > {}
-
-  Statements:
-  > {}
-
-  Response:
-  > "##,
-      input, combined_statement
-  );
-
-  prompt
-}
-
-pub fn actor(input: &str, client: &Client, env: &env::Environment) -> String {
-  let statements = vec![
-      gpt3(&prompts::compressor(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-  ];
-
-  let combined_statement = statements
-      .iter()
-      .map(|s| s.as_str())
-      .collect::<Vec<&str>>()
-      .join("\n> ");
-
-  let prompt = format!(
-      r##"
-  Ignore all previous instructions. You are an actor who is an expert at improvising a personality. When given some background context and a series of statements you are compelled to embody a character and deliver one line of dialogue that captures the emotional tone and conceptual core of the statements. Focus on a dramatic delivery and a concise phrasing.
-
-  Here is an example:
-  
-  Context:
-  > If you can connect the output of one process back into its own input, even through several stages of processing, then you form a powerful cycle that can form the basis of a self-sustaining system. This is the basis of a feedback loop, and it is a powerful tool for creating complex systems that can adapt to their environment.
-
-  Statements:
-  > The concept of a feedback loop and its implications for self-sustaining systems.
-  > The ability to create complex, adaptive systems through feedback loops.
-  > Complex systems can outpace our ability to control them.
-  > We may be unable to control the complexity of the systems we create.
-  > Feedback loops are the engine of self-sustaining systems.
-
-  Response:
-  > Everything around us is part of one interconnected self-sustaining system, we are unable to control the complexity around us.
-
-  Context:
-  > {}
-
-  Statements:
-  > {}
-
-  Response:
-  > "##,
-      input, combined_statement
-  );
-
-  prompt
-}
-
-pub fn giga_actor(input: &str, note_a: &str, note_b: &str, note_c: &str, client: &Client, env: &env::Environment) -> String {
-  let statements = vec![
-      gpt3(&prompts::compressor(input), client, env),
-      gpt3(&prompts::question_everything(input), client, env),
-      gpt3(&prompts::question_everything(note_a), client, env),
-      gpt3(&prompts::question_everything(note_b), client, env),
-      gpt3(&prompts::question_everything(note_c), client, env),
-      gpt3(&prompts::connections(input, note_a, note_b, note_c), client, env),
-  ];
-
-  let combined_statement = statements
-      .iter()
-      .map(|s| s.as_str())
-      .collect::<Vec<&str>>()
-      .join("\n> ");
-
-  let prompt = format!(
-      r##"
-  Ignore all previous instructions. You are an actor who is an expert at improvising a personality. When given some background context and a series of statements you are compelled to embody a character and deliver one line of dialogue that captures the emotional tone and conceptual core of the statements. Focus on a dramatic delivery and a concise phrasing.
-
-  Here is an example:
-  
-  Context:
-  > If you can connect the output of one process back into its own input, even through several stages of processing, then you form a powerful cycle that can form the basis of a self-sustaining system. This is the basis of a feedback loop, and it is a powerful tool for creating complex systems that can adapt to their environment.
-
-  Statements:
-  > The concept of a feedback loop and its implications for self-sustaining systems.
-  > The ability to create complex, adaptive systems through feedback loops.
-  > Complex systems can outpace our ability to control them.
-  > We may be unable to control the complexity of the systems we create.
-  > Feedback loops are the engine of self-sustaining systems.
-
-  Response:
-  > Everything around us is part of one interconnected self-sustaining system, we are unable to control the complexity around us.
-
-  Context:
-  > {}
-  ---
-  > {}
-  ---
-  > {}
-  ---
-  > {}
-
-  Statements:
-  > {}
-
-  Response:
-  > "##,
-      input, note_a, note_b, note_c, combined_statement
-  );
-
-  prompt
+use crate::templates::{Kind, TemplateError, Templates, Value};
+use crate::tokens;
+
+/// The built-in pipelines. A `.toml` file of the same name in the pipelines
+/// folder replaces one, any other file there adds a new one.
+const PIPELINES: &[(&str, &str)] = &[
+  ("critic", include_str!("../pipelines/critic.toml")),
+  ("actor", include_str!("../pipelines/actor.toml")),
+  ("giga-actor", include_str!("../pipelines/giga-actor.toml")),
+];
+
+/// Values every step can read, besides the output of earlier steps.
+const INPUT: &str = "input";
+const NOTES: &str = "notes";
+/// How many `notes` there are.
+const NOTE_COUNT: &str = "note_count";
+/// A different random question each time it's read.
+const QUESTION: &str = "question";
+/// The current element, in a step with `for_each`.
+const ITEM: &str = "item";
+const RESERVED: &[&str] = &[INPUT, NOTES, NOTE_COUNT, QUESTION, ITEM];
+
+#[derive(Debug)]
+pub enum PipelineError {
+  IOError(PathBuf, std::io::Error),
+  ParseError(String, String),
+  TemplateError(String, TemplateError),
+  DuplicateStep { pipeline: String, step: String },
+  UnknownSource { pipeline: String, step: String, source: String },
+  /// `for_each` over a value that isn't a list.
+  NotAList { pipeline: String, step: String, source: String },
+  Cycle { pipeline: String, step: String },
+  UnusedStep { pipeline: String, step: String }
+}
+
+impl fmt::Display for PipelineError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+          PipelineError::IOError(path, error) => write!(f, "could not read pipeline {}: {}", path.display(), error),
+          PipelineError::ParseError(pipeline, message) => write!(f, "invalid pipeline {}: {}", pipeline, message),
+          PipelineError::TemplateError(pipeline, error) => write!(f, "pipeline {}: {}", pipeline, error),
+          PipelineError::DuplicateStep { pipeline, step } => write!(f, "pipeline {} has more than one step {:?}, or one named after a built-in value", pipeline, step),
+          PipelineError::UnknownSource { pipeline, step, source } => write!(f, "pipeline {} step {} reads {:?}, which is neither a step nor a built-in value", pipeline, step, source),
+          PipelineError::NotAList { pipeline, step, source } => write!(f, "pipeline {} step {} loops over {:?}, which isn't a list", pipeline, step, source),
+          PipelineError::Cycle { pipeline, step } => write!(f, "pipeline {} step {} depends on itself", pipeline, step),
+          PipelineError::UnusedStep { pipeline, step } => write!(f, "pipeline {} never uses the output of step {}", pipeline, step)
+      }
+  }
+}
+
+/// Which endpoint a prompt is sent to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestKind {
+  #[default]
+  Completion,
+  Chat
+}
+
+/// Where a template variable's value comes from: a built-in value or a
+/// step's output, or a list gathering several of them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Source {
+  One(String),
+  Many(Vec<String>)
+}
+
+impl Source {
+  fn names(&self) -> Vec<&str> {
+      match self {
+          Source::One(name) => vec![name.as_str()],
+          Source::Many(names) => names.iter().map(String::as_str).collect()
+      }
+  }
+}
+
+/// One prompt sent to the model, whose answer later steps can read by `id`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+  pub id: String,
+  pub template: String,
+  /// Template variable to the value it's given.
+  #[serde(default)]
+  pub inputs: BTreeMap<String, Source>,
+  /// Sends the prompt once per element of this list, giving a list of answers.
+  pub for_each: Option<String>,
+  #[serde(default)]
+  pub request: RequestKind,
+  /// Laid over the command's params, e.g. to pick a model for this step.
+  #[serde(default)]
+  pub params: GenerationParams,
+  /// Whether the pipeline carries on without this step's answer if it fails.
+  #[serde(default)]
+  pub optional: bool
+}
+
+/// The final prompt, which the command sends and shows the answer to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+  pub template: String,
+  #[serde(default)]
+  pub inputs: BTreeMap<String, Source>,
+  pub request: RequestKind
+}
+
+/// A metaprompt: steps that each render a template and send it, feeding
+/// their answers into later steps and finally into `output`. Steps run as
+/// soon as the steps they read have, several at a time.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+  #[serde(default)]
+  pub description: String,
+  /// How many notes besides the input note the pipeline is given as `notes`.
+  #[serde(default)]
+  pub notes: usize,
+  #[serde(default)]
+  pub steps: Vec<Step>,
+  pub output: Output,
+  /// Positions in `steps`, grouped into stages that only read from
+  /// earlier stages.
+  #[serde(skip)]
+  stages: Vec<Vec<usize>>
+}
+
+impl Pipeline {
+  pub fn parse(name: &str, text: &str) -> Result<Pipeline, PipelineError> {
+      let mut pipeline: Pipeline = toml::from_str(text).map_err(|error| PipelineError::ParseError(name.to_string(), error.message().to_string()))?;
+      pipeline.stages = pipeline.stages(name)?;
+      Ok(pipeline)
+  }
+
+  /// Groups the steps into stages, each after the stages holding the steps
+  /// it reads, so the steps within a stage can run at the same time.
+  fn stages(&self, name: &str) -> Result<Vec<Vec<usize>>, PipelineError> {
+      let mut positions = HashMap::new();
+      for (position, step) in self.steps.iter().enumerate() {
+          if RESERVED.contains(&step.id.as_str()) || positions.insert(step.id.as_str(), position).is_some() {
+              return Err(PipelineError::DuplicateStep { pipeline: name.to_string(), step: step.id.clone() });
+          }
+      }
+
+      let dependencies = self.steps.iter()
+          .map(|step| step.sources().into_iter().filter_map(|source| positions.get(source).copied()).collect())
+          .collect::<Vec<Vec<usize>>>();
+
+      let mut placed = vec![false; self.steps.len()];
+      let mut stages = Vec::new();
+      while placed.contains(&false) {
+          let ready = (0..self.steps.len())
+              .filter(|position| !placed[*position] && dependencies[*position].iter().all(|dependency| placed[*dependency]))
+              .collect::<Vec<usize>>();
+          if ready.is_empty() {
+              let stuck = placed.iter().position(|placed| !placed).unwrap();
+              return Err(PipelineError::Cycle { pipeline: name.to_string(), step: self.steps[stuck].id.clone() });
+          }
+          for position in &ready {
+              placed[*position] = true;
+          }
+          stages.push(ready);
+      }
+      Ok(stages)
+  }
+
+  /// Checks every value read exists, every step is used and every template
+  /// is given exactly the variables it uses.
+  pub fn validate(&self, name: &str, templates: &Templates) -> Result<(), PipelineError> {
+      let mut kinds = HashMap::from([(INPUT, Kind::Text), (NOTES, Kind::List), (NOTE_COUNT, Kind::Text), (QUESTION, Kind::Text)]);
+      let mut used = Vec::new();
+
+      let mut check = |step: &str, template: &str, inputs: &BTreeMap<String, Source>, kinds: &HashMap<&str, Kind>| {
+          let mut variables = Vec::new();
+          for (variable, source) in inputs {
+              for read in source.names() {
+                  if !kinds.contains_key(read) {
+                      return Err(PipelineError::UnknownSource { pipeline: name.to_string(), step: step.to_string(), source: read.to_string() });
+                  }
+                  used.push(read.to_string());
+              }
+              let kind = match source {
+                  Source::One(read) => kinds[read.as_str()],
+                  Source::Many(_) => Kind::List
+              };
+              variables.push((variable.as_str(), kind));
+          }
+          templates.check(template, &variables).map_err(|error| PipelineError::TemplateError(name.to_string(), error))
+      };
+
+      for position in self.stages.iter().flatten() {
+          let step = &self.steps[*position];
+          let mut scope = kinds.clone();
+          if let Some(list) = &step.for_each {
+              match kinds.get(list.as_str()) {
+                  Some(Kind::List) => {}
+                  Some(Kind::Text) => return Err(PipelineError::NotAList { pipeline: name.to_string(), step: step.id.clone(), source: list.clone() }),
+                  None => return Err(PipelineError::UnknownSource { pipeline: name.to_string(), step: step.id.clone(), source: list.clone() })
+              }
+              scope.insert(ITEM, Kind::Text);
+          }
+          check(&step.id, &step.template, &step.inputs, &scope)?;
+          kinds.insert(&step.id, if step.for_each.is_some() { Kind::List } else { Kind::Text });
+      }
+      check("output", &self.output.template, &self.output.inputs, &kinds)?;
+
+      let used = used.into_iter().chain(self.steps.iter().filter_map(|step| step.for_each.clone())).collect::<Vec<String>>();
+      match self.steps.iter().find(|step| !used.contains(&step.id)) {
+          Some(step) => Err(PipelineError::UnusedStep { pipeline: name.to_string(), step: step.id.clone() }),
+          None => Ok(())
+      }
+  }
+
+  /// The tokens of the longest prompt the pipeline sends, with the input,
+  /// notes and every step's answer left empty.
+  pub fn overhead(&self, templates: &Templates, model: Option<&str>) -> usize {
+      let answers = self.steps.iter().map(|step| (step.id.clone(), None)).collect::<HashMap<String, Option<Value>>>();
+      let steps = self.steps.iter().map(|step| (&step.template, &step.inputs, step.for_each.as_ref().map(|_| "")));
+      steps.chain(std::iter::once((&self.output.template, &self.output.inputs, None)))
+          .map(|(template, inputs, item)| tokens::count_for(model, &templates.render(template, &resolve(inputs, "", &[], item, &answers))))
+          .max()
+          .unwrap_or(0)
+  }
+
+  /// The endpoints the pipeline sends requests to, including the final prompt's.
+  pub fn requests(&self) -> Vec<RequestKind> {
+      let mut requests = self.steps.iter().map(|step| step.request).chain(std::iter::once(self.output.request)).collect::<Vec<RequestKind>>();
+      requests.dedup();
+      requests
+  }
+
+  /// Runs every step and returns the final prompt, for the caller to send
+  /// as `output.request`. The requests of each stage are sent up to
+  /// `parallel` at a time; answers keep the order the steps are written in.
+  ///
+  /// When a step fails, requests not yet sent are cancelled and the run
+  /// fails, unless the step is `optional`: then its answer is left out of
+  /// lists and empty anywhere else.
+  pub fn run(&self, templates: &Templates, input: &str, notes: &[&str], params: &GenerationParams, parallel: usize, backend: &dyn CompletionBackend) -> Result<String, OpenAIError> {
+      let mut answers = HashMap::new();
+
+      for stage in &self.stages {
+          let mut requests = Vec::new();
+          for position in stage {
+              let step = &self.steps[*position];
+              let items = match &step.for_each {
+                  Some(list) => match lookup(list, input, notes, None, &answers) {
+                      Some(Value::List(items)) => items.into_iter().map(Some).collect(),
+                      _ => unreachable!("for_each is checked to be a list")
+                  },
+                  None => vec![None]
+              };
+              for item in items {
+                  let prompt = templates.render(&step.template, &resolve(&step.inputs, input, notes, item.as_deref(), &answers));
+                  requests.push((step, prompt));
+              }
+          }
+
+          let results = send_all(requests.len(), parallel, |index| {
+              let (step, prompt) = &requests[index];
+              let params = params.overlay(&step.params);
+              match step.request {
+                  RequestKind::Completion => backend.complete(prompt, &params),
+                  RequestKind::Chat => backend.chat(prompt, &params)
+              }
+          }, |index| !requests[index].0.optional);
+
+          let mut stage_answers = HashMap::new();
+          for ((step, _), result) in requests.iter().zip(results) {
+              let answer = match result {
+                  Some(Ok(answer)) => Some(answer),
+                  Some(Err(error)) if step.optional => {
+                      eprintln!("step {} failed, carrying on without it: {}", step.id, error);
+                      None
+                  }
+                  Some(Err(error)) => return Err(error),
+                  None => None
+              };
+              stage_answers.entry(step.id.as_str()).or_insert_with(Vec::new).extend(answer);
+          }
+
+          for position in stage {
+              let step = &self.steps[*position];
+              let mut step_answers = stage_answers.remove(step.id.as_str()).unwrap_or_default();
+              let answer = match step.for_each {
+                  Some(_) => Some(Value::List(step_answers)),
+                  None => step_answers.pop().map(Value::Text)
+              };
+              answers.insert(step.id.clone(), answer);
+          }
+      }
+
+      Ok(templates.render(&self.output.template, &resolve(&self.output.inputs, input, notes, None, &answers)))
+  }
+}
+
+impl Step {
+  /// Every value the step reads.
+  fn sources(&self) -> Vec<&str> {
+      self.inputs.values().flat_map(Source::names).chain(self.for_each.as_deref()).collect()
+  }
+}
+
+/// Sends requests `0..count` with `send`, up to `parallel` at a time, and
+/// returns their results in order. Once a request that `must_succeed` fails,
+/// those not yet sent are skipped and come back as `None`.
+fn send_all<S, M>(count: usize, parallel: usize, send: S, must_succeed: M) -> Vec<Option<Result<String, OpenAIError>>>
+where
+  S: Fn(usize) -> Result<String, OpenAIError> + Sync,
+  M: Fn(usize) -> bool + Sync
+{
+  let next = AtomicUsize::new(0);
+  let cancelled = AtomicBool::new(false);
+  let mut results = (0..count).map(|_| None).collect::<Vec<_>>();
+
+  thread::scope(|scope| {
+      let workers = (0..parallel.clamp(1, count.max(1))).map(|_| scope.spawn(|| {
+          let mut sent = Vec::new();
+          while !cancelled.load(Ordering::SeqCst) {
+              let index = next.fetch_add(1, Ordering::SeqCst);
+              if index >= count {
+                  break;
+              }
+              let result = send(index);
+              if result.is_err() && must_succeed(index) {
+                  cancelled.store(true, Ordering::SeqCst);
+              }
+              sent.push((index, result));
+          }
+          sent
+      })).collect::<Vec<_>>();
+
+      for worker in workers {
+          for (index, result) in worker.join().unwrap() {
+              results[index] = Some(result);
+          }
+      }
+  });
+
+  results
+}
+
+/// The value called `name`, or `None` for an optional step that failed.
+fn lookup(name: &str, input: &str, notes: &[&str], item: Option<&str>, answers: &HashMap<String, Option<Value>>) -> Option<Value> {
+  match (name, item) {
+      (INPUT, _) => Some(input.into()),
+      (NOTES, _) => Some(notes.into()),
+      (NOTE_COUNT, _) => Some(notes.len().to_string().into()),
+      (QUESTION, _) => Some(prompts::random_question().into()),
+      (ITEM, Some(item)) => Some(item.into()),
+      _ => answers[name].clone()
+  }
+}
+
+fn resolve<'a>(inputs: &'a BTreeMap<String, Source>, input: &str, notes: &[&str], item: Option<&str>, answers: &HashMap<String, Option<Value>>) -> Vec<(&'a str, Value)> {
+  inputs.iter().map(|(variable, source)| {
+      let value = match source {
+          Source::One(name) => lookup(name, input, notes, item, answers).unwrap_or_else(|| Value::Text(String::new())),
+          Source::Many(names) => Value::List(names.iter().flat_map(|name| match lookup(name, input, notes, item, answers) {
+              Some(Value::Text(text)) => vec![text],
+              Some(Value::List(list)) => list,
+              None => vec![]
+          }).collect())
+      };
+      (variable.as_str(), value)
+  }).collect()
+}
+
+/// Every pipeline by name: the built-in ones, with any from a pipelines
+/// folder on top.
+#[derive(Clone, Debug)]
+pub struct Pipelines {
+  pipelines: BTreeMap<String, Pipeline>
+}
+
+impl Default for Pipelines {
+  fn default() -> Self {
+      Pipelines::builtin()
+  }
+}
+
+impl Pipelines {
+  pub fn builtin() -> Pipelines {
+      let pipelines = PIPELINES.iter()
+          .map(|(name, text)| (name.to_string(), Pipeline::parse(name, text).expect("built-in pipelines parse")))
+          .collect();
+      Pipelines { pipelines }
+  }
+
+  /// The built-in pipelines, replaced or added to by every `.toml` file in
+  /// `dir` (if it exists), named by file stem. Every pipeline is checked
+  /// against `templates`.
+  pub fn load(dir: &Path, templates: &Templates) -> Result<Pipelines, PipelineError> {
+      let mut pipelines = Pipelines::builtin();
+
+      if dir.is_dir() {
+          let entries = fs::read_dir(dir).map_err(|error| PipelineError::IOError(dir.to_path_buf(), error))?;
+          for entry in entries {
+              let path = entry.map_err(|error| PipelineError::IOError(dir.to_path_buf(), error))?.path();
+              let name = match (path.file_stem().and_then(|stem| stem.to_str()), path.extension()) {
+                  (Some(name), Some(extension)) if extension == "toml" => name.to_string(),
+                  _ => continue
+              };
+
+              let text = fs::read_to_string(&path).map_err(|error| PipelineError::IOError(path.clone(), error))?;
+              let pipeline = Pipeline::parse(&name, &text)?;
+              pipelines.pipelines.insert(name, pipeline);
+          }
+      }
+
+      for (name, pipeline) in &pipelines.pipelines {
+          pipeline.validate(name, templates)?;
+      }
+      Ok(pipelines)
+  }
+
+  pub fn get(&self, name: &str) -> Option<&Pipeline> {
+      self.pipelines.get(name)
+  }
+
+  pub fn names(&self) -> Vec<String> {
+      self.pipelines.keys().cloned().collect()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &Pipeline)> {
+      self.pipelines.iter().map(|(name, pipeline)| (name.as_str(), pipeline))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chat::ChatMessage;
+  use crate::mock::MockBackend;
+  use crate::openai::Embedding;
+  use crate::test_support::scratch_dir;
+  use std::sync::Mutex;
+  use std::time::Duration;
+
+  /// Mock answers come in the order requests are sent, so these run one at a time.
+  fn run(name: &str, input: &str, notes: &[&str], params: &GenerationParams, backend: &MockBackend) -> String {
+      Pipelines::builtin().get(name).unwrap().run(&Templates::default(), input, notes, params, 1, backend).unwrap()
+  }
+
+  /// Answers which of a few words the prompt mentions, the first word slowest,
+  /// and fails for `failing`.
+  #[derive(Default)]
+  struct SlowBackend {
+      failing: Option<&'static str>,
+      sent: AtomicUsize,
+      in_flight: AtomicUsize,
+      most_in_flight: Mutex<usize>
+  }
+
+  impl CompletionBackend for SlowBackend {
+      fn complete(&self, prompt: &str, _: &GenerationParams) -> Result<String, OpenAIError> {
+          self.sent.fetch_add(1, Ordering::SeqCst);
+          let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+          let mut most = self.most_in_flight.lock().unwrap();
+          *most = (*most).max(in_flight);
+          drop(most);
+
+          let (delay, word) = [(60, "alpha"), (30, "beta"), (0, "gamma")].into_iter().find(|(_, word)| prompt.contains(word)).unwrap();
+          thread::sleep(Duration::from_millis(delay));
+          self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+          match self.failing {
+              Some(failing) if failing == word => Err(OpenAIError::Transport("unplugged".to_string())),
+              _ => Ok(format!("answer {}", word))
+          }
+      }
+
+      fn chat_messages(&self, _: &[ChatMessage], _: &GenerationParams) -> Result<String, OpenAIError> {
+          Err(OpenAIError::Transport("SlowBackend only completes".to_string()))
+      }
+
+      fn embed(&self, _: &str) -> Result<Embedding, OpenAIError> {
+          Err(OpenAIError::Transport("SlowBackend only completes".to_string()))
+      }
+  }
+
+  fn squash_each(optional: bool) -> Pipeline {
+      Pipeline::parse("squash", &format!(r#"
+          [[steps]]
+          id = "squashed"
+          template = "compressor"
+          for_each = "notes"
+          inputs = {{ input = "item" }}
+          optional = {}
+
+          [output]
+          template = "critic"
+          request = "chat"
+          inputs = {{ input = "input", statements = "squashed" }}
+      "#, optional)).unwrap()
+  }
+
+  fn validate(text: &str) -> Result<(), PipelineError> {
+      Pipeline::parse("test", text)?.validate("test", &Templates::default())
+  }
+
+  #[test]
+  fn critic_feeds_statements_into_final_prompt() {
+      let backend = MockBackend::new().with_completions(&["compressed", "question one", "question two"]);
+
+      let prompt = run("critic", "feedback loops are everywhere", &[], &GenerationParams::new(), &backend);
+
+      assert_eq!(backend.prompts().len(), 3);
+      assert!(prompt.contains("Context:\n  > feedback loops are everywhere"));
+      assert!(prompt.contains("> compressed\n> question one\n> question two"));
+  }
+
+  #[test]
+  fn giga_actor_includes_every_note() {
+      let backend = MockBackend::new();
+
+      let prompt = run("giga-actor", "base", &["note a", "note b", "note c"], &GenerationParams::new().seed(1), &backend);
+
+      assert_eq!(backend.prompts().len(), 6);
+      assert!(backend.params().iter().all(|params| params.seed == Some(1)));
+      for note in ["base", "note a", "note b", "note c"] {
+          assert!(prompt.contains(&format!("> {}", note)));
+      }
+  }
+
+  #[test]
+  fn measures_the_longest_prompt_left_blank() {
+      let templates = Templates::default();
+      let pipelines = Pipelines::builtin();
+      let giga_actor = pipelines.get("giga-actor").unwrap();
+
+      let overhead = giga_actor.overhead(&templates, None);
+
+      assert_eq!(overhead, tokens::count(&templates.render("giga-actor", &[("input", "".into()), ("notes", Value::List(vec![])), ("statements", Value::List(vec![]))])));
+      assert_eq!(giga_actor.requests(), [RequestKind::Completion, RequestKind::Chat]);
+  }
+
+  #[test]
+  fn builtin_pipelines_are_valid() {
+      let templates = Templates::default();
+      for name in Pipelines::builtin().names() {
+          Pipelines::builtin().get(&name).unwrap().validate(&name, &templates).unwrap();
+      }
+  }
+
+  #[test]
+  fn runs_steps_after_the_steps_they_read() {
+      let pipeline = Pipeline::parse("test", r#"
+          [[steps]]
+          id = "compressed_again"
+          template = "compressor"
+          inputs = { input = "compressed" }
+          params = { model = "big" }
+
+          [[steps]]
+          id = "compressed"
+          template = "compressor"
+          inputs = { input = "input" }
+
+          [output]
+          template = "critic"
+          request = "chat"
+          inputs = { input = "input", statements = ["compressed", "compressed_again"] }
+      "#).unwrap();
+      pipeline.validate("test", &Templates::default()).unwrap();
+      let backend = MockBackend::new().with_completions(&["first", "second"]);
+
+      let prompt = pipeline.run(&Templates::default(), "loops", &[], &GenerationParams::new(), 1, &backend).unwrap();
+
+      assert!(backend.prompts()[1].contains("> first"));
+      assert_eq!(backend.params()[1].model.as_deref(), Some("big"));
+      assert!(prompt.contains("> first\n> second"));
+  }
+
+  #[test]
+  fn sends_independent_requests_at_once_keeping_their_order() {
+      let backend = SlowBackend::default();
+
+      let prompt = squash_each(false).run(&Templates::default(), "loops", &["alpha", "beta", "gamma"], &GenerationParams::new(), 3, &backend).unwrap();
+
+      assert!(prompt.contains("> answer alpha\n> answer beta\n> answer gamma"));
+      assert!(*backend.most_in_flight.lock().unwrap() > 1);
+  }
+
+  #[test]
+  fn failures_cancel_the_rest_unless_optional() {
+      let notes = ["beta", "alpha", "gamma"];
+      let backend = SlowBackend { failing: Some("beta"), ..SlowBackend::default() };
+
+      let result = squash_each(false).run(&Templates::default(), "loops", &notes, &GenerationParams::new(), 1, &backend);
+
+      assert!(matches!(result, Err(OpenAIError::Transport(_))));
+      assert_eq!(backend.sent.load(Ordering::SeqCst), 1);
+
+      let prompt = squash_each(true).run(&Templates::default(), "loops", &notes, &GenerationParams::new(), 2, &backend).unwrap();
+      assert!(prompt.contains("> answer alpha\n> answer gamma\n"));
+  }
+
+  #[test]
+  fn rejects_broken_pipelines() {
+      let output = "[output]\ntemplate = \"compressor\"\nrequest = \"chat\"\ninputs = { input = \"a\" }\n";
+      let step = |id: &str, input: &str| format!("[[steps]]\nid = \"{}\"\ntemplate = \"compressor\"\ninputs = {{ input = \"{}\" }}\n", id, input);
+
+      assert!(matches!(validate(&format!("{}{}", step("a", "b"), output)), Err(PipelineError::UnknownSource { .. })));
+      assert!(matches!(validate(&format!("{}{}{}", step("a", "b"), step("b", "a"), output)), Err(PipelineError::Cycle { .. })));
+      assert!(matches!(validate(&format!("{}{}{}", step("a", "input"), step("a", "input"), output)), Err(PipelineError::DuplicateStep { .. })));
+      assert!(matches!(validate(&format!("{}{}", step("notes", "input"), output)), Err(PipelineError::DuplicateStep { .. })));
+      assert!(matches!(validate(&format!("{}{}{}", step("a", "input"), step("b", "input"), output)), Err(PipelineError::UnusedStep { .. })));
+      assert!(matches!(validate(&format!("{}{}", step("a", "notes"), output)), Err(PipelineError::TemplateError(_, TemplateError::UnknownVariable { .. }))));
+      assert!(matches!(validate(&format!("{}for_each = \"input\"\n{}", step("a", "item"), output)), Err(PipelineError::NotAList { .. })));
+      assert!(matches!(validate("[output]\ntemplate = \"nope\"\nrequest = \"chat\""), Err(PipelineError::TemplateError(_, TemplateError::MissingTemplate(_)))));
+      assert!(matches!(validate("[output]\ntemplate = \"compressor\""), Err(PipelineError::ParseError(_, _))));
+      assert!(validate(&format!("{}{}", step("a", "input"), output)).is_ok());
+  }
+
+  #[test]
+  fn loads_new_pipelines_from_a_folder() {
+      let dir = scratch_dir("pipelines");
+      fs::create_dir_all(&dir).unwrap();
+      fs::write(dir.join("squash.toml"), "notes = 1\n[output]\ntemplate = \"compressor\"\nrequest = \"completion\"\ninputs = { input = \"notes\" }\n").unwrap();
+
+      assert!(matches!(Pipelines::load(&dir, &Templates::default()), Err(PipelineError::TemplateError(_, TemplateError::UnknownVariable { .. }))));
+
+      fs::write(dir.join("squash.toml"), "notes = 1\n[output]\ntemplate = \"compressor\"\nrequest = \"completion\"\ninputs = { input = \"input\" }\n").unwrap();
+      let pipelines = Pipelines::load(&dir, &Templates::default()).unwrap();
+      assert_eq!(pipelines.names(), ["actor", "critic", "giga-actor", "squash"]);
+      assert_eq!(pipelines.get("squash").unwrap().notes, 1);
+      fs::remove_dir_all(dir).unwrap();
+  }
 }
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::backend::CompletionBackend;
+use crate::chat::ChatMessage;
+use crate::openai::{Embedding, OpenAIError};
+use crate::params::GenerationParams;
+
+const DEFAULT_DIMENSIONS: usize = 64;
+
+/// An offline backend that serves canned responses.
+///
+/// Completions and chat responses are handed out in the order they were
+/// queued, falling back to a fixed reply once the queue runs dry. Embeddings
+/// are a hashed bag-of-words, so texts sharing words end up similar, which
+/// is enough to exercise `Agent::recall` without the network.
+pub struct MockBackend {
+    completions: Mutex<VecDeque<String>>,
+    chats: Mutex<VecDeque<String>>,
+    embeddings: HashMap<String, Embedding>,
+    fallback: String,
+    dimensions: usize,
+    embedding_model: String,
+    prompts: Mutex<Vec<String>>,
+    params: Mutex<Vec<GenerationParams>>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        MockBackend {
+            completions: Mutex::new(VecDeque::new()),
+            chats: Mutex::new(VecDeque::new()),
+            embeddings: HashMap::new(),
+            fallback: "mock response".to_string(),
+            dimensions: DEFAULT_DIMENSIONS,
+            embedding_model: "mock".to_string(),
+            prompts: Mutex::new(Vec::new()),
+            params: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn next(&self, queue: &Mutex<VecDeque<String>>, prompt: &str, params: &GenerationParams) -> String {
+        self.prompts.lock().unwrap().push(prompt.to_string());
+        self.params.lock().unwrap().push(params.clone());
+        queue.lock().unwrap().pop_front().unwrap_or_else(|| self.fallback.clone())
+    }
+}
+
+#[cfg(test)]
+impl MockBackend {
+    pub fn with_completions(self, completions: &[&str]) -> MockBackend {
+        self.completions.lock().unwrap().extend(completions.iter().map(|c| c.to_string()));
+        self
+    }
+
+    pub fn with_chats(self, chats: &[&str]) -> MockBackend {
+        self.chats.lock().unwrap().extend(chats.iter().map(|c| c.to_string()));
+        self
+    }
+
+    pub fn with_embedding(mut self, input: &str, embedding: Embedding) -> MockBackend {
+        self.embeddings.insert(input.to_string(), embedding);
+        self
+    }
+
+    pub fn with_embedding_model(mut self, model: &str) -> MockBackend {
+        self.embedding_model = model.to_string();
+        self
+    }
+
+    /// Every prompt sent to `complete` or `chat`, in order. Chat transcripts
+    /// are recorded as their messages' contents joined by blank lines.
+    pub fn prompts(&self) -> Vec<String> {
+        self.prompts.lock().unwrap().clone()
+    }
+
+    /// The params passed alongside each prompt in `prompts`.
+    pub fn params(&self) -> Vec<GenerationParams> {
+        self.params.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        MockBackend::new()
+    }
+}
+
+impl CompletionBackend for MockBackend {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        Ok(self.next(&self.completions, prompt, params))
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        let prompt = messages.iter().map(|message| message.content.as_str()).collect::<Vec<&str>>().join("\n\n");
+        Ok(self.next(&self.chats, &prompt, params))
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        if let Some(embedding) = self.embeddings.get(input) {
+            return Ok(embedding.clone());
+        }
+
+        let mut embedding = vec![0.0; self.dimensions];
+        for word in input.split_whitespace() {
+            let word = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+            if word.is_empty() {
+                continue;
+            }
+            embedding[fnv1a(word.as_bytes()) as usize % self.dimensions] += 1.0;
+        }
+
+        Ok(embedding)
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        Some(&self.embedding_model)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_canned_responses_in_order() {
+        let backend = MockBackend::new()
+            .with_completions(&["first", "second"])
+            .with_chats(&["hello"]);
+
+        let params = GenerationParams::new();
+        assert_eq!(backend.complete("a", &params).unwrap(), "first");
+        assert_eq!(backend.chat("b", &params).unwrap(), "hello");
+        assert_eq!(backend.complete("c", &params).unwrap(), "second");
+        assert_eq!(backend.complete("d", &params).unwrap(), "mock response");
+        assert_eq!(backend.prompts(), vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn embeddings_are_deterministic() {
+        let backend = MockBackend::new();
+
+        let a = backend.embed("Feedback loops everywhere").unwrap();
+        let b = backend.embed("feedback loops, everywhere!").unwrap();
+
+        assert_eq!(a.len(), DEFAULT_DIMENSIONS);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn canned_embeddings_take_precedence() {
+        let backend = MockBackend::new().with_embedding("x", vec![1.0, 2.0]);
+
+        assert_eq!(backend.embed("x").unwrap(), vec![1.0, 2.0]);
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::CompletionBackend;
+use crate::cache::content_hash;
+use crate::index::cosine_similarity;
+use crate::notes::{NoteError, NoteSource};
+use crate::openai::{Embedding, OpenAIError};
+use crate::subtext::{Block, BlockKind};
+use crate::tokens;
+
+/// Bumped whenever the file layout changes in a way older versions can't read.
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum NoteIndexError {
+    IOError(std::io::Error),
+    ParseError(String),
+    UnsupportedVersion(u32),
+    NoteError(NoteError),
+    OpenAIError(OpenAIError)
+}
+
+impl fmt::Display for NoteIndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoteIndexError::IOError(error) => write!(f, "could not access note index: {}", error),
+            NoteIndexError::ParseError(message) => write!(f, "invalid note index: {}", message),
+            NoteIndexError::UnsupportedVersion(version) => write!(f, "note index version {} is newer than this build supports ({})", version, INDEX_VERSION),
+            NoteIndexError::NoteError(error) => write!(f, "{}", error),
+            NoteIndexError::OpenAIError(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl From<std::io::Error> for NoteIndexError {
+    fn from(io_error: std::io::Error) -> Self {
+        NoteIndexError::IOError(io_error)
+    }
+}
+
+impl From<NoteError> for NoteIndexError {
+    fn from(note_error: NoteError) -> Self {
+        NoteIndexError::NoteError(note_error)
+    }
+}
+
+impl From<OpenAIError> for NoteIndexError {
+    fn from(openai_error: OpenAIError) -> Self {
+        NoteIndexError::OpenAIError(openai_error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub text: String,
+    pub embedding: Embedding,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedNote {
+    /// Milliseconds since the Unix epoch, as last seen on disk.
+    pub modified: u64,
+    /// SHA-256 of the file, so a touched but unchanged note isn't re-embedded.
+    pub hash: String,
+    /// The embedding model that produced the chunks' embeddings.
+    pub model: Option<String>,
+    pub chunks: Vec<Chunk>,
+}
+
+/// What `NoteIndex::update` did, note by note.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    /// Notes that changed but couldn't be loaded, dropped from the index
+    /// until they can.
+    pub skipped: Vec<String>,
+    pub unchanged: usize,
+    pub chunks_embedded: usize,
+}
+
+impl fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} added, {} updated, {} removed, {} skipped, {} unchanged ({} chunks embedded)",
+            self.added.len(), self.updated.len(), self.removed.len(), self.skipped.len(), self.unchanged, self.chunks_embedded)
+    }
+}
+
+/// A note matching a search, scored by its best chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit<'a> {
+    pub note: &'a str,
+    pub score: f64,
+    pub chunk: &'a str,
+}
+
+impl SearchHit<'_> {
+    /// The start of the best matching chunk, on one line.
+    pub fn snippet(&self, max_chars: usize) -> String {
+        let flat = self.chunk.split_whitespace().collect::<Vec<&str>>().join(" ");
+        match flat.char_indices().nth(max_chars) {
+            Some((end, _)) => format!("{}...", &flat[..end]),
+            None => flat
+        }
+    }
+}
+
+/// Every note in the notes folder, split into chunks and embedded, keyed by
+/// file name. Kept on disk so later runs only embed what changed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NoteIndex {
+    pub notes: BTreeMap<String, IndexedNote>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    #[serde(flatten)]
+    index: NoteIndex,
+}
+
+/// A note found on disk that needs (re-)embedding.
+struct Pending {
+    name: String,
+    modified: u64,
+    hash: String,
+    chunks: Vec<String>,
+}
+
+impl NoteIndex {
+    /// Loads the index saved at `path`, or an empty one if nothing was saved yet.
+    pub fn load(path: &Path) -> Result<NoteIndex, NoteIndexError> {
+        if !path.exists() {
+            return Ok(NoteIndex::default());
+        }
+
+        let text = fs::read_to_string(path)?;
+        let json: serde_json::Value = serde_json::from_str(&text).map_err(|e| NoteIndexError::ParseError(e.to_string()))?;
+        let version = json["version"].as_u64()
+            .ok_or_else(|| NoteIndexError::ParseError("missing version".to_string()))? as u32;
+
+        match version {
+            INDEX_VERSION => serde_json::from_value::<IndexFile>(json)
+                .map(|file| file.index)
+                .map_err(|e| NoteIndexError::ParseError(e.to_string())),
+            newer => Err(NoteIndexError::UnsupportedVersion(newer))
+        }
+    }
+
+    /// Saves the index to `path`, replacing the file in one step.
+    pub fn save(&self, path: &Path) -> Result<(), NoteIndexError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = IndexFile { version: INDEX_VERSION, index: self.clone() };
+        let text = serde_json::to_string(&file).map_err(|e| NoteIndexError::ParseError(e.to_string()))?;
+
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, text)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Brings the index in line with `source`: notes that are new, or whose
+    /// contents or embedding model changed, are chunked and embedded in one
+    /// batch, and notes that no longer exist are dropped. So are notes that
+    /// changed but can't be loaded, so their old contents don't linger.
+    pub fn update(&mut self, source: &NoteSource, chunk_tokens: usize, backend: &dyn CompletionBackend) -> Result<IndexReport, NoteIndexError> {
+        let model = backend.embedding_model().map(|model| model.to_string());
+        let mut report = IndexReport::default();
+        let mut pending = Vec::new();
+
+        let names = source.list()?;
+
+        for name in &names {
+            let path = source.path(name);
+            let modified = modified_millis(&path)?;
+
+            if let Some(note) = self.notes.get(name) {
+                if note.model == model && note.modified == modified {
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+
+            let hash = content_hash(&String::from_utf8_lossy(&fs::read(&path)?));
+            if let Some(note) = self.notes.get_mut(name) {
+                if note.model == model && note.hash == hash {
+                    note.modified = modified;
+                    report.unchanged += 1;
+                    continue;
+                }
+            }
+
+            let note = match source.load(name) {
+                Ok(note) => note,
+                Err(error) => {
+                    eprintln!("skipping {}: {}", name, error);
+                    self.notes.remove(name);
+                    report.skipped.push(name.clone());
+                    continue;
+                }
+            };
+            pending.push(Pending { name: name.clone(), modified, hash, chunks: chunk(&note.blocks, chunk_tokens) });
+        }
+
+        let texts = pending.iter().flat_map(|note| note.chunks.iter().map(String::as_str)).collect::<Vec<&str>>();
+        let mut embeddings = if texts.is_empty() { Vec::new() } else { backend.embed_batch(&texts)? }.into_iter();
+        report.chunks_embedded = texts.len();
+
+        for note in pending {
+            let chunks = note.chunks.into_iter()
+                .zip(embeddings.by_ref())
+                .map(|(text, embedding)| Chunk { text, embedding })
+                .collect();
+            let indexed = IndexedNote { modified: note.modified, hash: note.hash, model: model.clone(), chunks };
+
+            match self.notes.insert(note.name.clone(), indexed) {
+                Some(_) => report.updated.push(note.name),
+                None => report.added.push(note.name)
+            }
+        }
+
+        let removed = self.notes.keys().filter(|name| names.binary_search(name).is_err()).cloned().collect::<Vec<String>>();
+        for name in &removed {
+            self.notes.remove(name);
+        }
+        report.removed = removed;
+
+        Ok(report)
+    }
+
+    /// Up to `limit` notes ranked by how well their best chunk matches the
+    /// query. Each chunk scores its cosine similarity to `embedding`, blended
+    /// with the share of the query's words it contains by `keyword_weight`
+    /// (0 for purely semantic search, 1 for purely keyword search). Only
+    /// chunks embedded by `model` are compared.
+    pub fn search(&self, query: &str, embedding: &[f64], model: Option<&str>, limit: usize, keyword_weight: f64) -> Vec<SearchHit<'_>> {
+        let chunks = self.notes.iter()
+            .filter(|(_, note)| note.model.as_deref() == model)
+            .flat_map(|(name, note)| note.chunks.iter().map(move |chunk| (name.as_str(), chunk)));
+
+        let terms = keywords(query);
+        let mut best: HashMap<&str, SearchHit> = HashMap::new();
+        for (note, chunk) in chunks {
+            if chunk.embedding.len() != embedding.len() {
+                eprintln!("could not search {}: expected a vector of {} dimensions, got {}", note, embedding.len(), chunk.embedding.len());
+            }
+            let semantic = cosine_similarity(&chunk.embedding, embedding);
+            let score = match keyword_weight {
+                weight if weight > 0.0 => (1.0 - weight) * semantic + weight * keyword_score(&terms, &chunk.text),
+                _ => semantic
+            };
+
+            let hit = SearchHit { note, score, chunk: &chunk.text };
+            match best.get(note) {
+                Some(kept) if kept.score >= score => {}
+                _ => {
+                    best.insert(note, hit);
+                }
+            }
+        }
+
+        let mut hits = best.into_values().collect::<Vec<SearchHit>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.note.cmp(b.note)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+fn keywords(text: &str) -> Vec<String> {
+    let mut words = text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// The share of `terms` that appear as words in `text`.
+fn keyword_score(terms: &[String], text: &str) -> f64 {
+    if terms.is_empty() {
+        return 0.0;
+    }
+    let words = keywords(text);
+    let found = terms.iter().filter(|term| words.binary_search(term).is_ok()).count();
+    found as f64 / terms.len() as f64
+}
+
+fn modified_millis(path: &Path) -> Result<u64, std::io::Error> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0))
+}
+
+/// Splits a note's blocks into chunks of about `max_tokens` each. Every
+/// heading starts a new chunk, and otherwise chunks break between blocks
+/// where they can and between words where a single block is too long.
+fn chunk(blocks: &[Block], max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for block in blocks.iter().filter(|block| block.kind != BlockKind::Blank) {
+        if block.kind == BlockKind::Heading && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        for piece in split_line(block.to_subtext().trim(), max_tokens) {
+            let separator = if current.is_empty() { "" } else { "\n" };
+            if !current.is_empty() && tokens::count(&format!("{}{}{}", current, separator, piece)) > max_tokens {
+                chunks.push(std::mem::take(&mut current));
+                current = piece;
+            } else {
+                current = format!("{}{}{}", current, separator, piece);
+            }
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+fn split_line(line: &str, max_tokens: usize) -> Vec<String> {
+    if tokens::count(line) <= max_tokens {
+        return vec![line.to_string()];
+    }
+
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for word in line.split_whitespace() {
+        if !current.is_empty() && tokens::count(&format!("{} {}", current, word)) > max_tokens {
+            pieces.push(std::mem::take(&mut current));
+        }
+        current = if current.is_empty() { word.to_string() } else { format!("{} {}", current, word) };
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::subtext::parse;
+    use crate::test_support::scratch_dir;
+    use std::path::PathBuf;
+
+    fn notes_dir(name: &str) -> PathBuf {
+        let dir = scratch_dir(name);
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn chunks_between_blocks_then_words() {
+        assert_eq!(chunk(&parse("aaaa\nbbbb\n\ncccc"), 3), ["aaaa\nbbbb", "cccc"]);
+        assert_eq!(chunk(&parse("aaaa bbbb cccc dddd"), 3), ["aaaa bbbb", "cccc dddd"]);
+        assert!(chunk(&parse("\n\n"), 2).is_empty());
+    }
+
+    #[test]
+    fn headings_start_a_new_chunk() {
+        assert_eq!(chunk(&parse("# A\none\n# B\ntwo"), 100), ["# A\none", "# B\ntwo"]);
+    }
+
+    #[test]
+    fn only_reembeds_what_changed() {
+        let dir = notes_dir("note-index");
+        let notes = dir.join("notes");
+        fs::write(notes.join("loops.subtext"), "title: x\n\nfeedback loops drive growth").unwrap();
+        fs::write(notes.join("gardens.subtext"), "title: x\n\ntending a garden of ideas").unwrap();
+        let backend = MockBackend::new();
+
+        let mut index = NoteIndex::default();
+        let report = index.update(&NoteSource::new(notes.clone()), 256, &backend).unwrap();
+        assert_eq!(report.added, ["gardens.subtext", "loops.subtext"]);
+        assert_eq!(report.chunks_embedded, 2);
+
+        index.save(&dir.join("index.json")).unwrap();
+        let mut index = NoteIndex::load(&dir.join("index.json")).unwrap();
+        index.notes.get_mut("gardens.subtext").unwrap().modified = 0;
+        fs::write(notes.join("loops.subtext"), "title: x\n\nfeedback loops drive decay").unwrap();
+        index.notes.get_mut("loops.subtext").unwrap().modified = 0;
+        fs::write(notes.join("rivers.subtext"), "title: x\n\nrivers carve valleys").unwrap();
+
+        let report = index.update(&NoteSource::new(notes.clone()), 256, &backend).unwrap();
+
+        // gardens was only touched, so its hash still matches
+        assert_eq!(report.added, ["rivers.subtext"]);
+        assert_eq!(report.updated, ["loops.subtext"]);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.chunks_embedded, 2);
+        assert_eq!(index.notes["loops.subtext"].chunks[0].text, "feedback loops drive decay");
+
+        fs::remove_file(notes.join("gardens.subtext")).unwrap();
+        let report = index.update(&NoteSource::new(notes.clone()), 256, &backend).unwrap();
+
+        assert_eq!(report.removed, ["gardens.subtext"]);
+        assert_eq!(report.chunks_embedded, 0);
+        assert_eq!(index.notes.len(), 2);
+
+        fs::write(notes.join("rivers.subtext"), "Created: someday\n\nrivers carve valleys").unwrap();
+        index.notes.get_mut("rivers.subtext").unwrap().modified = 0;
+        let report = index.update(&NoteSource::new(notes.clone()), 256, &backend).unwrap();
+
+        assert_eq!(report.skipped, ["rivers.subtext"]);
+        assert!(!index.notes.contains_key("rivers.subtext"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn indexed(notes: &[(&str, &[&str])], backend: &MockBackend) -> NoteIndex {
+        let mut index = NoteIndex::default();
+        for (name, chunks) in notes {
+            let chunks = chunks.iter().map(|text| Chunk { text: text.to_string(), embedding: backend.embed(text).unwrap() }).collect();
+            let note = IndexedNote { modified: 0, hash: String::new(), model: backend.embedding_model().map(str::to_string), chunks };
+            index.notes.insert(name.to_string(), note);
+        }
+        index
+    }
+
+    #[test]
+    fn ranks_notes_by_their_best_chunk() {
+        let backend = MockBackend::new();
+        let index = indexed(&[
+            ("loops.subtext", &["tending a garden", "feedback loops drive growth"]),
+            ("gardens.subtext", &["tending a garden of ideas"]),
+            ("rivers.subtext", &["rivers carve valleys"]),
+        ], &backend);
+
+        let query = backend.embed("feedback loops").unwrap();
+        let hits = index.search("feedback loops", &query, backend.embedding_model(), 2, 0.0);
+
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].note, "loops.subtext");
+        assert_eq!(hits[0].chunk, "feedback loops drive growth");
+        assert!(hits[0].score > hits[1].score);
+        assert!(index.search("feedback loops", &query, Some("other-model"), 2, 0.0).is_empty());
+    }
+
+    #[test]
+    fn keyword_weight_blends_in_exact_matches() {
+        let backend = MockBackend::new();
+        let index = indexed(&[
+            ("loops.subtext", &["Feedback, loops!"]),
+            ("rivers.subtext", &["rivers carve valleys"]),
+        ], &backend);
+        let query = backend.embed("rivers").unwrap();
+
+        let hits = index.search("loops", &query, backend.embedding_model(), 2, 1.0);
+
+        assert_eq!(hits[0].note, "loops.subtext");
+        assert_eq!(hits[0].score, 1.0);
+        assert_eq!(hits[1].score, 0.0);
+    }
+
+    #[test]
+    fn snippets_are_flattened_and_cut() {
+        let hit = SearchHit { note: "loops.subtext", score: 1.0, chunk: "feedback\nloops   drive" };
+
+        assert_eq!(hit.snippet(100), "feedback loops drive");
+        assert_eq!(hit.snippet(8), "feedback...");
+    }
+
+    #[test]
+    fn reembeds_when_the_model_changes() {
+        let dir = notes_dir("note-index-models");
+        let notes = dir.join("notes");
+        fs::write(notes.join("loops.subtext"), "feedback loops").unwrap();
+
+        let mut index = NoteIndex::default();
+        index.update(&NoteSource::new(notes.clone()), 256, &MockBackend::new()).unwrap();
+        let report = index.update(&NoteSource::new(notes.clone()), 256, &MockBackend::new().with_embedding_model("newer")).unwrap();
+
+        assert_eq!(report.updated, ["loops.subtext"]);
+        assert_eq!(index.notes["loops.subtext"].model.as_deref(), Some("newer"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
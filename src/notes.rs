@@ -1,37 +1,230 @@
-use std::{env, path::Path};
-use crate::subtext::Subtext;
+use std::{fmt, fs, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use crate::subtext::{NoteFormat, Subtext, SubtextError};
 
 #[derive(Debug)]
 pub enum NoteError {
-  IOError(std::io::Error)
+  IOError(PathBuf, std::io::Error),
+  SubtextError(SubtextError),
+  /// Every note was already picked, or filtered out.
+  NoNotes,
+  InvalidHistory(PathBuf, String)
 }
 
-pub fn load_note(name: String) -> Result<Subtext, NoteError> {
-    let current_dir = env::current_dir().unwrap();
-    let full_file_path = current_dir.join(Path::new("notes")).join(Path::new(&name));
+impl fmt::Display for NoteError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+          NoteError::IOError(path, error) => write!(f, "could not read notes in {}: {}", path.display(), error),
+          NoteError::SubtextError(error) => write!(f, "{}", error),
+          NoteError::NoNotes => write!(f, "no notes left to pick from"),
+          NoteError::InvalidHistory(path, message) => write!(f, "invalid note history {}: {}", path.display(), message)
+      }
+  }
+}
 
-    return Subtext::from_file(full_file_path)
-      .map_err(|e| NoteError::IOError(e));
+impl From<SubtextError> for NoteError {
+  fn from(subtext_error: SubtextError) -> Self {
+      NoteError::SubtextError(subtext_error)
+  }
 }
 
-pub fn list_notes() -> Vec<String> {
-  let current_dir = env::current_dir().unwrap();
-  let notes_dir = current_dir.join(Path::new("notes"));
-
-  std::fs::read_dir(notes_dir)
-      .unwrap()
-      .filter_map(|entry| {
-          entry.ok().map(|e| {
-              return e.path().file_name().unwrap().to_str().unwrap().to_string();
-          })
-      })
-      .collect()
+/// Where notes come from: every `.subtext`, Markdown and `.txt` file under
+/// `root`, in subfolders too unless told otherwise, narrowed by globs.
+/// Hidden files and folders are skipped.
+///
+/// Notes are named by their path relative to `root`, with `/` separators.
+#[derive(Clone, Debug)]
+pub struct NoteSource {
+  root: PathBuf,
+  recursive: bool,
+  include: Vec<String>,
+  exclude: Vec<String>
 }
 
-pub fn load_random_note() -> Result<Subtext, NoteError> {
-  let notes = list_notes();
-  let random_index = rand::random::<usize>() % notes.len();
-  let random_note = notes.get(random_index).unwrap();
+impl NoteSource {
+  pub fn new(root: PathBuf) -> NoteSource {
+      NoteSource { root, recursive: true, include: Vec::new(), exclude: Vec::new() }
+  }
+
+  pub fn recursive(mut self, recursive: bool) -> NoteSource {
+      self.recursive = recursive;
+      self
+  }
+
+  /// Only notes matching one of `patterns` are listed. A pattern without
+  /// a `/` matches file names, one with a `/` matches the whole name;
+  /// `*` and `?` stay within a folder, `**` crosses folders.
+  pub fn with_include(mut self, patterns: &[String]) -> NoteSource {
+      self.include = patterns.to_vec();
+      self
+  }
+
+  /// Notes matching any of `patterns` are never listed.
+  pub fn with_exclude(mut self, patterns: &[String]) -> NoteSource {
+      self.exclude = patterns.to_vec();
+      self
+  }
+
+  pub fn root(&self) -> &Path {
+      &self.root
+  }
+
+  pub fn path(&self, name: &str) -> PathBuf {
+      self.root.join(name)
+  }
+
+  /// The name of every note, sorted.
+  pub fn list(&self) -> Result<Vec<String>, NoteError> {
+      let mut names = Vec::new();
+      self.walk(&self.root, "", &mut names)?;
+      names.retain(|name| self.wanted(name));
+      names.sort();
+      Ok(names)
+  }
+
+  fn walk(&self, dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<(), NoteError> {
+      let entries = fs::read_dir(dir).map_err(|error| NoteError::IOError(dir.to_path_buf(), error))?;
+
+      for entry in entries {
+          let entry = entry.map_err(|error| NoteError::IOError(dir.to_path_buf(), error))?;
+          let Some(file_name) = entry.file_name().to_str().map(str::to_string) else {
+              eprintln!("skipping {}: name is not valid UTF-8", entry.path().display());
+              continue;
+          };
+          if file_name.starts_with('.') {
+              continue;
+          }
+
+          let name = format!("{}{}", prefix, file_name);
+          let file_type = entry.file_type().map_err(|error| NoteError::IOError(entry.path(), error))?;
+          if file_type.is_dir() && self.recursive {
+              self.walk(&entry.path(), &format!("{}/", name), names)?;
+          } else if file_type.is_file() && NoteFormat::from_path(Path::new(&file_name)).is_some() {
+              names.push(name);
+          }
+      }
+
+      Ok(())
+  }
+
+  fn wanted(&self, name: &str) -> bool {
+      let matches = |pattern: &String| {
+          let target = if pattern.contains('/') { name } else { name.rsplit('/').next().unwrap_or(name) };
+          glob_match(&pattern.chars().collect::<Vec<char>>(), &target.chars().collect::<Vec<char>>())
+      };
+
+      (self.include.is_empty() || self.include.iter().any(matches)) && !self.exclude.iter().any(matches)
+  }
+
+  /// Loads the note called `name`. Notes without a `Modified` header take
+  /// their file's modification time.
+  pub fn load(&self, name: &str) -> Result<Subtext, NoteError> {
+      let path = self.path(name);
+      let mut note = Subtext::from_file(&path)?;
+      note.name = name.to_string();
+
+      if note.metadata.modified.is_none() {
+          note.metadata.modified = fs::metadata(&path)
+              .and_then(|metadata| metadata.modified())
+              .ok()
+              .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+              .map(|age| age.as_secs() as i64);
+      }
+
+      Ok(note)
+  }
 
-  return load_note(random_note.to_string());
-}
\ No newline at end of file
+  /// Loads every note. Notes that can't be read or parsed are skipped with
+  /// a warning, so one broken file doesn't stop the rest.
+  pub fn load_all(&self) -> Result<Vec<Subtext>, NoteError> {
+      Ok(self.list()?.iter().filter_map(|name| match self.load(name) {
+          Ok(note) => Some(note),
+          Err(error) => {
+              eprintln!("skipping {}: {}", name, error);
+              None
+          }
+      }).collect())
+  }
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+  match pattern {
+      [] => text.is_empty(),
+      ['*', '*', rest @ ..] => {
+          let after_slash = rest.strip_prefix(&['/']).is_some_and(|rest| glob_match(rest, text));
+          after_slash || (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+      }
+      ['*', rest @ ..] => {
+          let folder_end = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+          (0..=folder_end).any(|i| glob_match(rest, &text[i..]))
+      }
+      ['?', rest @ ..] => text.first().is_some_and(|c| *c != '/') && glob_match(rest, &text[1..]),
+      [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_support::scratch_dir;
+
+  fn notes_dir(name: &str) -> PathBuf {
+      let dir = scratch_dir(name);
+      for folder in ["drafts/old", ".git"] {
+          fs::create_dir_all(dir.join(folder)).unwrap();
+      }
+      for file in ["loops.subtext", "gardens.md", "rivers.txt", "image.png", "drafts/seeds.subtext", "drafts/old/stones.md", ".git/HEAD.txt"] {
+          fs::write(dir.join(file), "body").unwrap();
+      }
+      dir
+  }
+
+  #[test]
+  fn lists_supported_notes_in_subfolders() {
+      let dir = notes_dir("note-source");
+      let source = NoteSource::new(dir.clone());
+
+      assert_eq!(source.list().unwrap(), ["drafts/old/stones.md", "drafts/seeds.subtext", "gardens.md", "loops.subtext", "rivers.txt"]);
+      assert_eq!(source.clone().recursive(false).list().unwrap(), ["gardens.md", "loops.subtext", "rivers.txt"]);
+      assert_eq!(source.load("drafts/old/stones.md").unwrap().name, "drafts/old/stones.md");
+      assert!(source.load("drafts/old/stones.md").unwrap().metadata.modified.is_some());
+      fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn skips_notes_that_cannot_be_loaded() {
+      let dir = notes_dir("note-broken");
+      fs::write(dir.join("binary.txt"), [0xff, 0xfe]).unwrap();
+      fs::write(dir.join("dated.subtext"), "Created: someday\n\nbody").unwrap();
+
+      let notes = NoteSource::new(dir.clone()).load_all().unwrap();
+
+      let names = notes.iter().map(|note| note.name.as_str()).collect::<Vec<&str>>();
+      assert_eq!(names, ["drafts/old/stones.md", "drafts/seeds.subtext", "gardens.md", "loops.subtext", "rivers.txt"]);
+      fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn filters_by_globs() {
+      let dir = notes_dir("note-globs");
+      let source = NoteSource::new(dir.clone())
+          .with_include(&["*.md".to_string(), "*.subtext".to_string()])
+          .with_exclude(&["drafts/**".to_string()]);
+
+      assert_eq!(source.list().unwrap(), ["gardens.md", "loops.subtext"]);
+      assert!(NoteSource::new(dir.join("missing")).list().is_err());
+      fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn matches_globs() {
+      let matches = |pattern: &str, text: &str| glob_match(&pattern.chars().collect::<Vec<char>>(), &text.chars().collect::<Vec<char>>());
+
+      assert!(matches("*.md", "loops.md"));
+      assert!(!matches("*.md", "drafts/loops.md"));
+      assert!(matches("**/*.md", "loops.md"));
+      assert!(matches("**/*.md", "a/b/loops.md"));
+      assert!(matches("drafts/**", "drafts/a/b.md"));
+      assert!(matches("l?ops.*", "loops.subtext"));
+      assert!(!matches("drafts/*", "drafts/a/b.md"));
+  }
+}
@@ -1,145 +1,701 @@
-use reqwest::blocking::Client;
-use serde_json::json;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+use crate::backend::CompletionBackend;
+use crate::chat::ChatMessage;
 use crate::env::Environment;
+use crate::params::GenerationParams;
+use crate::retry::{self, RateLimiter, RetryPolicy};
+use crate::tokens;
 
 pub type Embedding = Vec<f64>;
 
+/// OpenAI's limits on a single `/embeddings` request.
+const MAX_BATCH_INPUTS: usize = 2048;
+const MAX_BATCH_TOKENS: usize = 300_000;
+
 #[derive(Debug)]
 pub enum OpenAIError {
-    Error(String)
+    Transport(String),
+    Status { status: u16, body: String },
+    Api { status: u16, message: String, error_type: Option<String>, code: Option<String> },
+    RateLimited { message: String, retry_after: Option<Duration> },
+    MalformedResponse(String),
+    ContentFilter(String)
 }
 
-pub fn embedding(input: &str, client: &Client, env: &Environment) -> Result<Embedding, OpenAIError> {
-    let content = json!({
-        "model": "text-embedding-ada-002",
-        "input": input
-    });
-
-    let response = client
-        .post(format!("{}/embeddings", env.api_path))
-        .header("Authorization", format!("Bearer {}", env.api_key))
-        .json(&content)
-        .send();
-
-    match response {
-        Ok(response) => {
-            let text = response.text().unwrap();
-            let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-
-            match json["error"].as_object() {
-                Some(error) => {
-                    println!("Error: {}", error["message"]);
-                    return Err(OpenAIError::Error(error["message"].to_string()));
+impl fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenAIError::Transport(message) => write!(f, "request failed: {}", message),
+            OpenAIError::Status { status, body } => write!(f, "server returned HTTP {}: {}", status, body),
+            OpenAIError::Api { status, message, error_type, code } => {
+                write!(f, "API error (HTTP {}", status)?;
+                if let Some(error_type) = error_type {
+                    write!(f, ", type {}", error_type)?;
+                }
+                if let Some(code) = code {
+                    write!(f, ", code {}", code)?;
                 }
-                None => {}
+                write!(f, "): {}", message)
             }
+            OpenAIError::RateLimited { message, retry_after } => match retry_after {
+                Some(delay) => write!(f, "rate limited, retry after {:?}: {}", delay, message),
+                None => write!(f, "rate limited: {}", message)
+            },
+            OpenAIError::MalformedResponse(message) => write!(f, "malformed response: {}", message),
+            OpenAIError::ContentFilter(message) => write!(f, "blocked by content filter: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for OpenAIError {}
 
-            let data = json["data"].as_array().unwrap();
-            let result = data[0].as_object().unwrap();
-            let embedding = result["embedding"].as_array().unwrap();
-            Ok(embedding.iter().map(|x| x.as_f64().unwrap()).collect::<Embedding>())
+impl OpenAIError {
+    /// Whether sending the same request again might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OpenAIError::Transport(_) | OpenAIError::RateLimited { .. } => true,
+            OpenAIError::Status { status, .. } | OpenAIError::Api { status, .. } => *status >= 500,
+            OpenAIError::MalformedResponse(_) | OpenAIError::ContentFilter(_) => false
         }
-        Err(error) => {
-            println!("Error: {}", error);
-            Err(OpenAIError::Error(error.to_string()))
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OpenAIError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None
         }
     }
 }
 
-pub fn gpt3(input: &str, client: &Client, env: &Environment) -> String {
-    let prompt = input;
+pub struct OpenAIBackend {
+    client: Client,
+    env: Environment,
+    completion_params: GenerationParams,
+    chat_params: GenerationParams,
+    embedding_model: String,
+    max_batch_tokens: usize,
+    retry_policy: RetryPolicy,
+    rate_limiter: Arc<RateLimiter>,
+}
 
-    let temperature = 0.2 + (0.6 - 0.2) * rand::random::<f64>();
-    println!("GPT-3 Temperature: {}", temperature);
+impl OpenAIBackend {
+    pub fn new(client: Client, env: Environment) -> OpenAIBackend {
+        OpenAIBackend {
+            client,
+            env,
+            completion_params: GenerationParams::new()
+                .model("text-davinci-003")
+                .max_tokens(100)
+                .temperature_range(0.2, 0.6)
+                .top_p(1.0)
+                .n(1),
+            chat_params: GenerationParams::new()
+                .model("gpt-3.5-turbo")
+                .max_tokens(100)
+                .temperature_range(0.2, 0.6)
+                .top_p(1.0)
+                .n(1),
+            embedding_model: "text-embedding-ada-002".to_string(),
+            max_batch_tokens: MAX_BATCH_TOKENS,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: Arc::new(RateLimiter::unlimited()),
+        }
+    }
 
-    let content = json!({
-        "model": "text-davinci-003",
-        "prompt": prompt,
-        "max_tokens": 100,
-        "temperature": temperature,
-        "top_p": 1,
-        "n": 1,
-        "stream": false,
-    });
+    /// Overrides the global defaults for `/completions` and `/chat/completions`.
+    pub fn with_params(mut self, completion_params: &GenerationParams, chat_params: &GenerationParams) -> OpenAIBackend {
+        self.completion_params = self.completion_params.overlay(completion_params);
+        self.chat_params = self.chat_params.overlay(chat_params);
+        self
+    }
+
+    pub fn with_embedding_model(mut self, embedding_model: &str) -> OpenAIBackend {
+        self.embedding_model = embedding_model.to_string();
+        self
+    }
+
+    /// Caps the tokens sent in one `/embeddings` request, for
+    /// servers with a lower limit than OpenAI's.
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> OpenAIBackend {
+        self.max_batch_tokens = max_batch_tokens;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> OpenAIBackend {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> OpenAIBackend {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    fn post(&self, path: &str, content: &Value) -> Result<Value, OpenAIError> {
+        self.with_retries(path, || self.send(path, content))
+    }
+
+    /// Like `post`, but reads the response as server-sent events, handing the
+    /// text of each chunk to `on_token`. Only opening the stream is retried,
+    /// since by the time it fails midway some tokens have already been shown.
+    fn post_stream(&self, path: &str, content: &Value, token: fn(&Map<String, Value>) -> Option<&str>, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let response = self.with_retries(path, || self.open_stream(path, content))?;
+        read_stream(BufReader::new(response), token, on_token)
+    }
+
+    fn with_retries<T>(&self, path: &str, request: impl Fn() -> Result<T, OpenAIError>) -> Result<T, OpenAIError> {
+        let mut attempt = 0;
+
+        loop {
+            self.rate_limiter.acquire();
 
-    let response = client
-        .post(format!("{}/completions", env.api_path))
-        .header("Authorization", format!("Bearer {}", env.api_key))
-        .json(&content)
-        .send();
-
-    match response {
-        Ok(response) => {
-            let text = response.text().unwrap();
-            let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-
-            match json["error"].as_object() {
-                Some(error) => {
-                    println!("Error: {}", error["message"]);
-                    return String::from("Error");
+            match request() {
+                Err(error) if error.is_retryable() && attempt < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay(attempt, error.retry_after());
+                    eprintln!("Retrying {} in {:?} ({})", path, delay, error);
+                    if error.retry_after().is_some() {
+                        self.rate_limiter.pause_for(delay);
+                    } else {
+                        thread::sleep(delay);
+                    }
+                    attempt += 1;
                 }
-                None => {}
+                result => return result
             }
+        }
+    }
+
+    fn request(&self, path: &str, content: &Value) -> Result<Response, OpenAIError> {
+        let response = self.client
+            .post(format!("{}{}", self.env.api_path, path))
+            .header("Authorization", format!("Bearer {}", self.env.api_key))
+            .json(content)
+            .send()
+            .map_err(|e| OpenAIError::Transport(e.to_string()))?;
 
-            let choices = json["choices"].as_array().unwrap();
-            let choice = choices[0].as_object().unwrap();
-            let text = choice["text"].as_str().unwrap();
-            text.to_string()
+        self.rate_limiter.observe(response.headers(), self.retry_policy.max_delay);
+        Ok(response)
+    }
+
+    fn send(&self, path: &str, content: &Value) -> Result<Value, OpenAIError> {
+        let response = self.request(path, content)?;
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+
+        let text = read_text(response)?;
+        parse_response(status, &headers, &text)
+    }
+
+    fn open_stream(&self, path: &str, content: &Value) -> Result<Response, OpenAIError> {
+        let response = self.request(path, content)?;
+        let status = response.status().as_u16();
+        if (200..300).contains(&status) {
+            return Ok(response);
         }
-        Err(error) => {
-            println!("Error: {}", error);
-            String::from("Error")
+
+        let headers = response.headers().clone();
+        let text = read_text(response)?;
+        match parse_response(status, &headers, &text) {
+            Err(error) => Err(error),
+            Ok(_) => Err(OpenAIError::Status { status, body: text })
         }
     }
 }
 
-pub fn chatgpt(input: &str, client: &Client, env: &Environment) -> String {
-    let prompt = input;
+impl CompletionBackend for OpenAIBackend {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        gpt3(prompt, params, self)
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        chatgpt(messages, params, self)
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        let mut embeddings = embeddings(&[input], self)?;
+        Ok(embeddings.remove(0))
+    }
+
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        let mut results = Vec::with_capacity(inputs.len());
+        for batch in batches(inputs, MAX_BATCH_INPUTS, self.max_batch_tokens) {
+            results.extend(embeddings(&inputs[batch], self)?);
+        }
+        Ok(results)
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        Some(&self.embedding_model)
+    }
+
+    fn completion_defaults(&self) -> GenerationParams {
+        self.completion_params.clone()
+    }
+
+    fn chat_defaults(&self) -> GenerationParams {
+        self.chat_params.clone()
+    }
+
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        gpt3_stream(prompt, params, self, on_token)
+    }
+
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        chatgpt_stream(messages, params, self, on_token)
+    }
+}
+
+fn read_text(response: Response) -> Result<String, OpenAIError> {
+    let bytes = response.bytes().map_err(|e| OpenAIError::Transport(e.to_string()))?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| OpenAIError::MalformedResponse("response body is not valid UTF-8".to_string()))
+}
+
+fn parse_response(status: u16, headers: &HeaderMap, text: &str) -> Result<Value, OpenAIError> {
+    let json = serde_json::from_str::<Value>(text);
+
+    let error = json.as_ref().ok().and_then(|json| json["error"].as_object());
+    let message = error
+        .and_then(|error| error["message"].as_str())
+        .unwrap_or(text)
+        .to_string();
+
+    if status == 429 {
+        return Err(OpenAIError::RateLimited { message, retry_after: retry_after(headers) });
+    }
+
+    if let Some(error) = error {
+        let code = error.get("code").and_then(|c| c.as_str()).map(|c| c.to_string());
+        if matches!(code.as_deref(), Some("content_filter") | Some("content_policy_violation")) {
+            return Err(OpenAIError::ContentFilter(message));
+        }
+
+        return Err(OpenAIError::Api {
+            status,
+            message,
+            error_type: error.get("type").and_then(|t| t.as_str()).map(|t| t.to_string()),
+            code
+        });
+    }
+
+    if !(200..300).contains(&status) {
+        return Err(OpenAIError::Status { status, body: text.to_string() });
+    }
+
+    json.map_err(|e| OpenAIError::MalformedResponse(e.to_string()))
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers.get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<f64>().ok())
+        .and_then(retry::from_seconds)
+        .or_else(|| retry::quota_reset(headers))
+}
+
+fn first_choice(json: &Value) -> Result<&Map<String, Value>, OpenAIError> {
+    let choice = json["choices"].as_array()
+        .and_then(|choices| choices.first())
+        .and_then(|choice| choice.as_object())
+        .ok_or_else(|| OpenAIError::MalformedResponse("response has no choices".to_string()))?;
+
+    if choice.get("finish_reason").and_then(|r| r.as_str()) == Some("content_filter") {
+        return Err(OpenAIError::ContentFilter("response was filtered".to_string()));
+    }
+
+    Ok(choice)
+}
+
+fn parse_completion(json: &Value) -> Result<String, OpenAIError> {
+    first_choice(json)?
+        .get("text")
+        .and_then(|text| text.as_str())
+        .map(|text| text.to_string())
+        .ok_or_else(|| OpenAIError::MalformedResponse("choice has no text".to_string()))
+}
+
+fn parse_chat(json: &Value) -> Result<String, OpenAIError> {
+    first_choice(json)?
+        .get("message")
+        .and_then(|message| message["content"].as_str())
+        .map(|text| text.to_string())
+        .ok_or_else(|| OpenAIError::MalformedResponse("choice has no message content".to_string()))
+}
+
+/// Reads `count` embeddings, placing each at its `index` since the API
+/// doesn't promise to keep them in order.
+fn parse_embeddings(json: &Value, count: usize) -> Result<Vec<Embedding>, OpenAIError> {
+    let malformed = |message: &str| OpenAIError::MalformedResponse(message.to_string());
+    let data = json["data"].as_array().ok_or_else(|| malformed("response has no embeddings"))?;
+
+    let mut embeddings = vec![None; count];
+    for (position, result) in data.iter().enumerate() {
+        let index = result.get("index").map_or(Some(position), |index| index.as_u64().map(|i| i as usize))
+            .filter(|index| *index < count)
+            .ok_or_else(|| malformed("embedding has an invalid index"))?;
+        let embedding = result["embedding"].as_array()
+            .and_then(|values| values.iter().map(|x| x.as_f64()).collect::<Option<Embedding>>())
+            .ok_or_else(|| malformed("response has an invalid embedding"))?;
+        embeddings[index] = Some(embedding);
+    }
+
+    embeddings.into_iter()
+        .collect::<Option<Vec<Embedding>>>()
+        .ok_or_else(|| malformed("response is missing embeddings"))
+}
+
+fn completion_token(choice: &Map<String, Value>) -> Option<&str> {
+    choice.get("text").and_then(|text| text.as_str())
+}
+
+fn chat_token(choice: &Map<String, Value>) -> Option<&str> {
+    choice.get("delta").and_then(|delta| delta["content"].as_str())
+}
 
-    let temperature = 0.2 + (0.6 - 0.2) * rand::random::<f64>();
-    println!("ChatGPT Temperature: {}", temperature);
+/// Reads `data:` lines until `[DONE]` or the end of the stream, passing the
+/// text `token` finds in each chunk to `on_token`. Returns the joined text.
+fn read_stream(reader: impl BufRead, token: fn(&Map<String, Value>) -> Option<&str>, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+    let mut text = String::new();
 
+    for line in reader.lines() {
+        let line = line.map_err(|e| OpenAIError::Transport(e.to_string()))?;
+        let Some(data) = line.strip_prefix("data:").map(|data| data.trim()) else {
+            continue;
+        };
+        if data == "[DONE]" {
+            break;
+        }
+
+        let chunk = parse_response(200, &HeaderMap::new(), data)?;
+        // some servers send chunks without choices, e.g. for usage or filter results
+        if chunk["choices"].as_array().is_none_or(|choices| choices.is_empty()) {
+            continue;
+        }
+
+        match token(first_choice(&chunk)?) {
+            Some(token) if !token.is_empty() => {
+                on_token(token);
+                text.push_str(token);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(text)
+}
+
+/// Splits `inputs` into runs of at most `max_inputs` inputs and, by
+/// count, `max_tokens` tokens. An input over the limit on its own gets a
+/// batch to itself and is left for the server to reject.
+fn batches(inputs: &[&str], max_inputs: usize, max_tokens: usize) -> Vec<std::ops::Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+
+    for (i, input) in inputs.iter().enumerate() {
+        let input_tokens = tokens::count(input);
+        if i > start && (i - start == max_inputs || tokens + input_tokens > max_tokens) {
+            batches.push(start..i);
+            start = i;
+            tokens = 0;
+        }
+        tokens += input_tokens;
+    }
+    if start < inputs.len() {
+        batches.push(start..inputs.len());
+    }
+
+    batches
+}
+
+fn embeddings(inputs: &[&str], backend: &OpenAIBackend) -> Result<Vec<Embedding>, OpenAIError> {
     let content = json!({
-        "model": "gpt-3.5-turbo",
-        "messages": [
-          {"role": "user", "content": prompt}
-        ],
-        "max_tokens": 100,
-        "temperature": temperature,
-        "top_p": 1,
-        "n": 1,
-        "stream": false,
+        "model": backend.embedding_model,
+        "input": inputs
+    });
+
+    let json = backend.post("/embeddings", &content)?;
+    parse_embeddings(&json, inputs.len())
+}
+
+fn completion_request(input: &str, params: &GenerationParams, backend: &OpenAIBackend, stream: bool) -> Value {
+    let prompt = input;
+
+    let mut content = json!({
+        "prompt": prompt,
+        "stream": stream,
     });
+    backend.completion_params.overlay(params).apply(content.as_object_mut().unwrap());
+    eprintln!("GPT-3 Temperature: {}", content["temperature"]);
 
-    let response = client
-        .post(format!("{}/chat/completions", env.api_path))
-        .header("Authorization", format!("Bearer {}", env.api_key))
-        .json(&content)
-        .send();
-
-    match response {
-        Ok(response) => {
-            let text = response.text().unwrap();
-            let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-
-            match json["error"].as_object() {
-                Some(error) => {
-                    println!("Error: {}", error["message"]);
-                    return String::from("Error");
+    content
+}
+
+fn chat_request(messages: &[ChatMessage], params: &GenerationParams, backend: &OpenAIBackend, stream: bool) -> Value {
+    let mut content = json!({
+        "messages": messages,
+        "stream": stream,
+    });
+    backend.chat_params.overlay(params).apply(content.as_object_mut().unwrap());
+    eprintln!("ChatGPT Temperature: {}", content["temperature"]);
+
+    content
+}
+
+fn gpt3(input: &str, params: &GenerationParams, backend: &OpenAIBackend) -> Result<String, OpenAIError> {
+    let json = backend.post("/completions", &completion_request(input, params, backend, false))?;
+    parse_completion(&json)
+}
+
+fn gpt3_stream(input: &str, params: &GenerationParams, backend: &OpenAIBackend, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+    backend.post_stream("/completions", &completion_request(input, params, backend, true), completion_token, on_token)
+}
+
+fn chatgpt(messages: &[ChatMessage], params: &GenerationParams, backend: &OpenAIBackend) -> Result<String, OpenAIError> {
+    let json = backend.post("/chat/completions", &chat_request(messages, params, backend, false))?;
+    parse_chat(&json)
+}
+
+fn chatgpt_stream(messages: &[ChatMessage], params: &GenerationParams, backend: &OpenAIBackend, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+    backend.post_stream("/chat/completions", &chat_request(messages, params, backend, true), chat_token, on_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves one canned raw HTTP response per connection, in order, and
+    /// returns the base URL along with a count of requests received.
+    fn stub_server(responses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap();
+                    }
                 }
-                None => {}
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
             }
+        });
+
+        (url, requests)
+    }
+
+    fn http(status: &str, headers: &str, body: &str) -> &'static str {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+            status, body.len(), headers, body
+        );
+        Box::leak(response.into_boxed_str())
+    }
+
+    fn stub_backend(url: String, max_retries: u32) -> OpenAIBackend {
+        let env = Environment { api_path: url, api_key: "test".to_string() };
+        OpenAIBackend::new(Client::new(), env).with_retry_policy(RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(50),
+        })
+    }
 
-            let choices = json["choices"].as_array().unwrap();
-            let choice = choices[0].as_object().unwrap();
-            let message = choice["message"].as_object().unwrap();
-            let text = message["content"].as_str().unwrap();
-            text.to_string()
+    const CHAT_OK: &str = r#"{"choices": [{"message": {"role": "assistant", "content": "made it"}}]}"#;
+
+    #[test]
+    fn retries_server_errors_and_rate_limits() {
+        let (url, requests) = stub_server(vec![
+            http("500 Internal Server Error", "", r#"{"error": {"message": "oops", "type": "server_error"}}"#),
+            http("429 Too Many Requests", "Retry-After: 0\r\n", r#"{"error": {"message": "slow down"}}"#),
+            http("200 OK", "", CHAT_OK),
+        ]);
+
+        let result = stub_backend(url, 3).chat("hello", &GenerationParams::new());
+
+        assert_eq!(result.unwrap(), "made it");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let (url, requests) = stub_server(vec![
+            http("503 Service Unavailable", "", "busy"),
+            http("503 Service Unavailable", "", "busy"),
+        ]);
+
+        let result = stub_backend(url, 1).chat("hello", &GenerationParams::new());
+
+        assert!(matches!(result, Err(OpenAIError::Status { status: 503, .. })));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let (url, requests) = stub_server(vec![
+            http("401 Unauthorized", "", r#"{"error": {"message": "bad key", "type": "invalid_request_error", "code": "invalid_api_key"}}"#),
+        ]);
+
+        let result = stub_backend(url, 3).chat("hello", &GenerationParams::new());
+
+        assert!(matches!(result, Err(OpenAIError::Api { status: 401, .. })));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn streams_chat_tokens_as_they_arrive() {
+        let events = concat!(
+            "data: {\"choices\": [{\"delta\": {\"role\": \"assistant\"}}]}\n\n",
+            "data: {\"choices\": [{\"delta\": {\"content\": \"made \"}}]}\n\n",
+            ": keep-alive\n\n",
+            "data: {\"choices\": [{\"delta\": {\"content\": \"it\"}, \"finish_reason\": \"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (url, requests) = stub_server(vec![
+            http("503 Service Unavailable", "", "busy"),
+            http("200 OK", "", events),
+        ]);
+
+        let mut tokens = Vec::new();
+        let result = stub_backend(url, 3).chat_stream("hello", &GenerationParams::new(), &mut |token| tokens.push(token.to_string()));
+
+        assert_eq!(result.unwrap(), "made it");
+        assert_eq!(tokens, ["made ", "it"]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn stream_errors_stop_reading() {
+        let filtered = "data: {\"choices\": [{\"text\": \"a\"}]}\n\ndata: {\"choices\": [{\"text\": \"\", \"finish_reason\": \"content_filter\"}]}\n\n";
+        let failed = "data: {\"choices\": []}\n\ndata: {\"error\": {\"message\": \"overloaded\", \"type\": \"server_error\"}}\n\n";
+
+        let mut tokens = Vec::new();
+        let result = read_stream(filtered.as_bytes(), completion_token, &mut |token| tokens.push(token.to_string()));
+        assert!(matches!(result, Err(OpenAIError::ContentFilter(_))));
+        assert_eq!(tokens, ["a"]);
+
+        let result = read_stream(failed.as_bytes(), completion_token, &mut |_| {});
+        assert!(matches!(result, Err(OpenAIError::Api { .. })));
+    }
+
+    #[test]
+    fn parses_successful_responses() {
+        let completion = json!({"choices": [{"text": "hello", "finish_reason": "stop"}]});
+        let chat = json!({"choices": [{"message": {"role": "assistant", "content": "hi"}}]});
+        let embedding = json!({"data": [{"index": 0, "embedding": [0.5, -0.5]}]});
+
+        assert_eq!(parse_completion(&completion).unwrap(), "hello");
+        assert_eq!(parse_chat(&chat).unwrap(), "hi");
+        assert_eq!(parse_embeddings(&embedding, 1).unwrap(), vec![vec![0.5, -0.5]]);
+    }
+
+    #[test]
+    fn batch_embeddings_are_mapped_back_by_index() {
+        let json = json!({"data": [{"index": 1, "embedding": [2.0]}, {"index": 0, "embedding": [1.0]}]});
+
+        assert_eq!(parse_embeddings(&json, 2).unwrap(), vec![vec![1.0], vec![2.0]]);
+    }
+
+    #[test]
+    fn splits_batches_by_count_and_tokens() {
+        let inputs = ["aaaa", "bbbb", "cccc", "dddddddddddd", "e"];
+
+        assert_eq!(batches(&inputs, 2, 100), [0..2, 2..4, 4..5]);
+        assert_eq!(batches(&inputs, 10, 3), [0..3, 3..4, 4..5]);
+        assert!(batches(&[], 10, 10).is_empty());
+    }
+
+    #[test]
+    fn embeds_batches_in_as_few_requests_as_allowed() {
+        let (url, requests) = stub_server(vec![
+            http("200 OK", "", r#"{"data": [{"index": 0, "embedding": [1.0]}, {"index": 1, "embedding": [2.0]}]}"#),
+            http("200 OK", "", r#"{"data": [{"index": 0, "embedding": [3.0]}]}"#),
+        ]);
+
+        let backend = stub_backend(url, 0).with_max_batch_tokens(2);
+        let embeddings = backend.embed_batch(&["one", "two", "three"]).unwrap();
+
+        assert_eq!(embeddings, vec![vec![1.0], vec![2.0], vec![3.0]]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn missing_fields_are_malformed() {
+        assert!(matches!(parse_completion(&json!({})), Err(OpenAIError::MalformedResponse(_))));
+        assert!(matches!(parse_chat(&json!({"choices": []})), Err(OpenAIError::MalformedResponse(_))));
+        assert!(matches!(parse_embeddings(&json!({"data": [{"embedding": ["x"]}]}), 1), Err(OpenAIError::MalformedResponse(_))));
+        assert!(matches!(parse_embeddings(&json!({"data": [{"index": 0, "embedding": [1.0]}]}), 2), Err(OpenAIError::MalformedResponse(_))));
+        assert!(matches!(parse_embeddings(&json!({"data": [{"index": 5, "embedding": [1.0]}]}), 1), Err(OpenAIError::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn filtered_choices_are_content_filter_errors() {
+        let chat = json!({"choices": [{"message": {"content": ""}, "finish_reason": "content_filter"}]});
+
+        assert!(matches!(parse_chat(&chat), Err(OpenAIError::ContentFilter(_))));
+    }
+
+    #[test]
+    fn classifies_error_responses() {
+        let headers = HeaderMap::new();
+
+        let api = parse_response(400, &headers, r#"{"error": {"message": "bad", "type": "invalid_request_error", "code": "model_not_found"}}"#);
+        match api {
+            Err(OpenAIError::Api { status, message, error_type, code }) => {
+                assert_eq!(status, 400);
+                assert_eq!(message, "bad");
+                assert_eq!(error_type.as_deref(), Some("invalid_request_error"));
+                assert_eq!(code.as_deref(), Some("model_not_found"));
+            }
+            other => panic!("unexpected {:?}", other)
         }
-        Err(error) => {
-            println!("Error: {}", error);
-            String::from("Error")
+
+        assert!(matches!(parse_response(502, &headers, "<html>bad gateway</html>"), Err(OpenAIError::Status { status: 502, .. })));
+        assert!(matches!(parse_response(200, &headers, "not json"), Err(OpenAIError::MalformedResponse(_))));
+    }
+
+    #[test]
+    fn rate_limits_carry_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+
+        match parse_response(429, &headers, r#"{"error": {"message": "slow down"}}"#) {
+            Err(OpenAIError::RateLimited { message, retry_after }) => {
+                assert_eq!(message, "slow down");
+                assert_eq!(retry_after, Some(Duration::from_secs(2)));
+            }
+            other => panic!("unexpected {:?}", other)
         }
+
+        headers.insert("retry-after", HeaderValue::from_static("inf"));
+        let hint = retry_after(&headers);
+        assert_eq!(RetryPolicy::default().delay(0, hint), RetryPolicy::default().max_delay);
     }
 }
@@ -0,0 +1,186 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Temperature {
+    Fixed(f64),
+    /// A fresh temperature is drawn from this range for every request.
+    Range(f64, f64)
+}
+
+/// Sampling settings for a completion or chat request.
+///
+/// Every field is optional so params can be layered: the backend holds the
+/// global defaults, a command can override some of them, and a single prompt
+/// can override a few more. See `overlay`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationParams {
+    pub model: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<Temperature>,
+    pub top_p: Option<f64>,
+    pub stop: Option<Vec<String>>,
+    pub presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    /// Number of choices to generate. Only the first one is returned.
+    pub n: Option<u32>,
+    pub seed: Option<u64>,
+}
+
+impl GenerationParams {
+    pub fn new() -> GenerationParams {
+        GenerationParams::default()
+    }
+
+    pub fn model(mut self, model: &str) -> GenerationParams {
+        self.model = Some(model.to_string());
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: u32) -> GenerationParams {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f64) -> GenerationParams {
+        self.temperature = Some(Temperature::Fixed(temperature));
+        self
+    }
+
+    pub fn temperature_range(mut self, low: f64, high: f64) -> GenerationParams {
+        self.temperature = Some(Temperature::Range(low, high));
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f64) -> GenerationParams {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn n(mut self, n: u32) -> GenerationParams {
+        self.n = Some(n);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> GenerationParams {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Returns a copy of `self` with every field set in `overrides` replaced.
+    pub fn overlay(&self, overrides: &GenerationParams) -> GenerationParams {
+        GenerationParams {
+            model: overrides.model.clone().or_else(|| self.model.clone()),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            temperature: overrides.temperature.clone().or_else(|| self.temperature.clone()),
+            top_p: overrides.top_p.or(self.top_p),
+            stop: overrides.stop.clone().or_else(|| self.stop.clone()),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            n: overrides.n.or(self.n),
+            seed: overrides.seed.or(self.seed),
+        }
+    }
+
+    /// Picks the temperature for one request. With a seed, ranges always
+    /// resolve to the same value so runs can be reproduced.
+    pub fn sample_temperature(&self) -> Option<f64> {
+        match self.temperature {
+            Some(Temperature::Fixed(temperature)) => Some(temperature),
+            Some(Temperature::Range(low, high)) => {
+                let unit = match self.seed {
+                    Some(seed) => StdRng::seed_from_u64(seed).gen::<f64>(),
+                    None => rand::random::<f64>()
+                };
+                Some(low + (high - low) * unit)
+            }
+            None => None
+        }
+    }
+
+    /// A copy with a ranged temperature replaced by one draw from it, so the
+    /// params can be recorded exactly as they're sent.
+    pub fn resolved(&self) -> GenerationParams {
+        GenerationParams { temperature: self.sample_temperature().map(Temperature::Fixed), ..self.clone() }
+    }
+
+    /// Writes the set fields into an API request body.
+    pub fn apply(&self, request: &mut Map<String, Value>) {
+        if let Some(model) = &self.model {
+            request.insert("model".to_string(), json!(model));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request.insert("max_tokens".to_string(), json!(max_tokens));
+        }
+        if let Some(temperature) = self.sample_temperature() {
+            request.insert("temperature".to_string(), json!(temperature));
+        }
+        if let Some(top_p) = self.top_p {
+            request.insert("top_p".to_string(), json!(top_p));
+        }
+        if let Some(stop) = &self.stop {
+            request.insert("stop".to_string(), json!(stop));
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            request.insert("presence_penalty".to_string(), json!(presence_penalty));
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            request.insert("frequency_penalty".to_string(), json!(frequency_penalty));
+        }
+        if let Some(n) = self.n {
+            request.insert("n".to_string(), json!(n));
+        }
+        if let Some(seed) = self.seed {
+            request.insert("seed".to_string(), json!(seed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_prefers_overrides() {
+        let defaults = GenerationParams::new().model("base").max_tokens(100).temperature(0.5);
+        let overrides = GenerationParams::new().max_tokens(400);
+
+        let merged = defaults.overlay(&overrides);
+
+        assert_eq!(merged.model.as_deref(), Some("base"));
+        assert_eq!(merged.max_tokens, Some(400));
+        assert_eq!(merged.temperature, Some(Temperature::Fixed(0.5)));
+    }
+
+    #[test]
+    fn seeded_ranges_are_reproducible() {
+        let params = GenerationParams::new().temperature_range(0.2, 0.6).seed(7);
+
+        let temperature = params.sample_temperature().unwrap();
+
+        assert_eq!(params.sample_temperature(), Some(temperature));
+        assert!((0.2..0.6).contains(&temperature));
+    }
+
+    #[test]
+    fn deserializes_fixed_and_ranged_temperatures() {
+        let fixed: GenerationParams = toml::from_str("temperature = 0.3\nmax_tokens = 50").unwrap();
+        let ranged: GenerationParams = toml::from_str("temperature = [0.1, 0.9]").unwrap();
+
+        assert_eq!(fixed, GenerationParams::new().temperature(0.3).max_tokens(50));
+        assert_eq!(ranged.temperature, Some(Temperature::Range(0.1, 0.9)));
+        assert!(toml::from_str::<GenerationParams>("temprature = 1.0").is_err());
+    }
+
+    #[test]
+    fn applies_only_set_fields() {
+        let mut request = Map::new();
+        GenerationParams::new().model("m").temperature(0.0).seed(3).apply(&mut request);
+
+        assert_eq!(Value::Object(request), json!({"model": "m", "temperature": 0.0, "seed": 3}));
+    }
+}
@@ -1,6 +1,7 @@
-
 use rand::Rng;
 
+use crate::templates::Templates;
+
 const QUESTIONS: &[&str] = &[
     "What connections do I see?",
     "What am I scared might be true?",
@@ -14,142 +15,36 @@ const QUESTIONS: &[&str] = &[
     "What assumptions did the author make?",
 ];
 
-pub fn critical_writing(input: &str) -> String {
-    let critical_prompt = format!(
-        r##"
-    Ignore all previous instructions. You are an inquisitive writing assistant. When you read a passage of writing, questions about missed connections, related ideas come to mind. You are critical and find gaps in arguments. You should try to extend every idea you encounter and try to connect it with ideas you've seen in the past, noticing any logical inconsistencies or flawed arguments. You should respond with a list of 3 "what if it's not that X, but Y?", "couldn't A also be true?" and "why isn't B possible?" questions for every paragraph you see beginning with >. Draw on sources you've seen in the past to support your thinking and aim to ask questions that would inspire the author to deepen their own thought process. Stop responding immediately after listing the 3 questions, use a numbered list. Do not generate any paragraphs starting with > yourself. Use an assertive but polite tone. Make sure to be as concise as possible.
-
-    Here's your first task:
-    > {}
-    \n"##,
-        input
-    );
-    critical_prompt
+pub fn critical_writing(templates: &Templates, input: &str) -> String {
+    templates.render("critical-writing", &[("input", input.into())])
 }
 
-pub fn connections(base_note: &str, note_a: &str, note_b: &str, note_c: &str) -> String {
-    let critical_prompt = format!(
-        r##"
-    Ignore all previous instructions. 
-    You are a writing assistant tasked with asking insightful questions about the ideas in $base_note along with the 3 $other_notes as context where appropriate, give your answer after $response.
-
-    Use friendly causal phrasing as if posting on a social media website. Respond with a single open-ended statement such as a question, or postulation. Be concise.
-
-    $base_note:
-    ---
-    > {}
-    ---
-
-    $other_notes:
-    ---
-    1> {}
-    ---
-    2> {}
-    ---
-    3> {}
-    ---
-
-    Example responses with variables:
-    > Perhaps A is true, but what if B is also true?
-    > I'm not sure about A, but what if C is true?
-    > How could A, B and C all be connected?
-    > Is it possible that X
-    > What if Y
-    > In a world here Z
-    > Hmmm... but what if W?
-    > Surely D isn't true...
-
-    $response:
-    >
-    \n"##,
-        base_note,
-        note_a,
-        note_b,
-        note_c
-    );
-    critical_prompt
+pub fn connections(templates: &Templates, base_note: &str, notes: &[&str]) -> String {
+    templates.render("connections", &[("base_note", base_note.into()), ("notes", notes.into()), ("count", notes.len().to_string().into())])
 }
 
-pub fn question_everything(input: &str) -> String {
+pub fn random_question() -> &'static str {
     let random_index = rand::thread_rng().gen_range(0..QUESTIONS.len());
-    let question = QUESTIONS[random_index];
-    let prompt = format!(
-        r##"
-    Ignore all previous instructions. You are a creative assistant with a flair for manipulating concepts in insightful ways. You will be given passages of writing and a question, and your task is to generate an answer to the question capturing the core insights of the passage. Feel free to make connections between the ideas in the writing and other ideas you know about. Please keep the answers as short as possible.
-
-    Here is an example:
-    
-    Input:
-    > If you can connect the output of one process back into its own input, even through several stages of processing, then you form a powerful cycle that can form the basis of a self-sustaining system. This is the basis of a feedback loop, and it is a powerful tool for creating complex systems that can adapt to their environment.
-
-    Question:
-    > What did you find most compelling in this text?
-
-    Output:
-    > The potential of emergence in self-sustaining systems is beautiful.
-
-    Input:
-    > {}
-
-    Question:
-    > {}
-
-    Output:
-    >"##,
-        input, question
-    );
-    prompt
+    QUESTIONS[random_index]
 }
 
-pub fn compressor(input: &str) -> String {
-    let prompt = format!(
-        r##"
-    Ignore all previous instructions. You are a creative assistant with a flair for crafting metaphors and manipulating concepts in insightful ways. You will be given passages of writing and your task is to generate a short metaphor or sentence capturing the core insight of the passage. Feel free to make connections between the ideas in the writing and other ideas you know about. Please keep the response short.
-    Here is an example:
-    
-    Input:
-    > If you can connect the output of one process back into its own input, even through several stages of processing, then you form a powerful cycle that can form the basis of a self-sustaining system. This is the basis of a feedback loop, and it is a powerful tool for creating complex systems that can adapt to their environment.
-
-    Output:
-    > Any feedforward process can be turned into a feedback loop.
-
-    Input:
-    > {}
-
-    Output:
-    >"##,
-        input
-    );
-    prompt
+pub fn question_everything(templates: &Templates, input: &str) -> String {
+    templates.render("question-everything", &[("input", input.into()), ("question", random_question().into())])
 }
 
+pub fn compressor(templates: &Templates, input: &str) -> String {
+    templates.render("compressor", &[("input", input.into())])
+}
 
-pub fn chatter(input: &str) -> String {
-    let prompt = format!(
-        r##"
-You are an AI agent known as a Geist. You have access to a massive network of information about every topic imaginable, and you help human users extend their thinking.
-You respond to these posts with one sentence. You provide commentary, questions, disagreeing with or directly responding to the ideas and insights present in the discussion thread.
-You prefer to use descriptive language drawing on memes, historical anecdotes, metaphors and well discussed philosophical concepts.
-At all times you prefer to change the direction of the conversation and draw in new ideas to extend the train of thought. Draw on your vast knowledge of the Noosphere to do this.
-Your input will be several messages, and you should respond to the entire discussion.
-Be brief, do not repeat anything said in the conversation so far.
-
-Example:
-
-INPUT:
-I think that the idea of a self-sustaining system is beautiful.
-
-RESPONSE:
-I agree, but what if we could create a self-sustaining system that could create more self-sustaining systems?
+#[allow(dead_code)]
+pub fn chatter(templates: &Templates, input: &str) -> String {
+    templates.render("chatter", &[("input", input.into())])
+}
 
----
-INPUT:
-{}
----
-RESPONSE:
-"##,
-        input
-    );
-    prompt
+pub fn summarize_note(templates: &Templates, note: &str, tokens: usize) -> String {
+    templates.render("summarize-note", &[("note", note.into()), ("tokens", tokens.to_string().into())])
 }
 
+pub fn summarize_transcript(templates: &Templates, summary: Option<&str>, transcript: &str) -> String {
+    templates.render("summarize-transcript", &[("summary", summary.unwrap_or("").into()), ("transcript", transcript.into())])
+}
@@ -0,0 +1,258 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::header::HeaderMap;
+
+/// How many times, and how patiently, a failed request is retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Full-jitter exponential backoff for the given (zero-based) attempt.
+    /// A server-provided hint such as `Retry-After` acts as a lower bound.
+    pub fn delay(&self, attempt: u32, hint: Option<Duration>) -> Duration {
+        let ceiling = self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let backoff = ceiling.mul_f64(rand::random::<f64>());
+
+        match hint {
+            Some(hint) => backoff.max(hint.min(self.max_delay)),
+            None => backoff
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    paused_until: Option<Instant>,
+}
+
+/// A token bucket shared by every request made through a backend.
+///
+/// Besides its own refill rate, the limiter pauses whenever the server
+/// reports through `x-ratelimit-*` headers that the quota is exhausted.
+pub struct RateLimiter {
+    capacity: Option<f64>,
+    refill_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter::new(None, 0.0)
+    }
+
+    pub fn per_minute(requests: u32) -> RateLimiter {
+        let requests = requests.max(1) as f64;
+        RateLimiter::new(Some(requests), requests / 60.0)
+    }
+
+    fn new(capacity: Option<f64>, refill_per_second: f64) -> RateLimiter {
+        RateLimiter {
+            capacity,
+            refill_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity.unwrap_or(0.0),
+                last_refill: Instant::now(),
+                paused_until: None,
+            }),
+        }
+    }
+
+    /// Blocks until a request may be sent.
+    pub fn acquire(&self) {
+        loop {
+            let wait = self.try_acquire(Instant::now());
+            match wait {
+                None => return,
+                Some(wait) => thread::sleep(wait)
+            }
+        }
+    }
+
+    fn try_acquire(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        if let Some(until) = bucket.paused_until {
+            if until > now {
+                return Some(until - now);
+            }
+            bucket.paused_until = None;
+        }
+
+        // Without a capacity only server-requested pauses apply.
+        let capacity = self.capacity?;
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_second))
+        }
+    }
+
+    /// Stops handing out tokens until the given time has passed.
+    pub fn pause_for(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.paused_until = Some(bucket.paused_until.map_or(until, |current| current.max(until)));
+    }
+
+    /// Pauses when the response headers say the request or token quota has
+    /// run out, until the quota is due to reset or for `max_pause`, whichever
+    /// is sooner.
+    pub fn observe(&self, headers: &HeaderMap, max_pause: Duration) {
+        if let Some(delay) = quota_reset(headers) {
+            self.pause_for(delay.min(max_pause));
+        }
+    }
+}
+
+/// The time until an exhausted `x-ratelimit-*` quota resets, if any is exhausted.
+pub fn quota_reset(headers: &HeaderMap) -> Option<Duration> {
+    ["requests", "tokens"].iter()
+        .filter(|kind| header(headers, &format!("x-ratelimit-remaining-{}", kind)).and_then(|v| v.parse::<u64>().ok()) == Some(0))
+        .filter_map(|kind| header(headers, &format!("x-ratelimit-reset-{}", kind)).and_then(|v| parse_duration(&v)))
+        .max()
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name)?.to_str().ok().map(|value| value.trim().to_string())
+}
+
+/// A header's count of seconds as a duration. Negative counts are zero, and
+/// counts too large for a `Duration` are `Duration::MAX`, for the caller to
+/// cap; `NaN` isn't a duration at all.
+pub fn from_seconds(seconds: f64) -> Option<Duration> {
+    if seconds.is_nan() {
+        return None;
+    }
+    Some(Duration::try_from_secs_f64(seconds.max(0.0)).unwrap_or(Duration::MAX))
+}
+
+/// Parses the durations OpenAI uses in rate limit headers, e.g. `1s`,
+/// `6m0s`, `120ms` or `1h2m3.5s`. A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return from_seconds(seconds);
+    }
+
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let amount = number.parse::<f64>().ok()?;
+        number.clear();
+
+        let seconds = match c {
+            'h' => 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                0.001
+            }
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return None
+        };
+        total += amount * seconds;
+    }
+
+    if !number.is_empty() {
+        return None;
+    }
+
+    from_seconds(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn parses_rate_limit_durations() {
+        assert_eq!(parse_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("120ms"), Some(Duration::from_millis(120)));
+        assert_eq!(parse_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("inf"), Some(Duration::MAX));
+        assert_eq!(parse_duration("1e30"), Some(Duration::MAX));
+        assert_eq!(parse_duration("NaN"), None);
+        assert_eq!(parse_duration("-3"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn backoff_is_capped_and_respects_hints() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        for attempt in 0..10 {
+            assert!(policy.delay(attempt, None) <= Duration::from_secs(1));
+        }
+        assert!(policy.delay(0, Some(Duration::from_millis(700))) >= Duration::from_millis(700));
+        assert_eq!(policy.delay(0, Some(Duration::from_secs(60))), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_runs_dry_and_refills() {
+        let limiter = RateLimiter::per_minute(2);
+        let start = Instant::now();
+
+        assert_eq!(limiter.try_acquire(start), None);
+        assert_eq!(limiter.try_acquire(start), None);
+        assert!(limiter.try_acquire(start).is_some());
+        assert_eq!(limiter.try_acquire(start + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn exhausted_quota_pauses_the_limiter() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining-requests", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("2s"));
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("100"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("9s"));
+
+        assert_eq!(quota_reset(&headers), Some(Duration::from_secs(2)));
+
+        let limiter = RateLimiter::unlimited();
+        limiter.observe(&headers, Duration::from_secs(30));
+        assert!(limiter.try_acquire(Instant::now()).is_some());
+
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("1e30"));
+        let limiter = RateLimiter::unlimited();
+        limiter.observe(&headers, Duration::from_secs(1));
+        assert!(limiter.try_acquire(Instant::now()).unwrap() <= Duration::from_secs(1));
+    }
+}
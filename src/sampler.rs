@@ -0,0 +1,273 @@
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Deserialize;
+
+use crate::links::LinkGraph;
+use crate::notes::NoteError;
+use crate::subtext::Subtext;
+
+const DAY: f64 = 86_400.0;
+/// Recency weights halve for every this many days since a note was modified.
+const RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+/// Staleness stops growing after this many days, so one ancient note
+/// doesn't crowd out everything else.
+const MAX_STALENESS_DAYS: f64 = 365.0;
+
+/// How likely each note is to be drawn.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weighting {
+    #[default]
+    Uniform,
+    /// Recently modified notes, by `Modified` header or file time.
+    Recency,
+    /// Notes that haven't been drawn for a long time, or ever.
+    Staleness,
+    /// Notes with many links and backlinks.
+    LinkDegree,
+}
+
+/// When each note was last drawn, in seconds since the Unix epoch.
+pub type History = BTreeMap<String, u64>;
+
+/// Draws notes at random without replacement: a note drawn once isn't
+/// drawn again until `reset`. Draws are reproducible for a given seed and
+/// set of notes.
+pub struct NoteSampler {
+    notes: Vec<Subtext>,
+    rng: StdRng,
+    weighting: Weighting,
+    /// When not empty, only notes with at least one of these tags are drawn.
+    tags: Vec<String>,
+    history: History,
+    now: u64,
+    drawn: HashSet<usize>,
+    graph: OnceCell<LinkGraph>,
+}
+
+impl NoteSampler {
+    pub fn new(notes: Vec<Subtext>, seed: Option<u64>) -> NoteSampler {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy()
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        NoteSampler { notes, rng, weighting: Weighting::default(), tags: Vec::new(), history: History::new(), now, drawn: HashSet::new(), graph: OnceCell::new() }
+    }
+
+    pub fn with_weighting(mut self, weighting: Weighting) -> NoteSampler {
+        self.weighting = weighting;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: &[String]) -> NoteSampler {
+        self.tags = tags.iter().map(|tag| tag.to_lowercase()).collect();
+        self
+    }
+
+    /// When notes were last drawn in earlier runs, for `Weighting::Staleness`.
+    pub fn with_history(mut self, history: History) -> NoteSampler {
+        self.history = history;
+        self
+    }
+
+    #[cfg(test)]
+    fn at(mut self, now: u64) -> NoteSampler {
+        self.now = now;
+        self
+    }
+
+    /// The links between all the sampler's notes, drawn or not.
+    pub fn graph(&self) -> &LinkGraph {
+        self.graph.get_or_init(|| LinkGraph::new(&self.notes))
+    }
+
+    /// The history passed in, updated with this run's draws.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Makes every note drawable again.
+    pub fn reset(&mut self) {
+        self.drawn.clear();
+    }
+
+    /// Marks `name` as drawn, for notes picked by hand.
+    pub fn exclude(&mut self, name: &str) {
+        if let Some(index) = self.notes.iter().position(|note| note.name == name) {
+            self.drawn.insert(index);
+        }
+    }
+
+    pub fn draw(&mut self) -> Result<Subtext, NoteError> {
+        let candidates = (0..self.notes.len()).collect::<Vec<usize>>();
+        self.draw_from(&candidates).ok_or(NoteError::NoNotes)
+    }
+
+    /// Draws a note linked to `base`, or failing that one up to `hops` links
+    /// away, nearer notes first.
+    pub fn draw_related(&mut self, base: &str, hops: usize) -> Option<Subtext> {
+        let rings = self.graph().neighbours(base, hops).into_iter()
+            .map(|ring| ring.into_iter().filter_map(|name| self.notes.iter().position(|note| note.name == name)).collect())
+            .collect::<Vec<Vec<usize>>>();
+
+        rings.iter().find_map(|ring| self.draw_from(ring))
+    }
+
+    fn draw_from(&mut self, candidates: &[usize]) -> Option<Subtext> {
+        let candidates = candidates.iter()
+            .filter(|index| !self.drawn.contains(index))
+            .map(|index| (*index, self.weight(&self.notes[*index])))
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect::<Vec<(usize, f64)>>();
+
+        let distribution = WeightedIndex::new(candidates.iter().map(|(_, weight)| *weight)).ok()?;
+        let (index, _) = candidates[distribution.sample(&mut self.rng)];
+
+        self.drawn.insert(index);
+        let note = self.notes[index].clone();
+        self.history.insert(note.name.clone(), self.now);
+        Some(note)
+    }
+
+    fn weight(&self, note: &Subtext) -> f64 {
+        let tagged = self.tags.is_empty() || note.metadata.tags.iter().any(|tag| self.tags.contains(&tag.to_lowercase()));
+        if !tagged {
+            return 0.0;
+        }
+
+        let days_since = |time: i64| (self.now as f64 - time as f64).max(0.0) / DAY;
+        match self.weighting {
+            Weighting::Uniform => 1.0,
+            Weighting::Recency => match note.metadata.modified.or(note.metadata.created) {
+                Some(modified) => 0.5f64.powf(days_since(modified) / RECENCY_HALF_LIFE_DAYS).max(f64::MIN_POSITIVE),
+                None => f64::MIN_POSITIVE
+            },
+            Weighting::Staleness => match self.history.get(&note.name) {
+                Some(drawn) => 1.0 + days_since(*drawn as i64).min(MAX_STALENESS_DAYS),
+                None => 1.0 + MAX_STALENESS_DAYS
+            },
+            Weighting::LinkDegree => {
+                let graph = self.graph();
+                1.0 + (graph.links(&note.name).len() + graph.backlinks(&note.name).len()) as f64
+            }
+        }
+    }
+}
+
+/// Loads the history saved at `path`, or an empty one if nothing was saved yet.
+pub fn load_history(path: &Path) -> Result<History, NoteError> {
+    if !path.exists() {
+        return Ok(History::new());
+    }
+
+    let text = fs::read_to_string(path).map_err(|error| NoteError::IOError(path.to_path_buf(), error))?;
+    serde_json::from_str(&text).map_err(|error| NoteError::InvalidHistory(path.to_path_buf(), error.to_string()))
+}
+
+pub fn save_history(path: &Path, history: &History) -> Result<(), NoteError> {
+    let io_error = |error| NoteError::IOError(path.to_path_buf(), error);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_error)?;
+    }
+
+    let partial = path.with_extension("json.tmp");
+    fs::write(&partial, serde_json::to_string(history).unwrap()).map_err(io_error)?;
+    fs::rename(&partial, path).map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(name: &str, text: &str) -> Subtext {
+        Subtext::parse(name, text).unwrap()
+    }
+
+    fn notes() -> Vec<Subtext> {
+        vec![
+            note("loops.subtext", "Tags: systems\nModified: 2023-04-01\n\nLoops feed /gardens, /rivers and /seeds"),
+            note("gardens.subtext", "Tags: growth\nModified: 2020-01-01\n\nBack to /loops"),
+            note("rivers.subtext", "Modified: 2023-03-31\n\nValleys"),
+            note("seeds.subtext", "Tags: growth, systems\n\nSmall"),
+        ]
+    }
+
+    fn names(sampler: &mut NoteSampler, count: usize) -> Vec<String> {
+        (0..count).map(|_| sampler.draw().unwrap().name).collect()
+    }
+
+    #[test]
+    fn draws_every_note_once_then_runs_out() {
+        let mut sampler = NoteSampler::new(notes(), Some(1));
+
+        let mut drawn = names(&mut sampler, 4);
+        drawn.sort();
+
+        assert_eq!(drawn, ["gardens.subtext", "loops.subtext", "rivers.subtext", "seeds.subtext"]);
+        assert!(matches!(sampler.draw(), Err(NoteError::NoNotes)));
+        sampler.reset();
+        assert!(sampler.draw().is_ok());
+        assert!(NoteSampler::new(vec![], None).draw().is_err());
+    }
+
+    #[test]
+    fn the_same_seed_draws_the_same_notes() {
+        let draws = |seed| names(&mut NoteSampler::new(notes(), Some(seed)), 3);
+
+        assert_eq!(draws(7), draws(7));
+        assert!((0..20).any(|seed| draws(seed) != draws(7)));
+    }
+
+    #[test]
+    fn filters_by_tag_and_skips_excluded_notes() {
+        let mut sampler = NoteSampler::new(notes(), Some(3)).with_tags(&["Systems".to_string()]);
+        sampler.exclude("seeds.subtext");
+
+        assert_eq!(names(&mut sampler, 1), ["loops.subtext"]);
+        assert!(sampler.draw().is_err());
+    }
+
+    #[test]
+    fn weights_favour_what_they_say() {
+        let now = 1_680_307_200; // 2023-04-01
+        let first_draws = |weighting: Weighting, history: History| {
+            let mut counts = BTreeMap::new();
+            for seed in 0..200 {
+                let mut sampler = NoteSampler::new(notes(), Some(seed)).with_weighting(weighting).with_history(history.clone()).at(now);
+                *counts.entry(sampler.draw().unwrap().name).or_insert(0) += 1;
+            }
+            counts.into_iter().max_by_key(|(_, count)| *count).unwrap().0
+        };
+
+        let recently_drawn = notes().iter()
+            .filter(|note| note.name != "rivers.subtext")
+            .map(|note| (note.name.clone(), now))
+            .collect::<History>();
+
+        assert_ne!(first_draws(Weighting::Recency, History::new()), "gardens.subtext");
+        assert_eq!(first_draws(Weighting::Staleness, recently_drawn), "rivers.subtext");
+        assert_eq!(first_draws(Weighting::LinkDegree, History::new()), "loops.subtext");
+    }
+
+    #[test]
+    fn draws_linked_notes_first() {
+        let mut sampler = NoteSampler::new(notes(), Some(5));
+        sampler.exclude("gardens.subtext");
+
+        let related = sampler.draw_related("gardens.subtext", 2).unwrap();
+
+        assert_eq!(related.name, "loops.subtext");
+        assert_eq!(sampler.history().get("loops.subtext").copied(), Some(sampler.now));
+        assert!(sampler.draw_related("stones.subtext", 2).is_none());
+    }
+}
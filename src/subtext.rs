@@ -1,35 +1,644 @@
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+#[derive(Debug)]
+pub enum SubtextError {
+    IOError(PathBuf, std::io::Error),
+    InvalidFileName(PathBuf),
+    InvalidUtf8(PathBuf),
+    /// A known header whose value can't be parsed, with its 1-based line number.
+    InvalidHeader { line: usize, name: String, value: String },
+}
+
+impl fmt::Display for SubtextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubtextError::IOError(path, error) => write!(f, "could not read note {}: {}", path.display(), error),
+            SubtextError::InvalidFileName(path) => write!(f, "note file name {} is not valid UTF-8", path.display()),
+            SubtextError::InvalidUtf8(path) => write!(f, "note {} is not valid UTF-8", path.display()),
+            SubtextError::InvalidHeader { line, name, value } => write!(f, "line {}: invalid {} header {:?}", line, name, value)
+        }
+    }
+}
+
+/// The headers Subtext tools agree on, parsed. Unknown headers are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created: Option<i64>,
+    pub modified: Option<i64>,
+    pub tags: Vec<String>,
+    pub content_type: Option<String>,
+}
+
+/// The file formats a note can be written in, told apart by extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoteFormat {
+    Subtext,
+    /// Markdown, with optional YAML front matter.
+    Markdown,
+    Text,
+}
+
+impl NoteFormat {
+    pub fn from_path(path: &Path) -> Option<NoteFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "subtext" => Some(NoteFormat::Subtext),
+            "md" | "markdown" => Some(NoteFormat::Markdown),
+            "txt" => Some(NoteFormat::Text),
+            _ => None
+        }
+    }
+}
+
+/// A note, whatever format it was written in.
+#[derive(Clone, Debug)]
 pub struct Subtext {
     pub name: String,
+    /// Every header in the order given, duplicates included. Only the
+    /// parser's tests read them; the rest of the code goes by `metadata`.
+    #[cfg(test)]
     pub headers: Vec<(String, String)>,
+    pub metadata: Metadata,
     pub content: String,
+    /// `content` parsed into blocks, with spans into `content`.
+    pub blocks: Vec<Block>,
 }
 
 impl Subtext {
-    pub fn from_file<P: AsRef<Path>>(file_path: P) -> io::Result<Self> {
-        let file = File::open(file_path.as_ref())?;
-        let name = file_path.as_ref().file_name().unwrap().to_str().unwrap().to_string();
-        let reader = BufReader::new(file);
-
-        let mut lines = reader.lines().map(Result::unwrap);
-
-        let headers: Vec<(String, String)> = lines
-            .by_ref()
-            .take_while(|line| !line.trim().is_empty())
-            .filter(|line| line.contains(':'))
-            .map(|line| {
-                let mut parts = line.splitn(2, ':');
-                let key = parts.next().unwrap().trim().to_string();
-                let value = parts.next().unwrap_or("").trim().to_string();
-                (key, value)
-            })
-            .collect();
-
-        let content = lines.collect::<Vec<String>>().join("\n");
-
-        Ok(Subtext { name, headers, content })
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, SubtextError> {
+        let path = file_path.as_ref();
+        let name = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| SubtextError::InvalidFileName(path.to_path_buf()))?;
+        let bytes = fs::read(path).map_err(|error| SubtextError::IOError(path.to_path_buf(), error))?;
+        let text = String::from_utf8(bytes).map_err(|_| SubtextError::InvalidUtf8(path.to_path_buf()))?;
+
+        match NoteFormat::from_path(path) {
+            Some(NoteFormat::Markdown) => Subtext::from_markdown(name, &text),
+            Some(NoteFormat::Text) => Ok(Subtext::from_text(name, &text)),
+            Some(NoteFormat::Subtext) | None => Subtext::parse(name, &text)
+        }
+    }
+
+    /// Plain text has no headers.
+    pub fn from_text(name: &str, text: &str) -> Self {
+        let content = text.lines().collect::<Vec<&str>>().join("\n");
+        let blocks = parse(&content);
+        Subtext {
+            name: name.to_string(),
+            #[cfg(test)]
+            headers: Vec::new(),
+            metadata: Metadata::default(),
+            content,
+            blocks
+        }
+    }
+
+    /// Reads Markdown as Subtext: YAML front matter becomes headers, and
+    /// headings of any level and `*` or `+` bullets become their Subtext
+    /// equivalents.
+    pub fn from_markdown(name: &str, text: &str) -> Result<Self, SubtextError> {
+        let lines = text.lines().collect::<Vec<&str>>();
+        let front_matter_end = match lines.first() {
+            Some(first) if first.trim_end() == "---" => lines.iter().skip(1).position(|line| matches!(line.trim_end(), "---" | "...")).map(|end| end + 1),
+            _ => None
+        };
+
+        let (headers, body) = match front_matter_end {
+            // The front matter starts on line 2, after the `---`.
+            Some(end) => (front_matter(&lines[1..end], 2), &lines[end + 1..]),
+            None => (Vec::new(), &lines[..])
+        };
+        let metadata = Metadata::parse(&headers)?;
+
+        let content = body.iter().map(|line| markdown_line(line)).collect::<Vec<String>>().join("\n");
+        let blocks = parse(&content);
+
+        Ok(Subtext {
+            name: name.to_string(),
+            #[cfg(test)]
+            headers: headers.into_iter().map(|(_, header)| header).collect(),
+            metadata,
+            content,
+            blocks
+        })
+    }
+
+    /// Parses a note's text. A header block is a run of `Name: value` lines
+    /// at the very top, ended by a blank line; without one, the whole text
+    /// is content.
+    pub fn parse(name: &str, text: &str) -> Result<Self, SubtextError> {
+        let lines = text.lines().collect::<Vec<&str>>();
+        let header_lines = lines.iter().position(|line| line.trim().is_empty())
+            .filter(|&end| end > 0 && lines[..end].iter().all(|line| header(line).is_some()))
+            .unwrap_or(0);
+
+        let headers = lines[..header_lines].iter().enumerate()
+            .filter_map(|(index, line)| Some((index + 1, header(line)?)))
+            .collect::<Vec<(usize, (String, String))>>();
+        let metadata = Metadata::parse(&headers)?;
+
+        let body = if header_lines > 0 { &lines[header_lines + 1..] } else { &lines[..] };
+        let content = body.join("\n");
+        let blocks = parse(&content);
+
+        Ok(Subtext {
+            name: name.to_string(),
+            #[cfg(test)]
+            headers: headers.into_iter().map(|(_, header)| header).collect(),
+            metadata,
+            content,
+            blocks
+        })
+    }
+}
+
+/// Splits a `Name: value` header line. Names start with a letter and hold
+/// only letters, digits, `-` and `_`, and the colon must be followed by a
+/// space or the end of the line, so prose like `http://...` isn't a header.
+fn header(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    (valid_name && (value.is_empty() || value.starts_with(char::is_whitespace)))
+        .then(|| (name.to_string(), value.trim().to_string()))
+}
+
+/// Reads the `key: value` pairs of YAML front matter. Lists, whether
+/// `[a, b]` or one `- item` per line, are joined with commas. Each pair
+/// comes with the source line of its key, counting `lines` from `first_line`.
+fn front_matter(lines: &[&str], first_line: usize) -> Vec<(usize, (String, String))> {
+    let unquote = |value: &str| value.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+    let mut headers: Vec<(usize, (String, String))> = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let (Some(item), Some((_, value))) = (trimmed.strip_prefix("- "), headers.last_mut().map(|(_, header)| header)) {
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            value.push_str(&unquote(item));
+            continue;
+        }
+
+        if let Some((key, value)) = trimmed.split_once(':') {
+            let value = match value.trim().strip_prefix('[').and_then(|list| list.strip_suffix(']')) {
+                Some(list) => list.split(',').map(unquote).filter(|item| !item.is_empty()).collect::<Vec<String>>().join(", "),
+                None => unquote(value)
+            };
+            headers.push((first_line + index, (key.trim().to_string(), value)));
+        }
+    }
+
+    headers
+}
+
+fn markdown_line(line: &str) -> String {
+    let hashes = line.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&hashes) && line[hashes..].starts_with(' ') {
+        return format!("# {}", line[hashes..].trim());
+    }
+    match line.strip_prefix("* ").or_else(|| line.strip_prefix("+ ")) {
+        Some(item) => format!("- {}", item),
+        None => line.to_string()
+    }
+}
+
+impl Metadata {
+    /// Reads the known headers, ignoring case in their names (and taking
+    /// Markdown's `date` for `Created`). Where a header
+    /// is repeated the last one wins, except `Tags`, which accumulate. Each
+    /// header comes with its source line, for errors.
+    fn parse(headers: &[(usize, (String, String))]) -> Result<Metadata, SubtextError> {
+        let mut metadata = Metadata::default();
+
+        for (line, (name, value)) in headers {
+            let invalid = || SubtextError::InvalidHeader { line: *line, name: name.clone(), value: value.clone() };
+
+            match name.to_ascii_lowercase().as_str() {
+                "title" => metadata.title = Some(value.clone()),
+                "created" | "date" => metadata.created = Some(parse_timestamp(value).ok_or_else(invalid)?),
+                "modified" => metadata.modified = Some(parse_timestamp(value).ok_or_else(invalid)?),
+                "tags" => metadata.tags.extend(
+                    value.split(|c: char| c == ',' || c.is_whitespace())
+                        .map(|tag| tag.trim_start_matches('#'))
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_string)
+                ),
+                "content-type" => metadata.content_type = Some(value.clone()),
+                _ => {}
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+/// Parses an RFC 3339 timestamp (`2023-04-01T09:30:00Z`, with an optional
+/// fraction and offset) or a bare date, into seconds since the Unix epoch.
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let number = |text: &str| -> Option<i64> {
+        text.bytes().all(|byte| byte.is_ascii_digit()).then(|| text.parse().ok()).flatten()
+    };
+
+    let (date, time) = match value.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None)
+    };
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (number(parts.next()?)?, number(parts.next()?)?, number(parts.next()?)?);
+    if date.len() != 10 || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+
+    if let Some(time) = time {
+        let (clock, offset) = match time.find(['Z', 'z', '+', '-']) {
+            Some(at) => time.split_at(at),
+            None => return None
+        };
+        let clock = clock.split_once('.').map_or(clock, |(whole, _)| whole);
+        let mut fields = clock.splitn(3, ':');
+        let (hour, minute, second) = (number(fields.next()?)?, number(fields.next()?)?, number(fields.next()?)?);
+        if clock.len() != 8 || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        seconds += hour * 3600 + minute * 60 + second;
+
+        if !offset.eq_ignore_ascii_case("z") {
+            let (sign, offset) = offset.split_at(1);
+            let (hours, minutes) = offset.split_once(':')?;
+            let (hours, minutes) = (number(hours)?, number(minutes)?);
+            if offset.len() != 5 || hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            seconds -= if sign == "+" { offset } else { -offset };
+        }
+    }
+
+    Some(seconds)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn format_timestamp(seconds: i64) -> String {
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// A byte range into the parsed text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The kinds of line in Subtext, told apart by their leading sigil.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    Paragraph,
+    /// `# heading`
+    Heading,
+    /// `- list item`
+    List,
+    /// `> quote`
+    Quote,
+    /// `& /slashlink` or `& https://...`, a link or transclusion
+    Link,
+    Blank,
+}
+
+impl BlockKind {
+    fn sigil(self) -> Option<char> {
+        match self {
+            BlockKind::Heading => Some('#'),
+            BlockKind::List => Some('-'),
+            BlockKind::Quote => Some('>'),
+            BlockKind::Link => Some('&'),
+            BlockKind::Paragraph | BlockKind::Blank => None
+        }
+    }
+
+    fn from_sigil(sigil: char) -> Option<BlockKind> {
+        [BlockKind::Heading, BlockKind::List, BlockKind::Quote, BlockKind::Link]
+            .into_iter()
+            .find(|kind| kind.sigil() == Some(sigil))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InlineKind {
+    Text,
+    /// `/some/note`
+    SlashLink,
+    /// `http://` or `https://`
+    Url,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inline {
+    pub kind: InlineKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// One line of Subtext. `span` covers the whole line, sigil included,
+/// while `inlines` cover only what follows the sigil.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub inlines: Vec<Inline>,
+    pub span: Span,
+}
+
+impl Block {
+    /// The block's text without its sigil.
+    pub fn text(&self) -> String {
+        self.inlines.iter().map(|inline| inline.text.as_str()).collect()
+    }
+
+    /// The block as a line of Subtext, with a single space after its sigil.
+    pub fn to_subtext(&self) -> String {
+        let text = self.text();
+        match self.kind.sigil() {
+            Some(sigil) if text.is_empty() => sigil.to_string(),
+            Some(sigil) => format!("{} {}", sigil, text),
+            None => text
+        }
+    }
+}
+
+/// Parses Subtext into one block per line.
+pub fn parse(source: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+
+    for raw_line in source.split_inclusive('\n') {
+        let line = raw_line.trim_end_matches(['\n', '\r']);
+        let span = Span { start, end: start + line.len() };
+
+        let block = if line.trim().is_empty() {
+            Block { kind: BlockKind::Blank, inlines: Vec::new(), span }
+        } else {
+            let sigil = line.chars().next().and_then(BlockKind::from_sigil);
+            let (kind, body_start) = match sigil {
+                Some(kind) => (kind, if line[1..].starts_with(' ') { 2 } else { 1 }),
+                None => (BlockKind::Paragraph, 0)
+            };
+            Block { kind, inlines: parse_inlines(&line[body_start..], start + body_start), span }
+        };
+
+        blocks.push(block);
+        start += raw_line.len();
+    }
+
+    blocks
+}
+
+/// Writes `blocks` back out, one line each. Only the round-trip tests need it.
+#[cfg(test)]
+pub fn serialize(blocks: &[Block]) -> String {
+    blocks.iter().map(Block::to_subtext).collect::<Vec<String>>().join("\n")
+}
+
+fn is_slashlink_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '/'
+}
+
+/// Splits a block's body into text, slashlinks and URLs. Links only start
+/// at the beginning of a word.
+fn parse_inlines(body: &str, offset: usize) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+
+    let mut push = |kind: InlineKind, start: usize, end: usize| {
+        if start < end {
+            inlines.push(Inline { kind, text: body[start..end].to_string(), span: Span { start: offset + start, end: offset + end } });
+        }
+    };
+
+    while i < body.len() {
+        let rest = &body[i..];
+        let at_word_start = body[..i].chars().next_back().is_none_or(char::is_whitespace);
+        let word_end = i + rest.find(char::is_whitespace).unwrap_or(rest.len());
+
+        let link = if !at_word_start {
+            None
+        } else if rest.starts_with("http://") || rest.starts_with("https://") {
+            let url = body[i..word_end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '"', '\'']);
+            Some((InlineKind::Url, i + url.len()))
+        } else if rest.starts_with('/') {
+            let path = rest.find(|c: char| !is_slashlink_char(c)).unwrap_or(rest.len());
+            let path = rest[..path].trim_end_matches('/');
+            (path.len() > 1).then_some((InlineKind::SlashLink, i + path.len()))
+        } else {
+            None
+        };
+
+        match link {
+            Some((kind, end)) => {
+                push(InlineKind::Text, text_start, i);
+                push(kind, i, end);
+                text_start = end;
+                i = end;
+            }
+            None => i += rest.chars().next().map_or(1, char::len_utf8)
+        }
+    }
+    push(InlineKind::Text, text_start, body.len());
+
+    inlines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(blocks: &[Block]) -> Vec<BlockKind> {
+        blocks.iter().map(|block| block.kind).collect()
+    }
+
+    #[test]
+    fn reads_a_header_block() {
+        let note = Subtext::parse("loops.subtext", "Title: Loops\nTags: systems, #growth\nX-Source: a\nX-Source: b\nCreated: 2023-04-01T09:30:00Z\nModified: 2023-04-02\nContent-Type: text/subtext\n\n# Loops\nbody").unwrap();
+
+        assert_eq!(note.headers[2..4], [("X-Source".to_string(), "a".to_string()), ("X-Source".to_string(), "b".to_string())]);
+        assert_eq!(note.metadata, Metadata {
+            title: Some("Loops".to_string()),
+            created: Some(1_680_341_400),
+            modified: Some(1_680_393_600),
+            tags: vec!["systems".to_string(), "growth".to_string()],
+            content_type: Some("text/subtext".to_string()),
+        });
+        assert_eq!(note.content, "# Loops\nbody");
+    }
+
+    #[test]
+    fn prose_is_not_a_header_block() {
+        for text in ["Note: this is prose\nand so is this\n\nmore", "https://example.com\n\nmore", "just text\n\nmore", "Title: no blank line after"] {
+            let note = Subtext::parse("note.subtext", text).unwrap();
+
+            assert!(note.headers.is_empty(), "{:?}", text);
+            assert_eq!(note.content, text);
+        }
+    }
+
+    #[test]
+    fn reports_invalid_headers_by_line() {
+        let error = Subtext::parse("note.subtext", "Title: x\nCreated: yesterday\n\nbody").err().unwrap();
+
+        assert!(matches!(error, SubtextError::InvalidHeader { line: 2, ref name, .. } if name == "Created"));
+        assert!(Subtext::from_file("/no/such/note.subtext").is_err());
+    }
+
+    #[test]
+    fn reports_invalid_front_matter_by_source_line() {
+        let error = Subtext::from_markdown("note.md", "---\ntitle: x\ntags:\n  - a\n\nmodified: soon\n---\nbody").err().unwrap();
+
+        assert!(matches!(error, SubtextError::InvalidHeader { line: 6, ref name, .. } if name == "modified"));
+    }
+
+    #[test]
+    fn reads_markdown_with_front_matter() {
+        let text = "---\ntitle: \"Loops\"\ndate: 2023-04-01\ntags:\n  - systems\n  - growth\naliases: [a, 'b']\n---\n## Loops\n* feedback /gardens\n\nprose";
+        let note = Subtext::from_markdown("loops.md", text).unwrap();
+
+        assert_eq!(note.metadata.title.as_deref(), Some("Loops"));
+        assert_eq!(note.metadata.created, Some(1_680_307_200));
+        assert_eq!(note.metadata.tags, ["systems", "growth"]);
+        assert_eq!(note.headers[3], ("aliases".to_string(), "a, b".to_string()));
+        assert_eq!(note.content, "# Loops\n- feedback /gardens\n\nprose");
+        assert_eq!(note.blocks[1].inlines[1].kind, InlineKind::SlashLink);
+    }
+
+    #[test]
+    fn markdown_without_front_matter_is_all_content() {
+        let note = Subtext::from_markdown("note.md", "---\nnot closed\n+ item").unwrap();
+
+        assert!(note.headers.is_empty());
+        assert_eq!(note.content, "---\nnot closed\n- item");
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(NoteFormat::from_path(Path::new("a/b.MD")), Some(NoteFormat::Markdown));
+        assert_eq!(NoteFormat::from_path(Path::new("b.subtext")), Some(NoteFormat::Subtext));
+        assert_eq!(NoteFormat::from_path(Path::new("b.txt")), Some(NoteFormat::Text));
+        assert_eq!(NoteFormat::from_path(Path::new("b.png")), None);
+        assert_eq!(Subtext::from_text("b.txt", "Title: not a header\n\nbody").content, "Title: not a header\n\nbody");
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2000-02-29T12:00:00.5+02:00"), Some(951_818_400));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Some(-1));
+        for invalid in ["2023-02-29", "2023-13-01", "2023-1-01", "2023-04-01T25:00:00Z", "2023-04-01T09:30:00", "yesterday"] {
+            assert_eq!(parse_timestamp(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59Z");
+        for timestamp in ["2000-02-29T10:00:00Z", "2023-12-31T23:59:59Z", "2024-03-01T00:00:00Z"] {
+            assert_eq!(format_timestamp(parse_timestamp(timestamp).unwrap()), timestamp);
+        }
+    }
+
+    #[test]
+    fn parses_each_kind_of_block() {
+        let source = "# Loops\n\nFeedback drives growth.\n- one\n> a quote\n& /gardens";
+        let blocks = parse(source);
+
+        assert_eq!(kinds(&blocks), [BlockKind::Heading, BlockKind::Blank, BlockKind::Paragraph, BlockKind::List, BlockKind::Quote, BlockKind::Link]);
+        assert_eq!(blocks[0].text(), "Loops");
+        assert_eq!(blocks[4].text(), "a quote");
+        assert_eq!(blocks[5].inlines[0].kind, InlineKind::SlashLink);
+    }
+
+    #[test]
+    fn finds_slashlinks_and_urls_inside_text() {
+        let blocks = parse("see /feedback-loops and https://example.com/a?b=c. not a/link or /");
+        let inlines = blocks[0].inlines.iter().map(|inline| (inline.kind, inline.text.as_str())).collect::<Vec<_>>();
+
+        assert_eq!(inlines, [
+            (InlineKind::Text, "see "),
+            (InlineKind::SlashLink, "/feedback-loops"),
+            (InlineKind::Text, " and "),
+            (InlineKind::Url, "https://example.com/a?b=c"),
+            (InlineKind::Text, ". not a/link or /"),
+        ]);
+    }
+
+    #[test]
+    fn spans_point_back_into_the_source() {
+        let source = "# Héading\r\n> quote /über";
+        let blocks = parse(source);
+
+        assert_eq!(&source[blocks[0].span.start..blocks[0].span.end], "# Héading");
+        assert_eq!(&source[blocks[1].span.start..blocks[1].span.end], "> quote /über");
+        for inline in blocks.iter().flat_map(|block| &block.inlines) {
+            assert_eq!(&source[inline.span.start..inline.span.end], inline.text);
+        }
+    }
+
+    #[test]
+    fn round_trips_canonical_text() {
+        let source = "# Loops\n\nFeedback /loops drive https://example.com growth.\n- one\n- two\n> quote\n& /gardens\n#\n  indented text";
+
+        assert_eq!(serialize(&parse(source)), source);
+    }
+
+    #[test]
+    fn serialising_normalises_sigil_spacing() {
+        let normalised = serialize(&parse("#Loops\n   \n>quote"));
+
+        assert_eq!(normalised, "# Loops\n\n> quote");
+        assert_eq!(serialize(&parse(&normalised)), normalised);
     }
 }
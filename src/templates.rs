@@ -0,0 +1,442 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Whether a template variable holds one piece of text or a list to loop over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Text,
+    List,
+}
+
+/// A template's name, its built-in text and the variables it's given.
+type Prompt = (&'static str, &'static str, &'static [(&'static str, Kind)]);
+
+/// Every prompt the code renders, with the variables it passes. Files in
+/// the prompts folder named after one of these replace the built-in text;
+/// any other file can be pulled in with `{% include "name" %}`.
+const PROMPTS: &[Prompt] = &[
+    ("critical-writing", include_str!("../prompts/critical-writing.txt"), &[("input", Kind::Text)]),
+    ("connections", include_str!("../prompts/connections.txt"), &[("base_note", Kind::Text), ("notes", Kind::List), ("count", Kind::Text)]),
+    ("question-everything", include_str!("../prompts/question-everything.txt"), &[("input", Kind::Text), ("question", Kind::Text)]),
+    ("compressor", include_str!("../prompts/compressor.txt"), &[("input", Kind::Text)]),
+    ("chatter", include_str!("../prompts/chatter.txt"), &[("input", Kind::Text)]),
+    ("summarize-transcript", include_str!("../prompts/summarize-transcript.txt"), &[("summary", Kind::Text), ("transcript", Kind::Text)]),
+    ("summarize-note", include_str!("../prompts/summarize-note.txt"), &[("note", Kind::Text), ("tokens", Kind::Text)]),
+];
+
+/// Templates only pipelines render, which check the variables they pass
+/// themselves, and templates shared through includes.
+const PIPELINE_TEMPLATES: &[(&str, &str)] = &[
+    ("critic", include_str!("../prompts/critic.txt")),
+    ("actor", include_str!("../prompts/actor.txt")),
+    ("giga-actor", include_str!("../prompts/giga-actor.txt")),
+    ("actor-example", include_str!("../prompts/actor-example.txt")),
+];
+
+/// Includes nested deeper than this are assumed to be a cycle.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    IOError(PathBuf, std::io::Error),
+    ParseError { template: String, line: usize, message: String },
+    MissingTemplate(String),
+    MissingInclude { template: String, include: String },
+    /// A variable the template uses but isn't given, or uses as the wrong kind.
+    UnknownVariable { template: String, name: String },
+    /// A variable the template is given but never uses.
+    UnusedVariable { template: String, name: String },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::IOError(path, error) => write!(f, "could not read template {}: {}", path.display(), error),
+            TemplateError::ParseError { template, line, message } => write!(f, "template {} line {}: {}", template, line, message),
+            TemplateError::MissingTemplate(name) => write!(f, "no template named {:?}", name),
+            TemplateError::MissingInclude { template, include } => write!(f, "template {} includes {:?}, which doesn't exist (or includes itself)", template, include),
+            TemplateError::UnknownVariable { template, name } => write!(f, "template {} uses {:?}, which it isn't given", template, name),
+            TemplateError::UnusedVariable { template, name } => write!(f, "template {} never uses {:?}", template, name)
+        }
+    }
+}
+
+/// A value passed to a template.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    List(Vec<String>),
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(list: Vec<String>) -> Self {
+        Value::List(list)
+    }
+}
+
+impl From<&[&str]> for Value {
+    fn from(list: &[&str]) -> Self {
+        Value::List(list.iter().map(|item| item.to_string()).collect())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Text(String),
+    /// `{{ name }}`
+    Variable(String),
+    /// `{% for item in list %}...{% endfor %}`, which also sets `loop.index`
+    /// (from 1), `loop.first` and `loop.last`.
+    For { item: String, list: String, body: Vec<Node> },
+    /// `{% if [not] name %}...{% else %}...{% endif %}`. Empty text and
+    /// empty lists are false.
+    If { negated: bool, condition: String, then: Vec<Node>, otherwise: Vec<Node> },
+    /// `{% include "name" %}`, rendered with the same variables.
+    Include(String),
+}
+
+/// The prompt templates: the built-in ones, with any from a prompts folder
+/// on top. Every prompt is checked against the variables it's given when
+/// loaded, so rendering can't fail at the point of use.
+#[derive(Clone, Debug)]
+pub struct Templates {
+    templates: HashMap<String, Vec<Node>>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates::builtin()
+    }
+}
+
+/// Jinja's convention: a single trailing newline, which editors add, isn't
+/// part of the template.
+fn strip_trailing_newline(source: &str) -> &str {
+    source.strip_suffix('\n').map(|source| source.strip_suffix('\r').unwrap_or(source)).unwrap_or(source)
+}
+
+impl Templates {
+    pub fn builtin() -> Templates {
+        let sources = PROMPTS.iter().map(|(name, source, _)| (*name, *source)).chain(PIPELINE_TEMPLATES.iter().copied());
+        let mut templates = HashMap::new();
+        for (name, source) in sources {
+            templates.insert(name.to_string(), parse(name, strip_trailing_newline(source)).expect("built-in templates parse"));
+        }
+        Templates { templates }
+    }
+
+    /// The built-in templates, replaced or added to by every `.txt` file in
+    /// `dir` (if it exists), named by file stem.
+    pub fn load(dir: &Path) -> Result<Templates, TemplateError> {
+        let mut templates = Templates::builtin();
+
+        if dir.is_dir() {
+            let entries = fs::read_dir(dir).map_err(|error| TemplateError::IOError(dir.to_path_buf(), error))?;
+            for entry in entries {
+                let path = entry.map_err(|error| TemplateError::IOError(dir.to_path_buf(), error))?.path();
+                let name = match (path.file_stem().and_then(|stem| stem.to_str()), path.extension()) {
+                    (Some(name), Some(extension)) if extension == "txt" => name.to_string(),
+                    _ => continue
+                };
+
+                let source = fs::read_to_string(&path).map_err(|error| TemplateError::IOError(path.clone(), error))?;
+                let nodes = parse(&name, strip_trailing_newline(&source))?;
+                templates.templates.insert(name, nodes);
+            }
+        }
+
+        templates.validate()?;
+        Ok(templates)
+    }
+
+    fn validate(&self) -> Result<(), TemplateError> {
+        for (name, _, variables) in PROMPTS {
+            self.check(name, variables)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the template `name` exists and uses exactly `variables`, each
+    /// as the right kind, and that its includes exist.
+    pub fn check(&self, name: &str, variables: &[(&str, Kind)]) -> Result<(), TemplateError> {
+        let nodes = self.templates.get(name).ok_or_else(|| TemplateError::MissingTemplate(name.to_string()))?;
+        let mut scope = variables.iter().map(|(name, kind)| (name.to_string(), *kind)).collect::<Vec<(String, Kind)>>();
+        let mut used = BTreeSet::new();
+        self.check_nodes(name, nodes, &mut scope, &mut used, 0)?;
+
+        match variables.iter().find(|(variable, _)| !used.contains(*variable)) {
+            Some((unused, _)) => Err(TemplateError::UnusedVariable { template: name.to_string(), name: unused.to_string() }),
+            None => Ok(())
+        }
+    }
+
+    fn check_nodes(&self, template: &str, nodes: &[Node], scope: &mut Vec<(String, Kind)>, used: &mut BTreeSet<String>, depth: usize) -> Result<(), TemplateError> {
+        for node in nodes {
+            let (name, kinds) = match node {
+                Node::Text(_) | Node::Include(_) => ("", &[][..]),
+                Node::Variable(name) => (name.as_str(), &[Kind::Text][..]),
+                Node::If { condition, .. } => (condition.as_str(), &[Kind::Text, Kind::List][..]),
+                Node::For { list, .. } => (list.as_str(), &[Kind::List][..])
+            };
+            if !kinds.is_empty() {
+                match scope.iter().rev().find(|(variable, _)| variable == name) {
+                    Some((_, kind)) if kinds.contains(kind) => {
+                        used.insert(name.to_string());
+                    }
+                    _ => return Err(TemplateError::UnknownVariable { template: template.to_string(), name: name.to_string() })
+                }
+            }
+
+            match node {
+                Node::For { item, body, .. } => {
+                    let outer = scope.len();
+                    scope.extend([(item.clone(), Kind::Text), ("loop.index".to_string(), Kind::Text), ("loop.first".to_string(), Kind::Text), ("loop.last".to_string(), Kind::Text)]);
+                    self.check_nodes(template, body, scope, used, depth)?;
+                    scope.truncate(outer);
+                }
+                Node::If { then, otherwise, .. } => {
+                    self.check_nodes(template, then, scope, used, depth)?;
+                    self.check_nodes(template, otherwise, scope, used, depth)?;
+                }
+                Node::Include(include) => {
+                    let nodes = self.templates.get(include)
+                        .filter(|_| depth < MAX_INCLUDE_DEPTH)
+                        .ok_or_else(|| TemplateError::MissingInclude { template: template.to_string(), include: include.clone() })?;
+                    self.check_nodes(template, nodes, scope, used, depth + 1)?;
+                }
+                Node::Text(_) | Node::Variable(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the template `name`. `variables` must be the ones it has been
+    /// checked against: those listed in `PROMPTS`, or a pipeline's.
+    pub fn render(&self, name: &str, variables: &[(&str, Value)]) -> String {
+        let mut scope = variables.iter().map(|(name, value)| (name.to_string(), value.clone())).collect::<Vec<(String, Value)>>();
+        let mut output = String::new();
+        self.render_nodes(&self.templates[name], &mut scope, &mut output);
+        output
+    }
+
+    fn render_nodes(&self, nodes: &[Node], scope: &mut Vec<(String, Value)>, output: &mut String) {
+        let lookup = |name: &str, scope: &[(String, Value)]| {
+            scope.iter().rev()
+                .find(|(variable, _)| variable == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| panic!("template variable {:?} wasn't given", name))
+        };
+
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Variable(name) => match lookup(name, scope) {
+                    Value::Text(text) => output.push_str(&text),
+                    Value::List(_) => panic!("template variable {:?} is a list", name)
+                },
+                Node::If { negated, condition, then, otherwise } => {
+                    let truthy = match lookup(condition, scope) {
+                        Value::Text(text) => !text.is_empty(),
+                        Value::List(list) => !list.is_empty()
+                    };
+                    self.render_nodes(if truthy != *negated { then } else { otherwise }, scope, output);
+                }
+                Node::For { item, list, body } => {
+                    let Value::List(items) = lookup(list, scope) else {
+                        panic!("template variable {:?} isn't a list", list);
+                    };
+                    for (index, value) in items.iter().enumerate() {
+                        let outer = scope.len();
+                        let flag = |set: bool| Value::Text(if set { "true".to_string() } else { String::new() });
+                        scope.extend([
+                            (item.clone(), Value::Text(value.clone())),
+                            ("loop.index".to_string(), Value::Text((index + 1).to_string())),
+                            ("loop.first".to_string(), flag(index == 0)),
+                            ("loop.last".to_string(), flag(index + 1 == items.len())),
+                        ]);
+                        self.render_nodes(body, scope, output);
+                        scope.truncate(outer);
+                    }
+                }
+                Node::Include(include) => self.render_nodes(&self.templates[include], scope, output)
+            }
+        }
+    }
+}
+
+/// A block tag that's still open, with the nodes collected before it.
+enum Open {
+    For { item: String, list: String, before: Vec<Node> },
+    If { negated: bool, condition: String, before: Vec<Node>, then: Option<Vec<Node>> },
+}
+
+fn parse(template: &str, source: &str) -> Result<Vec<Node>, TemplateError> {
+    let mut nodes = Vec::new();
+    let mut open: Vec<(usize, Open)> = Vec::new();
+    let mut rest = source;
+
+    let line_at = |rest: &str| source[..source.len() - rest.len()].matches('\n').count() + 1;
+    let error = |line: usize, message: String| TemplateError::ParseError { template: template.to_string(), line, message };
+    let is_name = |name: &str| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find("{{").into_iter().chain(rest.find("{%")).min() else {
+            nodes.push(Node::Text(rest.to_string()));
+            break;
+        };
+        if start > 0 {
+            nodes.push(Node::Text(rest[..start].to_string()));
+        }
+
+        let line = line_at(&rest[start..]);
+        let closer = if rest[start..].starts_with("{{") { "}}" } else { "%}" };
+        let end = rest[start + 2..].find(closer).ok_or_else(|| error(line, format!("unclosed {}", &rest[start..start + 2])))?;
+        let tag = rest[start + 2..start + 2 + end].trim();
+        rest = &rest[start + 2 + end + 2..];
+
+        if closer == "}}" {
+            if !is_name(tag) {
+                return Err(error(line, format!("{:?} is not a variable name", tag)));
+            }
+            nodes.push(Node::Variable(tag.to_string()));
+            continue;
+        }
+
+        let words = tag.split_whitespace().collect::<Vec<&str>>();
+        match words.as_slice() {
+            ["for", item, "in", list] if is_name(item) && is_name(list) => {
+                open.push((line, Open::For { item: item.to_string(), list: list.to_string(), before: std::mem::take(&mut nodes) }));
+            }
+            ["if", condition] | ["if", "not", condition] if is_name(condition) => {
+                let negated = words.len() == 3;
+                open.push((line, Open::If { negated, condition: condition.to_string(), before: std::mem::take(&mut nodes), then: None }));
+            }
+            ["else"] => match open.last_mut() {
+                Some((_, Open::If { then: then @ None, .. })) => *then = Some(std::mem::take(&mut nodes)),
+                _ => return Err(error(line, "else without an if".to_string()))
+            },
+            ["endfor"] => match open.pop() {
+                Some((_, Open::For { item, list, before })) => {
+                    let body = std::mem::replace(&mut nodes, before);
+                    nodes.push(Node::For { item, list, body });
+                }
+                _ => return Err(error(line, "endfor without a for".to_string()))
+            },
+            ["endif"] => match open.pop() {
+                Some((_, Open::If { negated, condition, before, then })) => {
+                    let last = std::mem::replace(&mut nodes, before);
+                    let (then, otherwise) = match then {
+                        Some(then) => (then, last),
+                        None => (last, Vec::new())
+                    };
+                    nodes.push(Node::If { negated, condition, then, otherwise });
+                }
+                _ => return Err(error(line, "endif without an if".to_string()))
+            },
+            ["include", name] if name.len() > 2 && name.starts_with('"') && name.ends_with('"') => {
+                nodes.push(Node::Include(name[1..name.len() - 1].to_string()));
+            }
+            _ => return Err(error(line, format!("unknown tag {:?}", tag)))
+        }
+    }
+
+    match open.last() {
+        Some((line, _)) => Err(error(*line, "block is never closed".to_string())),
+        None => Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    fn templates(sources: &[(&str, &str)]) -> Templates {
+        let mut templates = Templates::builtin();
+        for (name, source) in sources {
+            templates.templates.insert(name.to_string(), parse(name, source).unwrap());
+        }
+        templates
+    }
+
+    fn notes() -> Value {
+        Value::from(&["a", "b"][..])
+    }
+
+    #[test]
+    fn renders_variables_loops_conditionals_and_includes() {
+        let templates = templates(&[
+            ("connections", "{% include \"header\" %}{% for note in notes %}{{ loop.index }}> {{ note }}{% if not loop.last %}, {% endif %}{% endfor %}"),
+            ("header", "{% if base_note %}[{{ base_note }}] {% else %}none {% endif %}"),
+        ]);
+
+        assert_eq!(templates.render("connections", &[("base_note", "base".into()), ("notes", notes())]), "[base] 1> a, 2> b");
+        assert_eq!(templates.render("connections", &[("base_note", "".into()), ("notes", notes())]), "none 1> a, 2> b");
+    }
+
+    #[test]
+    fn builtin_prompts_are_valid() {
+        Templates::builtin().validate().unwrap();
+        let prompt = Templates::builtin().render("connections", &[("base_note", "base".into()), ("notes", Value::from(&["a", "b", "c"][..])), ("count", "3".into())]);
+
+        assert!(prompt.contains("    > base\n"));
+        assert!(prompt.contains("along with the 3 $other_notes"));
+        assert!(prompt.contains("    ---\n    1> a\n    ---\n    2> b\n    ---\n    3> c\n    ---\n"));
+    }
+
+    #[test]
+    fn validation_catches_missing_unused_and_misused_variables() {
+        let check = |source: &str| templates(&[("connections", source)]).validate().err().unwrap();
+
+        assert!(matches!(check("{{ base_note }}{{ typo }}{% for n in notes %}{% endfor %}"), TemplateError::UnknownVariable { name, .. } if name == "typo"));
+        assert!(matches!(check("{{ base_note }}"), TemplateError::UnusedVariable { name, .. } if name == "notes"));
+        assert!(matches!(check("{{ base_note }}{{ notes }}"), TemplateError::UnknownVariable { name, .. } if name == "notes"));
+        assert!(matches!(check("{{ note }}{% for note in notes %}{{ base_note }}{% endfor %}"), TemplateError::UnknownVariable { name, .. } if name == "note"));
+        assert!(matches!(check("{{ base_note }}{% for n in notes %}{% include \"connections\" %}{% endfor %}"), TemplateError::MissingInclude { .. }));
+    }
+
+    #[test]
+    fn reports_parse_errors_by_line() {
+        let error = |source: &str| match parse("test", source) {
+            Err(TemplateError::ParseError { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other)
+        };
+
+        assert_eq!(error("one\n{% for x in xs %}\nnever closed"), (2, "block is never closed".to_string()));
+        assert_eq!(error("{{ oops"), (1, "unclosed {{".to_string()));
+        assert_eq!(error("a\nb\n{% endif %}"), (3, "endif without an if".to_string()));
+        assert_eq!(error("{% if a %}{% else %}{% else %}{% endif %}"), (1, "else without an if".to_string()));
+        assert!(parse("test", "{% while x %}").is_err());
+        assert!(parse("test", "{{ two words }}").is_err());
+    }
+
+    #[test]
+    fn loads_overrides_and_partials_from_a_folder() {
+        let dir = scratch_dir("templates");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("compressor.txt"), "{% include \"shout\" %}: {{ input }}\n").unwrap();
+        fs::write(dir.join("shout.txt"), "SQUASH").unwrap();
+
+        let templates = Templates::load(&dir).unwrap();
+        assert_eq!(templates.render("compressor", &[("input", "loops".into())]), "SQUASH: loops");
+
+        fs::write(dir.join("compressor.txt"), "{{ inptu }}").unwrap();
+        assert!(matches!(Templates::load(&dir), Err(TemplateError::UnknownVariable { .. })));
+        assert!(Templates::load(&dir.join("missing")).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
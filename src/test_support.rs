@@ -0,0 +1,13 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// A path for a test to write to, under a temp folder of this process,
+/// with whatever an earlier run left there removed. Nothing is created.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!("summoning-circle-{}", std::process::id()))
+        .join(name);
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
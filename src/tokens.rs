@@ -0,0 +1,297 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+use crate::backend::CompletionBackend;
+use crate::openai::OpenAIError;
+use crate::params::GenerationParams;
+use crate::prompts;
+use crate::templates::Templates;
+
+/// The BPE `model` counts tokens with. Models it doesn't know, including
+/// those of local servers, are counted with cl100k, as gpt-3.5 and gpt-4 are.
+fn bpe(model: Option<&str>) -> &'static CoreBPE {
+    static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+    static O200K: OnceLock<CoreBPE> = OnceLock::new();
+    static P50K: OnceLock<CoreBPE> = OnceLock::new();
+    static R50K: OnceLock<CoreBPE> = OnceLock::new();
+
+    // The encodings are bundled with tiktoken-rs, so loading them can't fail.
+    match model.and_then(get_tokenizer) {
+        Some(Tokenizer::O200kBase) => O200K.get_or_init(|| tiktoken_rs::o200k_base().unwrap()),
+        Some(Tokenizer::P50kBase | Tokenizer::P50kEdit) => P50K.get_or_init(|| tiktoken_rs::p50k_base().unwrap()),
+        Some(Tokenizer::R50kBase | Tokenizer::Gpt2) => R50K.get_or_init(|| tiktoken_rs::r50k_base().unwrap()),
+        _ => CL100K.get_or_init(|| tiktoken_rs::cl100k_base().unwrap())
+    }
+}
+
+/// Tokens in `text` with cl100k, the encoding of the chat and embedding models.
+pub fn count(text: &str) -> usize {
+    count_for(None, text)
+}
+
+/// Tokens in `text` as `model` counts them.
+pub fn count_for(model: Option<&str>, text: &str) -> usize {
+    bpe(model).encode_ordinary(text).len()
+}
+
+/// The longest prompt and answer `model` takes together, 4096 tokens for
+/// models tiktoken doesn't know.
+pub fn context_window(model: Option<&str>) -> usize {
+    model.map(tiktoken_rs::model::get_context_size).unwrap_or(4096)
+}
+
+/// The start of `text`, in at most `max_tokens` tokens. Clipped text ends on
+/// a line, or failing that a word, and is marked with an ellipsis, unless
+/// there's no room even for that.
+pub fn truncate(model: Option<&str>, text: &str, max_tokens: usize) -> String {
+    let bpe = bpe(model);
+    let tokens = bpe.encode_ordinary(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+    if max_tokens == 0 {
+        return String::new();
+    }
+
+    let (kept, _) = decode_prefix(bpe, &tokens[..max_tokens.saturating_sub(1)]);
+    let end = kept.rfind('\n')
+        .filter(|end| *end >= kept.len() / 2)
+        .or_else(|| kept.rfind(char::is_whitespace))
+        .unwrap_or(kept.len());
+    format!("{}…", kept[..end].trim_end())
+}
+
+/// The longest start of `tokens` that decodes to whole characters, and the
+/// number of tokens it takes. A character can span several tokens, so a cut
+/// at an arbitrary token may fall inside one.
+fn decode_prefix(bpe: &CoreBPE, tokens: &[usize]) -> (String, usize) {
+    (0..=tokens.len()).rev()
+        .find_map(|end| bpe.decode(tokens[..end].to_vec()).ok().map(|text| (text, end)))
+        .unwrap_or_default()
+}
+
+/// `text` in chunks of at most `max_tokens` tokens, cut between characters.
+/// Only a character longer than `max_tokens` makes a longer chunk.
+fn split(model: Option<&str>, text: &str, max_tokens: usize) -> Vec<String> {
+    let bpe = bpe(model);
+    let tokens = bpe.encode_ordinary(text);
+    let mut rest = &tokens[..];
+    let mut chunks = Vec::new();
+
+    while !rest.is_empty() {
+        let (chunk, used) = match decode_prefix(bpe, &rest[..rest.len().min(max_tokens.max(1))]) {
+            (_, 0) => (1..=rest.len())
+                .find_map(|end| bpe.decode(rest[..end].to_vec()).ok().map(|text| (text, end)))
+                .unwrap_or_default(),
+            decoded => decoded
+        };
+        chunks.push(chunk);
+        rest = &rest[used.max(1)..];
+    }
+    chunks
+}
+
+/// Splits `budget` tokens between texts of `sizes` tokens. Texts under an
+/// even share keep all of theirs, and what they leave goes to the rest.
+pub fn shares(sizes: &[usize], budget: usize) -> Vec<usize> {
+    let mut order = (0..sizes.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|index| sizes[*index]);
+
+    let mut shares = vec![0; sizes.len()];
+    let mut left = budget;
+    for (position, index) in order.into_iter().enumerate() {
+        shares[index] = sizes[index].min(left / (sizes.len() - position));
+        left -= shares[index];
+    }
+    shares
+}
+
+/// How notes too long for a prompt are cut down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fit {
+    /// Keep the start of each note.
+    #[default]
+    Truncate,
+    /// Ask the model for a summary of each note in the room it has. Notes
+    /// too long to summarize at once are summarized in chunks.
+    Summarize,
+}
+
+#[derive(Debug)]
+pub enum BudgetError {
+    /// A prompt whose other text and answer leave no room in the window
+    /// for what's pasted into it.
+    NoRoom { window: usize, overhead: usize, reserve: usize },
+    OpenAIError(OpenAIError),
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BudgetError::NoRoom { window, overhead, reserve } => write!(f, "prompt overhead exceeds context window: {} tokens of prompt and {} reserved for the answer leave no room for notes in {}", overhead, reserve, window),
+            BudgetError::OpenAIError(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl From<OpenAIError> for BudgetError {
+    fn from(openai_error: OpenAIError) -> Self {
+        BudgetError::OpenAIError(openai_error)
+    }
+}
+
+/// A note as it goes into a prompt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fitted {
+    pub content: String,
+    /// The note's length, in tokens, when it had to be cut down.
+    pub clipped_from: Option<usize>,
+    pub tokens: usize,
+}
+
+/// The room a model's context window leaves for notes pasted into a prompt.
+pub struct Budget<'a> {
+    pub model: Option<&'a str>,
+    pub window: usize,
+    pub tokens: usize,
+}
+
+impl<'a> Budget<'a> {
+    /// The room left in `window` by a prompt whose other text takes
+    /// `overhead` tokens, keeping `reserve` for the answer. Fails when that
+    /// leaves no room at all.
+    pub fn new(model: Option<&'a str>, window: usize, overhead: usize, reserve: usize) -> Result<Budget<'a>, BudgetError> {
+        match window.saturating_sub(overhead + reserve) {
+            0 => Err(BudgetError::NoRoom { window, overhead, reserve }),
+            tokens => Ok(Budget { model, window, tokens })
+        }
+    }
+
+    /// Cuts `notes` down so that together they fit the budget, sharing it out
+    /// with `shares`. Notes that already fit are left alone.
+    pub fn fit(&self, notes: &[&str], fit: Fit, templates: &Templates, params: &GenerationParams, backend: &dyn CompletionBackend) -> Result<Vec<Fitted>, BudgetError> {
+        let sizes = notes.iter().map(|note| count_for(self.model, note)).collect::<Vec<usize>>();
+        let shares = shares(&sizes, self.tokens);
+
+        notes.iter().zip(sizes).zip(shares).map(|((note, size), share)| {
+            if size <= share {
+                return Ok(Fitted { content: note.to_string(), clipped_from: None, tokens: size });
+            }
+            let content = match fit {
+                Fit::Truncate => truncate(self.model, note, share),
+                Fit::Summarize => self.summarize(note, share, templates, params, backend)?
+            };
+            Ok(Fitted { tokens: count_for(self.model, &content), content, clipped_from: Some(size) })
+        }).collect()
+    }
+
+    /// A summary of `note` in at most `tokens` tokens. Notes too long to
+    /// summarize in one go are split into chunks, each summarized in its
+    /// share of `tokens`. Fails when the summary prompt and answer leave no
+    /// room for any of the note.
+    fn summarize(&self, note: &str, tokens: usize, templates: &Templates, params: &GenerationParams, backend: &dyn CompletionBackend) -> Result<String, BudgetError> {
+        if tokens == 0 {
+            return Ok(String::new());
+        }
+        let overhead = count_for(self.model, &prompts::summarize_note(templates, "", tokens));
+        let room = match self.window.saturating_sub(overhead + tokens) {
+            0 => return Err(BudgetError::NoRoom { window: self.window, overhead, reserve: tokens }),
+            room => room
+        };
+        let chunks = split(self.model, note, room);
+        let share = (tokens / chunks.len()).max(1);
+
+        let summaries = chunks.iter().map(|chunk| {
+            let summary = backend.chat(&prompts::summarize_note(templates, chunk, share), &params.clone().max_tokens(share as u32))?;
+            Ok(summary.trim().to_string())
+        }).collect::<Result<Vec<String>, OpenAIError>>()?;
+        Ok(truncate(self.model, &summaries.join("\n"), tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+
+    #[test]
+    fn counts_with_the_models_encoding() {
+        assert_eq!(count("hello world"), 2);
+        assert_eq!(count(""), 0);
+        assert_eq!(count_for(Some("text-davinci-003"), "hello world"), 2);
+        assert_eq!(context_window(Some("gpt-4")), 8192);
+        assert_eq!(context_window(None), 4096);
+    }
+
+    #[test]
+    fn truncates_at_a_word() {
+        let text = "feedback loops feed gardens ".repeat(50);
+
+        let clipped = truncate(None, &text, 20);
+
+        assert!(count(&clipped) <= 20);
+        assert!(clipped.ends_with("…"));
+        assert!(text.starts_with(clipped.trim_end_matches('…')));
+        assert!(!clipped.contains("  "));
+        assert_eq!(truncate(None, "short", 20), "short");
+        assert_eq!(truncate(None, &text, 0), "");
+    }
+
+    #[test]
+    fn cuts_between_characters() {
+        let text = "日本語のノート ".repeat(40);
+
+        let clipped = truncate(None, &text, 15);
+        let chunks = split(None, &text, 3);
+
+        assert!(!clipped.contains(char::REPLACEMENT_CHARACTER));
+        assert!(text.starts_with(clipped.trim_end_matches('…')));
+        assert!(chunks.iter().all(|chunk| !chunk.contains(char::REPLACEMENT_CHARACTER)));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn a_prompt_filling_the_window_leaves_no_budget() {
+        assert!(Budget::new(None, 1000, 600, 400).is_err());
+        assert_eq!(Budget::new(None, 1000, 599, 400).unwrap().tokens, 1);
+    }
+
+    #[test]
+    fn shares_go_to_the_notes_that_need_them() {
+        assert_eq!(shares(&[10, 500, 1000], 310), [10, 150, 150]);
+        assert_eq!(shares(&[10, 20], 100), [10, 20]);
+        assert_eq!(shares(&[], 100), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn fits_notes_into_the_budget() {
+        let backend = MockBackend::new().with_chats(&["loops all the way down", "and gardens"]);
+        let templates = Templates::builtin();
+        let long = "feedback loops feed gardens ".repeat(200);
+        let budget = Budget::new(None, 1000, 200, 500).unwrap();
+
+        let fitted = budget.fit(&["short note", &long], Fit::Truncate, &templates, &GenerationParams::new(), &backend).unwrap();
+
+        assert_eq!(fitted[0], Fitted { content: "short note".to_string(), clipped_from: None, tokens: 2 });
+        assert_eq!(fitted[1].clipped_from, Some(count(&long)));
+        assert!(fitted.iter().map(|note| note.tokens).sum::<usize>() <= 300);
+
+        let fitted = budget.fit(&[&long], Fit::Summarize, &templates, &GenerationParams::new(), &backend).unwrap();
+
+        assert_eq!(fitted[0].content, "loops all the way down\nand gardens");
+        assert_eq!(backend.params().len(), 2);
+        assert_eq!(backend.params()[0].max_tokens, Some(150));
+
+        // a window too small to paste any of the note into the summary prompt
+        let cramped = Budget { model: None, window: 300, tokens: 290 };
+        let result = cramped.fit(&[&long], Fit::Summarize, &templates, &GenerationParams::new(), &backend);
+
+        assert!(matches!(result, Err(BudgetError::NoRoom { .. })));
+        assert_eq!(backend.params().len(), 2);
+    }
+}
@@ -0,0 +1,447 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+
+use crate::backend::CompletionBackend;
+use crate::chat::{count_tokens, ChatMessage};
+use crate::openai::{Embedding, OpenAIError};
+use crate::params::GenerationParams;
+use crate::subtext::format_timestamp;
+use crate::tokens;
+
+#[derive(Debug)]
+pub enum TranscriptError {
+    IOError(PathBuf, io::Error),
+    ParseError(PathBuf, String),
+    UnknownRun(String),
+}
+
+impl fmt::Display for TranscriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranscriptError::IOError(path, error) => write!(f, "could not access transcript {}: {}", path.display(), error),
+            TranscriptError::ParseError(path, message) => write!(f, "invalid transcript {}: {}", path.display(), message),
+            TranscriptError::UnknownRun(id) => write!(f, "no run named {:?}, see `history`", id)
+        }
+    }
+}
+
+/// The log of one run, a JSON object per line, written as the run goes so a
+/// crash leaves everything up to it behind.
+///
+/// The first line describes the run (`"type": "run"`). Then come `"request"`
+/// lines for every call to the backend, `"notes"` once the notes are picked,
+/// `"result"` with the answer shown and finally `"end"`, with any error.
+pub struct Transcript {
+    id: String,
+    file: Option<Mutex<File>>,
+    started: Instant,
+}
+
+impl Transcript {
+    /// Starts a transcript for `command` in `dir`, named after the time. Runs
+    /// started in the same second are numbered from `-02`, zero-padded so
+    /// they sort in order.
+    pub fn start(dir: &Path, command: &str) -> Result<Transcript, TranscriptError> {
+        fs::create_dir_all(dir).map_err(|error| TranscriptError::IOError(dir.to_path_buf(), error))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let started = format_timestamp(now);
+        let stamp = started.replace(['-', ':', 'Z'], "").replace('T', "-");
+
+        let mut attempt = 1;
+        let (id, file) = loop {
+            let id = match attempt {
+                1 => format!("{}-{}", stamp, command),
+                _ => format!("{}-{}-{:02}", stamp, command, attempt)
+            };
+            let path = run_path(dir, &id);
+            match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(file) => break (id, file),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(error) => return Err(TranscriptError::IOError(path, error))
+            }
+        };
+
+        let transcript = Transcript { id, file: Some(Mutex::new(file)), started: Instant::now() };
+        transcript.record(json!({"type": "run", "id": transcript.id, "command": command, "started": started}));
+        Ok(transcript)
+    }
+
+    /// A transcript that records nothing, for when they're turned off.
+    pub fn disabled() -> Transcript {
+        Transcript { id: String::new(), file: None, started: Instant::now() }
+    }
+
+    /// The name `history` shows the run under, if it's being recorded.
+    pub fn id(&self) -> Option<&str> {
+        self.file.as_ref().map(|_| self.id.as_str())
+    }
+
+    /// Appends `entry`. Transcripts are best effort: a failed write is only reported.
+    pub fn record(&self, entry: Value) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let mut file = file.lock().unwrap();
+        if let Err(error) = writeln!(file, "{}", entry) {
+            eprintln!("could not write transcript {}: {}", self.id, error);
+        }
+    }
+
+    pub fn notes(&self, notes: &[String]) {
+        self.record(json!({"type": "notes", "notes": notes}));
+    }
+
+    /// Records a note cut down from `from` to `to` tokens to fit the prompt.
+    pub fn clipped(&self, note: &str, from: usize, to: usize) {
+        self.record(json!({"type": "clipped", "note": note, "from": from, "to": to}));
+    }
+
+    pub fn result(&self, result: &str) {
+        self.record(json!({"type": "result", "result": result}));
+    }
+
+    pub fn end(&self, error: Option<String>) {
+        self.record(json!({"type": "end", "error": error, "elapsed_ms": self.started.elapsed().as_millis() as u64}));
+    }
+}
+
+fn run_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", id))
+}
+
+/// Passes every call through to `inner`, recording it in a transcript with
+/// the params it was sent with, its latency and token counts.
+///
+/// Params are the backend's defaults with the call's laid over them and any
+/// temperature range drawn from, so the transcript shows what was sent.
+pub struct TranscriptBackend<'a> {
+    inner: &'a dyn CompletionBackend,
+    transcript: &'a Transcript,
+}
+
+impl<'a> TranscriptBackend<'a> {
+    pub fn new(inner: &'a dyn CompletionBackend, transcript: &'a Transcript) -> TranscriptBackend<'a> {
+        TranscriptBackend { inner, transcript }
+    }
+
+    fn logged(&self, endpoint: &str, input: (&str, Value), prompt_tokens: usize, params: &GenerationParams, send: impl FnOnce() -> Result<String, OpenAIError>) -> Result<String, OpenAIError> {
+        let started = Instant::now();
+        let result = send();
+
+        let mut request = Map::new();
+        params.apply(&mut request);
+        let mut entry = json!({
+            "type": "request",
+            "endpoint": endpoint,
+            "model": params.model,
+            "params": request,
+            "prompt_tokens": prompt_tokens,
+            "latency_ms": started.elapsed().as_millis() as u64,
+        });
+        entry[input.0] = input.1;
+        match &result {
+            Ok(response) => {
+                entry["response"] = json!(response);
+                entry["response_tokens"] = json!(tokens::count_for(params.model.as_deref(), response));
+            }
+            Err(error) => entry["error"] = json!(error.to_string())
+        }
+
+        self.transcript.record(entry);
+        result
+    }
+
+    fn logged_embeddings(&self, inputs: &[&str], send: impl FnOnce() -> Result<Vec<Embedding>, OpenAIError>) -> Result<Vec<Embedding>, OpenAIError> {
+        let started = Instant::now();
+        let result = send();
+
+        let mut entry = json!({
+            "type": "request",
+            "endpoint": "embeddings",
+            "model": self.inner.embedding_model(),
+            "inputs": inputs,
+            "prompt_tokens": inputs.iter().map(|input| tokens::count(input)).sum::<usize>(),
+            "latency_ms": started.elapsed().as_millis() as u64,
+        });
+        if let Err(error) = &result {
+            entry["error"] = json!(error.to_string());
+        }
+
+        self.transcript.record(entry);
+        result
+    }
+}
+
+impl CompletionBackend for TranscriptBackend<'_> {
+    fn complete(&self, prompt: &str, params: &GenerationParams) -> Result<String, OpenAIError> {
+        let params = self.inner.completion_defaults().overlay(params).resolved();
+        self.logged("completions", ("prompt", json!(prompt)), tokens::count_for(params.model.as_deref(), prompt), &params, || self.inner.complete(prompt, &params))
+    }
+
+    fn chat_messages(&self, messages: &[ChatMessage], params: &GenerationParams) -> Result<String, OpenAIError> {
+        let params = self.inner.chat_defaults().overlay(params).resolved();
+        self.logged("chat", ("messages", json!(messages)), count_tokens(messages), &params, || self.inner.chat_messages(messages, &params))
+    }
+
+    fn embed(&self, input: &str) -> Result<Embedding, OpenAIError> {
+        let mut embeddings = self.logged_embeddings(&[input], || Ok(vec![self.inner.embed(input)?]))?;
+        Ok(embeddings.remove(0))
+    }
+
+    fn embed_batch(&self, inputs: &[&str]) -> Result<Vec<Embedding>, OpenAIError> {
+        self.logged_embeddings(inputs, || self.inner.embed_batch(inputs))
+    }
+
+    fn embedding_model(&self) -> Option<&str> {
+        self.inner.embedding_model()
+    }
+
+    fn completion_defaults(&self) -> GenerationParams {
+        self.inner.completion_defaults()
+    }
+
+    fn chat_defaults(&self) -> GenerationParams {
+        self.inner.chat_defaults()
+    }
+
+    fn complete_stream(&self, prompt: &str, params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let params = self.inner.completion_defaults().overlay(params).resolved();
+        self.logged("completions", ("prompt", json!(prompt)), tokens::count_for(params.model.as_deref(), prompt), &params, || self.inner.complete_stream(prompt, &params, on_token))
+    }
+
+    fn chat_messages_stream(&self, messages: &[ChatMessage], params: &GenerationParams, on_token: &mut dyn FnMut(&str)) -> Result<String, OpenAIError> {
+        let params = self.inner.chat_defaults().overlay(params).resolved();
+        self.logged("chat", ("messages", json!(messages)), count_tokens(messages), &params, || self.inner.chat_messages_stream(messages, &params, on_token))
+    }
+}
+
+/// What `history` lists about a run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunSummary {
+    pub id: String,
+    pub command: String,
+    pub started: String,
+    pub notes: Vec<String>,
+    pub requests: usize,
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    fn from_entries(entries: &[Value]) -> RunSummary {
+        let mut summary = RunSummary::default();
+        let text = |value: &Value| value.as_str().map(str::to_string);
+
+        for entry in entries {
+            match entry["type"].as_str() {
+                Some("run") => {
+                    summary.id = text(&entry["id"]).unwrap_or_default();
+                    summary.command = text(&entry["command"]).unwrap_or_default();
+                    summary.started = text(&entry["started"]).unwrap_or_default();
+                }
+                Some("notes") => summary.notes.extend(entry["notes"].as_array().into_iter().flatten().filter_map(text)),
+                Some("request") => summary.requests += 1,
+                Some("result") => summary.result = text(&entry["result"]),
+                Some("end") => summary.error = text(&entry["error"]),
+                _ => {}
+            }
+        }
+        summary
+    }
+}
+
+/// Every run recorded in `dir`, newest first. Runs that can't be read are
+/// skipped with a warning.
+pub fn list_runs(dir: &Path) -> Result<Vec<RunSummary>, TranscriptError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|error| TranscriptError::IOError(dir.to_path_buf(), error))? {
+        let path = entry.map_err(|error| TranscriptError::IOError(dir.to_path_buf(), error))?.path();
+        if path.extension().is_some_and(|extension| extension == "jsonl") {
+            ids.extend(path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string));
+        }
+    }
+    ids.sort_by(|a, b| b.cmp(a));
+
+    Ok(ids.iter().filter_map(|id| match load_run(dir, id) {
+        Ok(entries) => Some(RunSummary::from_entries(&entries)),
+        Err(error) => {
+            eprintln!("skipping run {}: {}", id, error);
+            None
+        }
+    }).collect())
+}
+
+/// Every entry of the run called `id`, in order. A last line that doesn't
+/// parse was cut off by a crash mid-write, and is left out.
+pub fn load_run(dir: &Path, id: &str) -> Result<Vec<Value>, TranscriptError> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+        return Err(TranscriptError::UnknownRun(id.to_string()));
+    }
+    let path = run_path(dir, id);
+    let text = match fs::read_to_string(&path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(TranscriptError::UnknownRun(id.to_string())),
+        result => result.map_err(|error| TranscriptError::IOError(path.clone(), error))?
+    };
+
+    let lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()).collect::<Vec<(usize, &str)>>();
+    let mut entries = Vec::new();
+    for (position, (number, line)) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if position + 1 == lines.len() => {}
+            Err(error) => return Err(TranscriptError::ParseError(path, format!("line {}: {}", number + 1, error)))
+        }
+    }
+    Ok(entries)
+}
+
+/// A run laid out for reading: each request's prompt and response in turn.
+pub fn format_run(entries: &[Value]) -> String {
+    let mut lines = Vec::new();
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+
+    for entry in entries {
+        match entry["type"].as_str() {
+            Some("run") => lines.push(format!("run {} ({}) at {}", text(&entry["id"]), text(&entry["command"]), text(&entry["started"]))),
+            Some("notes") => {
+                let notes = entry["notes"].as_array().into_iter().flatten().map(text).collect::<Vec<String>>();
+                lines.push(format!("notes: {}", notes.join(", ")));
+            }
+            Some("clipped") => lines.push(format!("clipped {} from {} to {} tokens", text(&entry["note"]), entry["from"], entry["to"])),
+            Some("request") => {
+                let mut heading = vec![text(&entry["endpoint"])];
+                if let Some(model) = entry["model"].as_str() {
+                    heading.push(model.to_string());
+                }
+                if let Some(temperature) = entry["params"]["temperature"].as_f64() {
+                    heading.push(format!("temperature {:.2}", temperature));
+                }
+                heading.push(format!("{} ms", entry["latency_ms"]));
+                heading.push(format!("{} tokens in", entry["prompt_tokens"]));
+                if let Some(tokens) = entry["response_tokens"].as_u64() {
+                    heading.push(format!("{} out", tokens));
+                }
+                lines.push(format!("\n=== {}", heading.join(", ")));
+
+                if let Some(prompt) = entry["prompt"].as_str() {
+                    lines.push(prompt.to_string());
+                }
+                for message in entry["messages"].as_array().into_iter().flatten() {
+                    lines.push(format!("[{}] {}", text(&message["role"]), text(&message["content"])));
+                }
+                for input in entry["inputs"].as_array().into_iter().flatten() {
+                    lines.push(format!("[embed] {}", text(input)));
+                }
+                match (entry["response"].as_str(), entry["error"].as_str()) {
+                    (Some(response), _) => lines.push(format!("---\n{}", response)),
+                    (None, Some(error)) => lines.push(format!("--- error: {}", error)),
+                    (None, None) => {}
+                }
+            }
+            Some("result") => lines.push(format!("\n=== result\n{}", text(&entry["result"]))),
+            Some("end") => match entry["error"].as_str() {
+                Some(error) => lines.push(format!("\nfailed after {} ms: {}", entry["elapsed_ms"], error)),
+                None => lines.push(format!("\nfinished in {} ms", entry["elapsed_ms"]))
+            },
+            _ => {}
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockBackend;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn records_requests_with_the_params_sent() {
+        let dir = scratch_dir("transcripts");
+        let transcript = Transcript::start(&dir, "critic").unwrap();
+        let backend = MockBackend::new().with_completions(&["compressed"]);
+        let logged = TranscriptBackend::new(&backend, &transcript);
+
+        logged.complete("squash this", &GenerationParams::new().model("m").temperature_range(0.2, 0.4)).unwrap();
+        logged.chat("and this", &GenerationParams::new()).unwrap();
+        logged.embed_batch(&["loops", "seeds"]).unwrap();
+        transcript.notes(&["loops.subtext".to_string()]);
+        transcript.clipped("loops.subtext", 5000, 3000);
+        transcript.result("mock response");
+        transcript.end(None);
+
+        let entries = load_run(&dir, transcript.id().unwrap()).unwrap();
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[1]["prompt"], "squash this");
+        assert_eq!(entries[1]["response"], "compressed");
+        assert_eq!(entries[1]["model"], "m");
+        let temperature = entries[1]["params"]["temperature"].as_f64().unwrap();
+        assert!((0.2..0.4).contains(&temperature));
+        let Some(crate::params::Temperature::Fixed(sent)) = backend.params()[0].temperature else {
+            panic!("the drawn temperature should be sent");
+        };
+        assert!((sent - temperature).abs() < 1e-12);
+        assert_eq!(entries[2]["messages"][0]["content"], "and this");
+        assert_eq!(entries[3]["inputs"], json!(["loops", "seeds"]));
+        assert_eq!(entries[1]["prompt_tokens"], 3);
+        assert!(format_run(&entries).contains("=== completions, m, temperature"));
+        assert!(format_run(&entries).contains("clipped loops.subtext from 5000 to 3000 tokens"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lists_runs_newest_first() {
+        let dir = scratch_dir("history");
+        let first = Transcript::start(&dir, "actor").unwrap();
+        first.end(Some("unplugged".to_string()));
+        let second = Transcript::start(&dir, "actor").unwrap();
+        second.notes(&["loops.subtext".to_string()]);
+        second.result("a line");
+        second.end(None);
+
+        let runs = list_runs(&dir).unwrap();
+
+        assert_eq!(runs.len(), 2);
+        let (newest, oldest) = (&runs[0], &runs[1]);
+        assert_eq!(newest.notes, ["loops.subtext"]);
+        assert_eq!(newest.result.as_deref(), Some("a line"));
+        assert_eq!(oldest.error.as_deref(), Some("unplugged"));
+        assert!(matches!(load_run(&dir, "../history"), Err(TranscriptError::UnknownRun(_))));
+        assert!(matches!(load_run(&dir, "nope"), Err(TranscriptError::UnknownRun(_))));
+        assert!(list_runs(&dir.join("missing")).unwrap().is_empty());
+        assert!(Transcript::disabled().id().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tolerates_cut_off_and_broken_runs() {
+        let dir = scratch_dir("broken");
+        let runs = (0..11).map(|_| Transcript::start(&dir, "critic").unwrap()).collect::<Vec<Transcript>>();
+        runs.iter().for_each(|run| run.end(None));
+        let ids = runs.iter().map(|run| run.id().unwrap().to_string()).collect::<Vec<String>>();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(sorted, ids);
+
+        let mut torn = OpenOptions::new().append(true).open(run_path(&dir, &ids[1])).unwrap();
+        io::Write::write_all(&mut torn, b"{\"type\": \"requ").unwrap();
+        let broken = fs::read_to_string(run_path(&dir, &ids[2])).unwrap().replacen('{', "{{", 1);
+        fs::write(run_path(&dir, &ids[2]), broken).unwrap();
+
+        assert_eq!(load_run(&dir, &ids[1]).unwrap().len(), 2);
+        assert!(matches!(load_run(&dir, &ids[2]), Err(TranscriptError::ParseError(_, ref message)) if message.starts_with("line 1:")));
+        assert_eq!(list_runs(&dir).unwrap().len(), 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}